@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::expr::{self, Scope};
+use crate::instructions::{operands, Context};
+
+/*
+DATA
+
+Directives that put data in a section instead of instructions, and can have a label in front like
+an instruction can:
+
+    .word 1, -2, @table.lo    16 bit values, one word each
+    .dword @table, 100000     32 bit values, two words each, the top half first
+    .fill 8, -1               8 words of all ones
+    .space 16                 16 words of 0
+    .align 4                  words of 0 up to the next multiple of 4
+    .string "hi\n"            one character a word
+    .string packed "hi\n"     two characters a word, the first in the top byte, and a 0 byte
+                              after the last if it's on its own
+
+Values can be expressions with labels and $, like operands (see expr.rs). How many words .fill,
+.space and .align make has to be known before anything has an address, so those can only use
+constants. A section that aligns something inside it is started on a multiple of that too, and
+none of them can make more words than fit before the end of the image (see layout.rs).
+Strings can have the same escapes as characters (\n, \t, \0, \\, \' and \") in them, and aren't ended with a 0 unless they say so.
+`.string wide` is the same as leaving the encoding out.
+*/
+
+
+pub const DIRECTIVES: [&str; 6] = [".word", ".dword", ".fill", ".space", ".align", ".string"];
+
+
+/// One word of data, worked out once every label has an address. The expressions are shared
+/// between the words made from the same one, like the two halves of a .dword or a whole .fill.
+#[derive(Clone, Debug)]
+pub enum Item {
+    Word(Rc<str>),  // an expression that fits in 16 bits
+    High(Rc<str>),  // the top half of an expression that fits in 32 bits
+    Low(Rc<str>),   // ... and the bottom half
+    Value(u16),
+}
+
+
+/// What a directive makes.
+pub enum Data {
+    Words(Vec<Item>),
+    Align(u32), // as many 0 words as it takes to get to a multiple of this
+}
+
+
+fn count(text: &str, constants: &HashMap<String, i64>, what: &str) -> Result<u32, String> {
+    let scope = Scope { constants, labels: None, here: None };
+    let value = expr::eval(text, &scope)?;
+    u32::try_from(value).map_err(|_| format!("{} can't be {}", what, value))
+}
+
+
+fn string(text: &str) -> Result<Vec<char>, String> {
+    let inner = text.strip_prefix('"').and_then(|t| t.strip_suffix('"'))
+        .filter(|_| text.len() >= 2)
+        .ok_or_else(|| format!("Expected a string in quotes, as in .string \"hello\", not {}", text))?;
+
+    let mut chars = Vec::new();
+    let mut iter = inner.chars();
+    while let Some(c) = iter.next() {
+        chars.push(match c {
+            '\\' => match iter.next() {
+                Some(e) => isa::expr::unescape(e).ok_or_else(|| format!("Unknown escape \\{} in a string. \
+                    There are \\n, \\t, \\0, \\\\, \\' and \\\"", e))?,
+                None => return Err(String::from("A string can't end with a \\")),
+            },
+            '"' => return Err(String::from("A \" in a string has to be written \\\"")),
+            c => c,
+        });
+    }
+    Ok(chars)
+}
+
+
+fn string_words(rest: &str) -> Result<Vec<Item>, String> {
+    let (packed, text) = match rest.split_once(char::is_whitespace) {
+        Some(("packed", text)) => (true, text.trim()),
+        Some(("wide", text)) => (false, text.trim()),
+        _ => (false, rest),
+    };
+    let chars = string(text)?;
+
+    if !packed {
+        return chars.iter().map(|c| u16::try_from(*c as u32).map(Item::Value)
+            .map_err(|_| format!("'{}' doesn't fit in a word", c))).collect();
+    }
+
+    let bytes = chars.iter().map(|c| u8::try_from(*c as u32)
+        .map_err(|_| format!("'{}' doesn't fit in a byte, so it can't be packed", c)))
+        .collect::<Result<Vec<u8>, String>>()?;
+    Ok(bytes.chunks(2)
+        .map(|pair| Item::Value(u16::from_be_bytes([pair[0], pair.get(1).copied().unwrap_or(0)])))
+        .collect())
+}
+
+
+/// How many words a .fill or .space makes, when only `room` more fit in the image.
+fn length(text: &str, constants: &HashMap<String, i64>, room: u64) -> Result<usize, String> {
+    let count = count(text, constants, "The count")?;
+    if count as u64 > room {
+        return Err(format!("The count is {}, but there's only room for {} more words before the end of \
+            the image at {:#010x}", count, room, crate::layout::MAX_IMAGE_WORDS));
+    }
+    Ok(count as usize)
+}
+
+
+/// Reads a data directive, `name` being one of DIRECTIVES, and `rest` what comes after it. `room`
+/// is how many more words fit in the image where it is.
+pub fn parse(name: &str, rest: &str, constants: &HashMap<String, i64>, room: u64) -> Result<Data, String> {
+    let ops = operands(rest);
+    let expect = |n: usize, pattern: &str| if ops.len() == n { Ok(()) } else {
+        Err(format!("{} takes {} value{}, but was given {}. Expected pattern is:\n  {}",
+            name, n, if n == 1 { "" } else { "s" }, ops.len(), pattern))
+    };
+
+    let words = match name {
+        ".word" | ".dword" if ops.is_empty() => return Err(format!("{} needs at least one value", name)),
+        ".word" => ops.iter().map(|v| Item::Word(Rc::from(*v))).collect(),
+        ".dword" => ops.iter()
+            .flat_map(|v| {
+                let v: Rc<str> = Rc::from(*v);
+                [Item::High(Rc::clone(&v)), Item::Low(v)]
+            })
+            .collect(),
+        ".fill" => {
+            expect(2, ".fill [count], [value]")?;
+            vec![Item::Word(Rc::from(ops[1])); length(ops[0], constants, room)?]
+        },
+        ".space" => {
+            expect(1, ".space [count]")?;
+            vec![Item::Value(0); length(ops[0], constants, room)?]
+        },
+        ".align" => {
+            expect(1, ".align [words]")?;
+            let align = count(ops[0], constants, "The alignment")?;
+            if align == 0 {
+                return Err(String::from("The alignment can't be 0"));
+            }
+            return Ok(Data::Align(align));
+        },
+        ".string" => string_words(rest.trim())?,
+        _ => unreachable!(),
+    };
+    Ok(Data::Words(words))
+}
+
+
+fn value(text: &str, ctx: &Context, bits: u32) -> Result<i64, String> {
+    let scope = Scope { constants: ctx.constants, labels: Some(ctx.labels), here: Some(ctx.addr) };
+    let value = expr::eval(text, &scope).map_err(|e| format!("{} in \"{}\"", e, text))?;
+
+    // either signed or not, as the same bits mean both
+    if value < -(1 << (bits - 1)) || value >= 1 << bits {
+        return Err(format!("{} is {}, which doesn't fit in {} bits, so it has to be from {} to {}",
+            text, value, bits, -(1i64 << (bits - 1)), (1i64 << bits) - 1));
+    }
+    Ok(value)
+}
+
+
+/// The word an item turns into, at ctx.addr.
+pub fn assemble(item: &Item, ctx: &Context) -> Result<u16, String> {
+    Ok(match item {
+        Item::Word(text) => value(text, ctx, 16)? as u16,
+        Item::High(text) => (value(text, ctx, 32)? >> 16) as u16,
+        Item::Low(text) => { // $ is where the .dword starts, not its second word
+            let first = Context { addr: ctx.addr - 1, labels: ctx.labels, constants: ctx.constants };
+            value(text, &first, 32)? as u16
+        },
+        Item::Value(word) => *word,
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(name: &str, rest: &str) -> Result<Vec<u16>, String> {
+        let constants = HashMap::from([(String::from("N"), 3)]);
+        let labels = HashMap::from([(String::from("table"), 0x0001_2345)]);
+        let items = match parse(name, rest, &constants, 0x100)? {
+            Data::Words(items) => items,
+            Data::Align(_) => panic!("{} {} is an .align", name, rest),
+        };
+        items.iter().enumerate()
+            .map(|(i, item)| assemble(item, &Context { addr: 0x100 + i as u32, labels: &labels, constants: &constants }))
+            .collect()
+    }
+
+    #[test]
+    fn words_and_dwords() {
+        assert_eq!(words(".word", "1, -1, @table.lo, $, N * 2"), Ok(vec![1, 0xFFFF, 0x2345, 0x103, 6]));
+        assert_eq!(words(".dword", "@table, -2, $"), Ok(vec![1, 0x2345, 0xFFFF, 0xFFFE, 0, 0x104]));
+        assert_eq!(words(".word", "0x10000"),
+            Err(String::from("0x10000 is 65536, which doesn't fit in 16 bits, so it has to be from -32768 to 65535")));
+        assert_eq!(words(".word", ""), Err(String::from(".word needs at least one value")));
+    }
+
+    #[test]
+    fn fill_and_space() {
+        assert_eq!(words(".fill", "N, 'x'"), Ok(vec![120; 3]));
+        assert_eq!(words(".fill", "2, $"), Ok(vec![0x100, 0x101]));
+        assert_eq!(words(".space", "N + 1"), Ok(vec![0; 4]));
+        assert_eq!(words(".fill", "-1, 0"), Err(String::from("The count can't be -1")));
+        assert!(words(".fill", "@table, 0").unwrap_err().contains("labels can't be used in constants"));
+        assert!(words(".space", "1, 2").unwrap_err().starts_with(".space takes 1 value, but was given 2"));
+
+        // only as many words as fit in what's left of the image, which is 0x100 here
+        assert_eq!(words(".space", "0x100").map(|w| w.len()), Ok(0x100));
+        assert_eq!(words(".fill", "0x101, 1"), Err(String::from("The count is 257, but there's only room \
+            for 256 more words before the end of the image at 0x00400000")));
+        assert!(words(".space", "4000000000").is_err());
+    }
+
+    #[test]
+    fn align() {
+        assert!(matches!(parse(".align", "N + 1", &HashMap::from([(String::from("N"), 3)]), 0x100), Ok(Data::Align(4))));
+        assert_eq!(parse(".align", "0", &HashMap::new(), 0x100).err(), Some(String::from("The alignment can't be 0")));
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(words(".string", "\"hi\\n\""), Ok(vec![104, 105, 10]));
+        assert_eq!(words(".string", "wide \"a\\0\""), Ok(vec![97, 0]));
+        assert_eq!(words(".string", "\"é, \\\"x\\\"\""), Ok(vec![0xE9, 44, 32, 34, 120, 34]));
+        assert_eq!(words(".string", "packed \"abc\""), Ok(vec![0x6162, 0x6300]));
+        assert_eq!(words(".string", "packed \"ab\""), Ok(vec![0x6162]));
+        assert_eq!(words(".string", "\"\""), Ok(vec![]));
+
+        assert_eq!(words(".string", "packed \"€\""), Err(String::from("'€' doesn't fit in a byte, so it can't be packed")));
+        assert_eq!(words(".string", "\"a\\q\""),
+            Err(String::from("Unknown escape \\q in a string. There are \\n, \\t, \\0, \\\\, \\' and \\\"")));
+        assert_eq!(words(".string", "\"a\"b\""), Err(String::from("A \" in a string has to be written \\\"")));
+        assert_eq!(words(".string", "hi"), Err(String::from("Expected a string in quotes, as in .string \"hello\", not hi")));
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+
+use isa::expr::{self, BinOp, Half, UnOp};
+
+/*
+Expressions, which can go anywhere a number can: immediates, JR targets, ALU operations, and the
+values of constants. For example
+
+    #(UART_BASE + 4) & 0x3f
+    #lo(table) >> 10
+    #'A' + 1
+    JR $ + 2
+
+Numbers are decimal, or hex, binary or octal with 0x, 0b or 0o in front, and can have _ in them
+anywhere, as in 0b1010_0101. 'c' is the number of a character, with the same escapes as strings:
+\n, \t, \0, \\, \' and \". Names are constants (.equ and .set) first, then labels, and @name is always a
+label, with @name.lo and @name.hi its bottom and top halves, the same as lo(@name) and hi(@name).
+$ is the address of the instruction it is in. The operators are the ones C has for integers, with
+C's precedence: | ^ & << >> + - * / % and unary - ~ +. Everything is worked out in 64 bits, and
+only checked against the field it goes in at the end. Shifts have to be by 0 to 63, and dividing
+by 0 or overflowing a division is an error.
+
+They're read by isa/src/expr.rs, which the debugger reads its conditions with too, so what it has
+that can't mean anything here (comparisons, || && !, and [memory]) is turned away after reading.
+*/
+
+
+type Expr = isa::expr::Expr<String>;
+
+
+/// Reads an expression, leaving its names to be looked up when it is worked out, and turning
+/// away what only the debugger has.
+pub fn parse(text: &str) -> Result<Expr, String> {
+    let e = expr::parse(text, |name| Ok(String::from(name)))?;
+    e.check(&mut |e| match e {
+        Expr::Binary(BinOp::Lt | BinOp::Le, ..) => Err(String::from("There are no comparisons, did you mean <<?")),
+        Expr::Binary(BinOp::Gt | BinOp::Ge, ..) => Err(String::from("There are no comparisons, did you mean >>?")),
+        Expr::Binary(BinOp::Eq | BinOp::Ne, ..) => Err(String::from("There are no comparisons")),
+        Expr::Binary(BinOp::Or, ..) => Err(String::from("There is no ||, did you mean |?")),
+        Expr::Binary(BinOp::And, ..) => Err(String::from("There is no &&, did you mean &?")),
+        Expr::Unary(UnOp::Not, _) => Err(String::from("There is no !, did you mean ~?")),
+        Expr::Mem(_) => Err(String::from("Memory can't be read while assembling")),
+        _ => Ok(()),
+    })?;
+    Ok(e)
+}
+
+
+/// What names mean where an expression is worked out. Constants are worked out where they are
+/// defined, before anything has an address, so they get no labels and no $.
+pub struct Scope<'a> {
+    pub constants: &'a HashMap<String, i64>,
+    pub labels: Option<&'a HashMap<String, u32>>,
+    pub here: Option<u32>,
+}
+
+impl Scope<'_> {
+    fn label(&self, name: &str) -> Result<i64, String> {
+        match self.labels {
+            Some(labels) => labels.get(name).map(|addr| *addr as i64)
+                .ok_or_else(|| format!("Undefined label \"{}\"", name)),
+            None => Err(format!("\"{}\" isn't a constant defined above here, and labels can't be used \
+                in constants, as they don't have addresses yet", name)),
+        }
+    }
+}
+
+
+/// Works out a parsed expression. Anything parse turned away can't be in it.
+fn value(e: &Expr, scope: &Scope) -> Result<i64, String> {
+    Ok(match e {
+        Expr::Num(n) => *n,
+        Expr::Name(name) => match scope.constants.get(name) {
+            Some(value) => *value,
+            None => scope.label(name)?,
+        },
+        Expr::Label(name, half) => {
+            let addr = scope.label(name)?;
+            match half {
+                Half::Whole => addr,
+                Half::Lo => addr & 0xffff,
+                Half::Hi => (addr >> 16) & 0xffff,
+            }
+        },
+        Expr::Here => match scope.here {
+            Some(addr) => addr as i64,
+            None => return Err(String::from("$ can't be used in constants")),
+        },
+        Expr::Unary(op, e) => {
+            let v = value(e, scope)?;
+            match op {
+                UnOp::Neg => v.wrapping_neg(),
+                UnOp::BitNot => !v,
+                UnOp::Lo => v & 0xffff,
+                UnOp::Hi => (v >> 16) & 0xffff,
+                UnOp::Not => unreachable!("parse turns away !"),
+            }
+        },
+        Expr::Binary(op, lhs, rhs) => {
+            let l = value(lhs, scope)?;
+            let r = value(rhs, scope)?;
+            if matches!(op, BinOp::Shl | BinOp::Shr) && !(0..64).contains(&r) {
+                return Err(format!("Can't shift by {}, shifts have to be from 0 to 63", r));
+            }
+            if matches!(op, BinOp::Div | BinOp::Rem) && r == 0 {
+                return Err(String::from("Division by zero"));
+            }
+            match op {
+                BinOp::BitOr => l | r,
+                BinOp::BitXor => l ^ r,
+                BinOp::BitAnd => l & r,
+                BinOp::Shl => l << r,
+                BinOp::Shr => l >> r,
+                BinOp::Add => l.wrapping_add(r),
+                BinOp::Sub => l.wrapping_sub(r),
+                BinOp::Mul => l.wrapping_mul(r),
+                BinOp::Div => l.checked_div(r)
+                    .ok_or_else(|| format!("{} / {} doesn't fit in 64 bits", l, r))?,
+                BinOp::Rem => l.wrapping_rem(r), // only MIN % -1 wraps, and that is 0 anyway
+                _ => unreachable!("parse turns away comparisons and logic"),
+            }
+        },
+        Expr::Mem(_) => unreachable!("parse turns away memory"),
+    })
+}
+
+
+/// Parses and works out an expression in one go.
+pub fn eval(text: &str, scope: &Scope) -> Result<i64, String> {
+    value(&parse(text)?, scope)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval_with(text: &str, constants: &[(&str, i64)], labels: &[(&str, u32)]) -> Result<i64, String> {
+        let constants = constants.iter().map(|(n, v)| (String::from(*n), *v)).collect();
+        let labels = labels.iter().map(|(n, v)| (String::from(*n), *v)).collect();
+        eval(text, &Scope { constants: &constants, labels: Some(&labels), here: Some(0x100) })
+    }
+
+    fn value(text: &str) -> i64 {
+        eval_with(text, &[], &[]).unwrap_or_else(|e| panic!("{}: {}", text, e))
+    }
+
+    #[test]
+    fn precedence_follows_c() {
+        assert_eq!(value("1 + 2 * 3"), 7);
+        assert_eq!(value("(1 + 2) * 3"), 9);
+        assert_eq!(value("1 << 2 + 1"), 8);
+        assert_eq!(value("1 | 6 & 3"), 3);
+        assert_eq!(value("1 ^ 3 | 4"), 6);
+        assert_eq!(value("-2 * -3 + +1"), 7);
+        assert_eq!(value("~0 & 0xff"), 0xff);
+        assert_eq!(value("7 / 2 % 2"), 1);
+        assert_eq!(value("10 - 4 - 3"), 3);
+    }
+
+    #[test]
+    fn numbers_and_characters() {
+        assert_eq!(value("0x1F + 0b11 + 0o17 + 1_000"), 0x1F + 3 + 15 + 1000);
+        assert_eq!(value("'A' + 1"), 66);
+        assert_eq!(value("'\\n' | '\\''"), 10 | 39);
+        assert_eq!(value("','"), 44);
+        assert_eq!(eval_with("'ab'", &[], &[]), Err(String::from("Expected one character in quotes, like 'A' or '\\n'")));
+        assert_eq!(eval_with("'\\q'", &[], &[]),
+            Err(String::from("Unknown escape \\q in a character. There are \\n, \\t, \\0, \\\\, \\' and \\\"")));
+    }
+
+    #[test]
+    fn names_labels_and_here() {
+        let constants = [("BASE", 0x10), ("table", 5)];
+        let labels = [("table", 0x0001_2345), ("loop", 0xF8)];
+        let eval = |text| eval_with(text, &constants, &labels);
+
+        assert_eq!(eval("BASE + 4"), Ok(0x14));
+        assert_eq!(eval("table"), Ok(5)); // constants come first
+        assert_eq!(eval("@table"), Ok(0x0001_2345));
+        assert_eq!(eval("@table.lo + @table.hi"), Ok(0x2345 + 1));
+        assert_eq!(eval("lo(@table) - hi(@table)"), Ok(0x2344));
+        assert_eq!(eval("hi(@table + 0x10000)"), Ok(2));
+        assert_eq!(eval("loop - $"), Ok(-8));
+        assert_eq!(eval("@nowhere"), Err(String::from("Undefined label \"nowhere\"")));
+
+        let none = HashMap::new();
+        let scope = Scope { constants: &none, labels: None, here: None };
+        assert_eq!(super::eval("$", &scope), Err(String::from("$ can't be used in constants")));
+        assert!(super::eval("loop", &scope).unwrap_err().contains("labels can't be used in constants"));
+    }
+
+    #[test]
+    fn shifts_and_division() {
+        assert_eq!(value("1 << 63"), i64::MIN);
+        assert_eq!(value("-8 >> 1"), -4);
+        assert_eq!(eval_with("1 << 64", &[], &[]), Err(String::from("Can't shift by 64, shifts have to be from 0 to 63")));
+        assert_eq!(eval_with("1 >> -1", &[], &[]), Err(String::from("Can't shift by -1, shifts have to be from 0 to 63")));
+
+        assert_eq!(eval_with("1 / 0", &[], &[]), Err(String::from("Division by zero")));
+        assert_eq!(eval_with("1 % 0", &[], &[]), Err(String::from("Division by zero")));
+        assert_eq!(eval_with("(1 << 63) / -1", &[], &[]),
+            Err(String::from("-9223372036854775808 / -1 doesn't fit in 64 bits")));
+        assert_eq!(value("(1 << 63) % -1"), 0);
+        assert_eq!(value("-7 / 2"), -3);
+    }
+
+    #[test]
+    fn bad_expressions() {
+        assert_eq!(eval_with("1 < 2", &[], &[]), Err(String::from("There are no comparisons, did you mean <<?")));
+        assert_eq!(eval_with("1 + (2 >= 1)", &[], &[]), Err(String::from("There are no comparisons, did you mean >>?")));
+        assert_eq!(eval_with("1 && 2", &[], &[]), Err(String::from("There is no &&, did you mean &?")));
+        assert_eq!(eval_with("-!1", &[], &[]), Err(String::from("There is no !, did you mean ~?")));
+        assert_eq!(eval_with("[4]", &[], &[]), Err(String::from("Memory can't be read while assembling")));
+        assert_eq!(eval_with("(1 + 2", &[], &[]), Err(String::from("Missing ) at the end")));
+        assert_eq!(eval_with("1 +", &[], &[]), Err(String::from("Expression ended early")));
+        assert_eq!(eval_with("sqrt(4)", &[], &[]), Err(String::from("Unknown function \"sqrt\". There are lo() and hi()")));
+        assert_eq!(eval_with("@", &[], &[]), Err(String::from("Expected a label name after @")));
+        assert_eq!(eval_with("0x10000000000000000", &[], &[]),
+            Err(String::from("0x10000000000000000 is too big. Numbers can be up to 9223372036854775807")));
+    }
+}
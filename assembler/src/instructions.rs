@@ -0,0 +1,457 @@
+use std::collections::HashMap;
+
+use isa::{alu_op, FlagOp, InstrClass, Instruction, COND_ALWAYS, CONDITION_FLAGS};
+
+use crate::expr::{self, Scope};
+use crate::source::unquoted;
+
+/*
+INSTRUCTIONS
+
+Turns one line of source into an Instruction, in the syntax the disassembler writes out (see the
+top of disassembler/src/lib.rs). Sources come before destinations:
+
+    NOP
+    SETFLG +GT -EQ ~LS        set, clear or toggle flags (GT, EQ, LS, OV), keeping the rest
+    JA J                      jump to the address in J (P, J, K, L or PC)
+    JA GT|EQ, J               ... if gt or eq is set, NEVER for never
+    STORE C, [K]              write C to the address in K
+    LOAD [K], C               read the address in K into C
+    PUTH C, J1                copy C into a half register, J0 to L1
+    GETH P0, C                copy a half register, P0 to L1, into C
+    JR loop                   jump to a label, which can also be written @loop
+    JR LS, $+2                ... or by a number of words from the JR itself
+    MOV B, C                  C = B
+    ADD A, B, C               C = A + B, and the same for SUB and MUL
+    MOV #5, C                 C = 5
+    ADD A, #5, C              C = A + 5
+    TRA #9, A, B, C           an ALU operation by number
+    IM #9, A, #5, C
+    LOADIMM #5, C             the same as MOV #5, C
+
+Registers are A to H, or R0 to R7. Anywhere a number goes, it can be an expression (see
+expr.rs) using constants, labels and $, as in MOV #(UART_BASE + 4) & 63, C or JR $ + 2. @name is
+the address of a label, and @name.lo and @name.hi are its bottom and top halves, as in
+MOV @table.lo, C. Numbers can be written in hex, binary or octal, or as a character, as in
+MOV #0b1010_0000 | 'A', C. A JR goes to the address its target works out to, so JR loop and JR $-3 both
+do what they look like. That goes for plain numbers too: JR 5 goes to address 5, like JA would, and
+5 words on is JR $+5. Every value is checked against the width of the field it goes in.
+
+Conditions are GT, EQ and LS joined with |, and a jump is taken if any of them is set. All three
+together can't be written, as that's the encoding of an unconditional jump, which is taken even
+when no flag is set. SETFLG's flags can be separated by spaces or commas.
+
+An immediate is only 6 bits, so MOV @name.lo, C only works for labels below 64. To get any other
+address into an address register, use LOADADDR, which builds each half in a thin register 6 bits
+at a time and PUTHs it in, in 16 words, overwriting the thin register:
+
+    LOADADDR @table, C, J     J = @table, through C
+
+The address can be any expression, but $ in it is the address of whichever of the 16 words it
+ends up in, so put a label where it's needed instead.
+*/
+
+
+/// Splits operands on commas, leaving any in a character like ',' alone.
+pub fn operands(text: &str) -> Vec<&str> {
+    if text.trim().is_empty() {
+        return Vec::new();
+    }
+    let mut ops = Vec::new();
+    let mut start = 0;
+    for (i, _) in unquoted(text).filter(|(_, c)| *c == ',') {
+        ops.push(text[start..i].trim());
+        start = i + 1;
+    }
+    ops.push(text[start..].trim());
+    ops
+}
+
+
+fn expect_count(mnemonic: &str, ops: &[&str], count: usize, pattern: &str) -> Result<(), String> {
+    if ops.len() != count {
+        return Err(format!("{} takes {} operand{}, but was given {}. Expected pattern is:\n  {}",
+            mnemonic, count, if count == 1 { "" } else { "s" }, ops.len(), pattern));
+    }
+    Ok(())
+}
+
+
+/// A register from A to H, which R0 to R7 also name.
+fn register(op: &str) -> Result<u8, String> {
+    let upper = op.to_ascii_uppercase();
+    let num = match upper.strip_prefix('R') {
+        Some(num) if !num.is_empty() && num.chars().all(|c| c.is_ascii_digit()) => num.parse().ok(),
+        _ => isa::register_number(&upper),
+    };
+    match num {
+        Some(num) if num < 8 => Ok(num),
+        _ => Err(format!("Expected a register from A to H, not \"{}\"", op)),
+    }
+}
+
+
+/// A half register, P0 to L1.
+fn half_register(op: &str) -> Result<u8, String> {
+    match isa::register_number(op) {
+        Some(num) if num >= 8 => Ok(num),
+        _ => Err(format!("Expected a half register from P0 to L1, not \"{}\"", op)),
+    }
+}
+
+
+/// An address register, P, J, K or L, or PC for P.
+fn addr_register(op: &str) -> Result<u8, String> {
+    isa::addr_register_number(op)
+        .ok_or_else(|| format!("Expected an address register (P, J, K or L), not \"{}\"", op))
+}
+
+
+/// An address register in brackets, as in [K].
+fn memory(op: &str) -> Result<u8, String> {
+    match op.strip_prefix('[').and_then(|op| op.strip_suffix(']')) {
+        Some(reg) => addr_register(reg.trim()),
+        None => Err(format!("Expected an address register in brackets, like [K], not \"{}\"", op)),
+    }
+}
+
+
+/// Where the instruction being assembled goes, where every label is, and the constants as they
+/// were on its line.
+pub struct Context<'a> {
+    pub addr: u32,
+    pub labels: &'a HashMap<String, u32>,
+    pub constants: &'a HashMap<String, i64>,
+}
+
+impl Context<'_> {
+    fn eval(&self, text: &str) -> Result<i64, String> {
+        let scope = Scope { constants: self.constants, labels: Some(self.labels), here: Some(self.addr) };
+        expr::eval(text, &scope)
+    }
+}
+
+
+fn is_immediate(op: &str) -> bool {
+    op.starts_with('#') || op.starts_with('@')
+}
+
+
+/// #expression, or an expression starting with a label, like @table.lo + 1.
+fn immediate(op: &str, ctx: &Context) -> Result<i64, String> {
+    let text = match op.strip_prefix('#') {
+        Some(text) => text,
+        None if op.starts_with('@') => op,
+        None => return Err(format!("Expected an immediate, like #4, not \"{}\"", op)),
+    };
+    ctx.eval(text).map_err(|e| format!("{} in \"{}\"", e, op))
+}
+
+
+/// Checks that a value fits a field of an instruction, saying what it was for if not.
+fn fit(class: InstrClass, field: &str, what: &str, value: i64) -> Result<i64, String> {
+    let field = class.format().unwrap().fields.iter().find(|f| f.name == field).unwrap();
+    if value < field.lowest() as i64 || value > field.max() as i64 {
+        let hex = if value > 9 { format!(" ({:#x})", value) } else { String::new() };
+        let signed = if field.signed { "signed " } else { "" };
+        return Err(format!("{} is {}{}, but {} only has {} {}bits for it, so it has to be from {} to {}",
+            what, value, hex, class.name(), field.width, signed, field.lowest(), field.max()));
+    }
+    Ok(value)
+}
+
+
+/// A jump condition like GT|EQ. NEVER is 0, and ALWAYS is the same as leaving it out.
+fn condition(op: &str) -> Result<u8, String> {
+    let upper = op.to_ascii_uppercase();
+    match upper.as_str() {
+        "NEVER" => return Ok(0),
+        "ALWAYS" => return Ok(COND_ALWAYS),
+        _ => {},
+    }
+
+    let mut cond = 0;
+    for flag in upper.split('|') {
+        match CONDITION_FLAGS.iter().find(|(name, _)| *name == flag.trim()) {
+            Some((_, bit)) => cond |= bit,
+            None => return Err(format!("Unknown condition \"{}\". Conditions are GT, EQ and LS, \
+                joined with |, or NEVER", flag.trim())),
+        }
+    }
+    if cond == COND_ALWAYS {
+        return Err(format!("{} can't be written: every flag at once is how an unconditional jump is encoded, \
+            which jumps even when no flag is set. Leave the condition out to always jump", op.trim()));
+    }
+    Ok(cond)
+}
+
+
+/// The condition and the last operand of a jump, which has the condition first if it has one.
+fn jump_operands<'a>(mnemonic: &str, ops: &[&'a str], pattern: &str) -> Result<(u8, &'a str), String> {
+    match ops {
+        [target] => Ok((COND_ALWAYS, target)),
+        [cond, target] => Ok((condition(cond)?, target)),
+        _ => Err(format!("{} takes a target and an optional condition. Expected pattern is:\n  {}",
+            mnemonic, pattern)),
+    }
+}
+
+
+/// How far a JR at ctx.addr has to go to reach its target, which is an address: a label, $+N or
+/// $-N words, or any expression.
+fn relative(op: &str, ctx: &Context) -> Result<i64, String> {
+    let target = ctx.eval(op).map_err(|e| format!("{}. Expected a jump target like loop, $+2 or $-3, \
+        not \"{}\"", e, op))?;
+    let distance = target - ctx.addr as i64;
+
+    // JR 5 is easy to mistake for 5 words on, so say what it means if it can't be reached
+    if isa::parse_number(op).is_ok() && fit(InstrClass::Jr, "offset", "", distance).is_err() {
+        return Err(format!("JR {} goes to address {}, which is {} words away, but JR can only go from -64 to 63. \
+            Write JR $+{} to go {} words on", op, op, distance, op, op));
+    }
+    Ok(distance)
+}
+
+
+fn flag_ops(ops: &str) -> Result<Instruction, String> {
+    let mut flags = [FlagOp::Keep; 4];
+    for op in ops.split(|c: char| c == ',' || c.is_whitespace()).filter(|op| !op.is_empty()) {
+        let mut chars = op.chars();
+        let (what, name) = match chars.next() {
+            Some('+') => (FlagOp::Set, chars.as_str()),
+            Some('-') => (FlagOp::Clear, chars.as_str()),
+            Some('~') => (FlagOp::Toggle, chars.as_str()),
+            _ => return Err(format!("Expected a flag after +, - or ~, like +GT, not \"{}\"", op)),
+        };
+        let index = ["GT", "EQ", "LS", "OV"].iter().position(|f| f.eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("Unknown flag \"{}\". The flags are GT, EQ, LS and OV", name))?;
+        flags[index] = what;
+    }
+    let [gt, eq, ls, ov] = flags;
+    Ok(Instruction::SetFlg { gt, eq, ls, ov })
+}
+
+
+/// An ALU operation, with the second source either a register (TRA) or an immediate (IM).
+fn alu(op: u8, dst: u8, a: u8, b: &str, ctx: &Context) -> Result<Instruction, String> {
+    if !is_immediate(b) {
+        return Ok(Instruction::Tra { op, dst, a, b: register(b)? });
+    }
+
+    // checked here, as anything that doesn't fit in a u8 would wrap before isa::encode could see it
+    let imm = fit(InstrClass::Im, "imm", &format!("The immediate {}", b), immediate(b, ctx)?).map_err(|e| {
+        let lower = b.to_ascii_lowercase();
+        if [".lo", ".hi", "lo(", "hi("].iter().any(|half| lower.contains(half)) {
+            format!("{}. To get an address that big into an address register, use LOADADDR @name, C, J", e)
+        } else {
+            e
+        }
+    })?;
+    Ok(Instruction::Im { op, dst, a, imm: imm as u8 })
+}
+
+
+/// Pseudo-instructions that stand for several instructions, given back as the lines of each one,
+/// so that every word has its own text to assemble. None for anything else.
+pub fn expand(text: &str) -> Option<Result<Vec<String>, String>> {
+    let text = text.trim();
+    let (mnemonic, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    if !mnemonic.eq_ignore_ascii_case("LOADADDR") {
+        return None;
+    }
+    Some(load_addr(&operands(rest)))
+}
+
+
+/// LOADADDR value, C, J: each half of value is at most 16 bits, so it's built as
+/// ((top 4 bits * 64) + middle 6 bits) * 64 + bottom 6 bits, where every immediate fits in IM.
+fn load_addr(ops: &[&str]) -> Result<Vec<String>, String> {
+    expect_count("LOADADDR", ops, 3, "LOADADDR [value], [thin_register], [address_register]")?;
+    let via = isa::REGISTER_NAMES[register(ops[1])? as usize];
+    let bottom = addr_register(ops[2])?;
+    if bottom == 0b1000 {
+        return Err(String::from("LOADADDR can't write P, as the program would jump halfway through. \
+            Load the address into J, K or L and JA to it"));
+    }
+
+    let mut lines = Vec::new();
+    for (half, dst) in [("hi", bottom + 1), ("lo", bottom)] {
+        let value = format!("{}({})", half, ops[0]);
+        lines.extend([
+            format!("MOV #{} >> 12, {}", value, via),
+            format!("MUL {}, #8, {}", via, via),
+            format!("MUL {}, #8, {}", via, via),
+            format!("ADD {}, #({} >> 6) & 63, {}", via, value, via),
+            format!("MUL {}, #8, {}", via, via),
+            format!("MUL {}, #8, {}", via, via),
+            format!("ADD {}, #{} & 63, {}", via, value, via),
+            format!("PUTH {}, {}", via, isa::REGISTER_NAMES[dst as usize]),
+        ]);
+    }
+    Ok(lines)
+}
+
+
+/// Assembles one instruction. `text` is the whole line, without any comment.
+pub fn assemble(text: &str, ctx: &Context) -> Result<Instruction, String> {
+    let text = text.trim();
+    let (mnemonic, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let upper = mnemonic.to_ascii_uppercase();
+    let ops = operands(rest);
+
+    match upper.as_str() {
+        "NOP" => {
+            expect_count(&upper, &ops, 0, "NOP")?;
+            Ok(Instruction::Nop)
+        },
+        "SETFLG" => flag_ops(rest),
+
+        "JA" => {
+            let (cond, target) = jump_operands(&upper, &ops, "JA [condition,] J")?;
+            Ok(Instruction::Ja { cond, addr: addr_register(target)? })
+        },
+        "JR" => {
+            let (cond, target) = jump_operands(&upper, &ops, "JR [condition,] $+N")?;
+            let offset = fit(InstrClass::Jr, "offset", &format!("The distance to {}", target),
+                relative(target, ctx)?)?;
+            Ok(Instruction::Jr { cond, offset: offset as i8 })
+        },
+
+        "STORE" => {
+            expect_count(&upper, &ops, 2, "STORE [thin_register], [[address_register]]")?;
+            Ok(Instruction::Store { src: register(ops[0])?, addr: memory(ops[1])? })
+        },
+        "LOAD" => {
+            expect_count(&upper, &ops, 2, "LOAD [[address_register]], [thin_register]")?;
+            Ok(Instruction::Load { dst: register(ops[1])?, addr: memory(ops[0])? })
+        },
+        "PUTH" => {
+            expect_count(&upper, &ops, 2, "PUTH [thin_register], [half_register]")?;
+            Ok(Instruction::PutH { half: half_register(ops[1])?, src: register(ops[0])? })
+        },
+        "GETH" => {
+            expect_count(&upper, &ops, 2, "GETH [half_register], [thin_register]")?;
+            Ok(Instruction::GetH { dst: register(ops[1])?, half: half_register(ops[0])? })
+        },
+
+        "LOADIMM" => {
+            expect_count(&upper, &ops, 2, "LOADIMM #[imm_value], [thin_register]")?;
+            if !is_immediate(ops[0]) {
+                return Err(format!("LOADIMM needs an immediate, like #4, not \"{}\"", ops[0]));
+            }
+            alu(4, register(ops[1])?, 0, ops[0], ctx)
+        },
+        "MOV" => {
+            expect_count(&upper, &ops, 2, "MOV [thin_register or #imm], [thin_register]")?;
+            alu(4, register(ops[1])?, 0, ops[0], ctx)
+        },
+        "TRA" => {
+            expect_count(&upper, &ops, 4, "TRA #[op], [thin_register], [thin_register], [thin_register]")?;
+            let op = fit(InstrClass::Tra, "op", "The ALU operation", immediate(ops[0], ctx)?)?;
+            Ok(Instruction::Tra { op: op as u8, dst: register(ops[3])?, a: register(ops[1])?, b: register(ops[2])? })
+        },
+        "IM" => {
+            expect_count(&upper, &ops, 4, "IM #[op], [thin_register], #[imm_value], [thin_register]")?;
+            let op = fit(InstrClass::Im, "op", "The ALU operation", immediate(ops[0], ctx)?)?;
+            if !is_immediate(ops[2]) {
+                return Err(format!("IM needs an immediate, like #4, not \"{}\"", ops[2]));
+            }
+            alu(op as u8, register(ops[3])?, register(ops[1])?, ops[2], ctx)
+        },
+
+        name => match alu_op(name) {
+            Some(op) => {
+                expect_count(&upper, &ops, 3,
+                    &format!("{} [thin_register], [thin_register or #imm], [thin_register]", upper))?;
+                alu(op, register(ops[2])?, register(ops[0])?, ops[1], ctx)
+            },
+            None => Err(format!("Unrecognized instruction \"{}\"", mnemonic)),
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(addr: u32, text: &str) -> Result<Instruction, String> {
+        let (labels, constants) = (HashMap::from([(String::from("loop"), 2)]), HashMap::new());
+        assemble(text, &Context { addr, labels: &labels, constants: &constants })
+    }
+
+    #[test]
+    fn conditions() {
+        assert_eq!(at(0, "JA GT|EQ, J"), Ok(Instruction::Ja { cond: 0b110, addr: 10 }));
+        assert_eq!(at(0, "JA ls | gt, J"), Ok(Instruction::Ja { cond: 0b101, addr: 10 }));
+        assert_eq!(at(0, "JA NEVER, J"), Ok(Instruction::Ja { cond: 0, addr: 10 }));
+        assert_eq!(at(0, "JA ALWAYS, J"), at(0, "JA J"));
+        assert_eq!(at(0, "JA J"), Ok(Instruction::Ja { cond: COND_ALWAYS, addr: 10 }));
+
+        for text in ["JA GT|EQ|LS, J", "JR LS|EQ|GT, loop"] {
+            let error = at(0, text).unwrap_err();
+            assert!(error.contains("is how an unconditional jump is encoded"), "{}", error);
+        }
+        assert!(at(0, "JA GT|OV, J").unwrap_err().starts_with("Unknown condition \"OV\""));
+    }
+
+    #[test]
+    fn setflg_takes_spaces_or_commas() {
+        let expected = Ok(Instruction::SetFlg { gt: FlagOp::Set, eq: FlagOp::Clear, ls: FlagOp::Keep, ov: FlagOp::Toggle });
+        for text in ["SETFLG +GT -EQ ~OV", "SETFLG +GT, -EQ, ~OV", "SETFLG +GT,-EQ ,~OV"] {
+            assert_eq!(at(0, text), expected, "{}", text);
+        }
+        assert_eq!(at(0, "SETFLG"), Ok(Instruction::SetFlg {
+            gt: FlagOp::Keep, eq: FlagOp::Keep, ls: FlagOp::Keep, ov: FlagOp::Keep }));
+        assert!(at(0, "SETFLG GT").is_err());
+        assert!(at(0, "SETFLG +GT, +XX").is_err());
+    }
+
+    #[test]
+    fn jr_targets_are_addresses() {
+        // a label, $ and a plain number all name where to go, not how far
+        assert_eq!(at(5, "JR loop"), Ok(Instruction::Jr { cond: COND_ALWAYS, offset: -3 }));
+        assert_eq!(at(5, "JR @loop"), at(5, "JR loop"));
+        assert_eq!(at(5, "JR $+5"), Ok(Instruction::Jr { cond: COND_ALWAYS, offset: 5 }));
+        assert_eq!(at(5, "JR $-1"), Ok(Instruction::Jr { cond: COND_ALWAYS, offset: -1 }));
+        assert_eq!(at(5, "JR 2"), Ok(Instruction::Jr { cond: COND_ALWAYS, offset: -3 }));
+        assert_eq!(at(5, "JR GT, 9"), Ok(Instruction::Jr { cond: 0b100, offset: 4 }));
+
+        // and when the number is out of reach, it says so
+        let error = at(100, "JR 5").unwrap_err();
+        assert!(error.starts_with("JR 5 goes to address 5, which is -95 words away"), "{}", error);
+        assert!(error.ends_with("Write JR $+5 to go 5 words on"), "{}", error);
+        let error = at(100, "JR $+64").unwrap_err();
+        assert!(error.starts_with("The distance to $+64 is 64"), "{}", error);
+    }
+
+    #[test]
+    fn loadaddr_builds_both_halves() {
+        for (value, top, bottom) in [("loop", 0, 2), ("0x0001_2345", 1, 0x2345), ("0xFFFF_FFFF", 0xFFFF, 0xFFFF)] {
+            let lines = expand(&format!("LOADADDR {}, D, K", value)).unwrap().unwrap();
+            assert_eq!(lines.len(), 16);
+
+            // run it, as far as MOV, ADD, MUL and PUTH go
+            let (mut regs, mut halves) = ([0u64; 8], [None; 16]);
+            for line in &lines {
+                match at(0, line) {
+                    Ok(Instruction::Im { op, dst, a, imm }) => regs[dst as usize] = match op {
+                        4 => imm as u64,
+                        5 => regs[a as usize] + imm as u64,
+                        7 => regs[a as usize] * imm as u64,
+                        _ => panic!("{}", line),
+                    },
+                    Ok(Instruction::PutH { half, src }) => halves[half as usize] = Some(regs[src as usize]),
+                    other => panic!("{}: {:?}", line, other),
+                }
+            }
+            assert_eq!((halves[13], halves[12]), (Some(top), Some(bottom)), "{}", value);
+            assert_eq!(halves.iter().flatten().count(), 2);
+        }
+
+        assert!(expand("LOADADDR @loop, C, P").unwrap().unwrap_err().starts_with("LOADADDR can't write P"));
+        assert!(expand("loadaddr @loop, J").unwrap().is_err());
+        assert!(expand("LOADADDR @loop, J, C").unwrap().is_err());
+        assert_eq!(expand("LOAD [K], C"), None);
+    }
+}
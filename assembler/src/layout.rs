@@ -0,0 +1,218 @@
+/*
+LAYOUT
+
+Sections with an address go there, and can't overlap each other. Sections without one are then
+packed into the gaps that are left, in the order they are declared, by one of these strategies:
+
+    first-fit       the lowest gap the section fits in (the default)
+    best-fit        the smallest gap the section fits in, keeping big gaps for big sections
+    largest-first   first fit, but placing the biggest sections first
+
+A relative section can ask to start on a multiple of some number of words with `.name align 16:`,
+and --align sets that for every relative section that doesn't say.
+
+The .abin is one flat image from address 0 up to the end of the highest section, so every section
+has to end below MAX_IMAGE_WORDS. Anything that has to be further up (like the slow RAM at
+0x8000_0000) has to be copied there by the program when it runs.
+*/
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    FirstFit,
+    BestFit,
+    LargestFirst,
+}
+
+impl Strategy {
+    pub fn from_name(name: &str) -> Option<Strategy> {
+        match name {
+            "first-fit" => Some(Strategy::FirstFit),
+            "best-fit" => Some(Strategy::BestFit),
+            "largest-first" => Some(Strategy::LargestFirst),
+            _ => None,
+        }
+    }
+}
+
+
+/// What layout needs to know about a section.
+pub struct Block<'a> {
+    pub name: &'a str,
+    pub start: Option<u32>, // None for a relative section
+    pub len: u32,
+    pub align: u32,
+}
+
+
+const END_OF_MEMORY: u64 = 1 << 32;
+
+/// The most words a program image can have, which is 8 MB of .abin.
+pub const MAX_IMAGE_WORDS: u64 = 1 << 22;
+
+
+fn range(start: u64, len: u32) -> String {
+    format!("{:#010x}..{:#010x}", start, start + len as u64)
+}
+
+
+fn align_up(addr: u64, align: u32) -> u64 {
+    addr.div_ceil(align as u64) * align as u64
+}
+
+
+/// The start address of every block, in the same order.
+pub fn place(blocks: &[Block], strategy: Strategy) -> Result<Vec<u32>, String> {
+    let mut starts: Vec<Option<u32>> = blocks.iter().map(|b| b.start).collect();
+
+    // (start, end, index) of everything placed so far, which starts with the absolute sections
+    let mut used: Vec<(u64, u64, usize)> = Vec::new();
+    for (i, block) in blocks.iter().enumerate() {
+        let Some(start) = block.start else { continue };
+        let (start, end) = (start as u64, start as u64 + block.len as u64);
+        if end > END_OF_MEMORY {
+            return Err(format!("Section \"{}\" ({}) runs past the end of memory", block.name, range(start, block.len)));
+        }
+        if block.len == 0 {
+            continue;
+        }
+        if let Some((_, _, j)) = used.iter().find(|(s, e, _)| start < *e && *s < end) {
+            let other = &blocks[*j];
+            return Err(format!("Sections \"{}\" ({}) and \"{}\" ({}) overlap",
+                other.name, range(other.start.unwrap() as u64, other.len), block.name, range(start, block.len)));
+        }
+        used.push((start, end, i));
+    }
+
+    let mut order: Vec<usize> = (0..blocks.len()).filter(|i| blocks[*i].start.is_none()).collect();
+    if strategy == Strategy::LargestFirst {
+        order.sort_by_key(|i| std::cmp::Reverse(blocks[*i].len)); // stable, so ties keep their order
+    }
+
+    for i in order {
+        let block = &blocks[i];
+        used.sort();
+
+        // every gap the block fits in, as (where it would start, how big the gap is)
+        let mut fits = Vec::new();
+        let mut free = 0;
+        for (start, end) in used.iter().map(|(s, e, _)| (*s, *e)).chain([(END_OF_MEMORY, END_OF_MEMORY)]) {
+            let at = align_up(free, block.align);
+            if at + block.len as u64 <= start {
+                fits.push((at, start - free));
+            }
+            free = free.max(end);
+        }
+
+        let chosen = match strategy {
+            Strategy::BestFit => fits.iter().min_by_key(|(_, size)| *size),
+            Strategy::FirstFit | Strategy::LargestFirst => fits.first(),
+        };
+        let Some((at, _)) = chosen else {
+            return Err(format!("No room for section \"{}\" ({} words)", block.name, block.len));
+        };
+
+        starts[i] = Some(*at as u32);
+        if block.len > 0 {
+            used.push((*at, *at + block.len as u64, i));
+        }
+    }
+
+    Ok(starts.into_iter().map(|s| s.unwrap()).collect())
+}
+
+
+/// Checks that every block ends where the image can still reach, now that they all have a place.
+pub fn check_image(blocks: &[Block], starts: &[u32]) -> Result<(), String> {
+    let highest = blocks.iter().zip(starts).max_by_key(|(b, start)| **start as u64 + b.len as u64);
+    match highest {
+        Some((b, start)) if *start as u64 + b.len as u64 > MAX_IMAGE_WORDS => Err(format!(
+            "Section \"{}\" ends at {:#010x}, but the program is written as one image from address 0, \
+            which can only be {:#010x} words long. Sections further up have to be copied there when the program runs",
+            b.name, *start as u64 + b.len as u64, MAX_IMAGE_WORDS)),
+        _ => Ok(()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn abs(name: &str, start: u32, len: u32) -> Block<'_> {
+        Block { name, start: Some(start), len, align: 1 }
+    }
+
+    fn rel(name: &str, len: u32) -> Block<'_> {
+        Block { name, start: None, len, align: 1 }
+    }
+
+    // gaps of 10 words at 0x10 and 4 words at 0x30, then everything from 0x40 up
+    fn with_gaps<'a>(mut relative: Vec<Block<'a>>) -> Vec<Block<'a>> {
+        let mut blocks = vec![abs("start", 0, 0x10), abs("a", 0x1A, 0x16), abs("b", 0x34, 0xC)];
+        blocks.append(&mut relative);
+        blocks
+    }
+
+    #[test]
+    fn first_fit_takes_the_lowest_gap() {
+        let blocks = with_gaps(vec![rel("x", 3), rel("y", 8), rel("z", 4)]);
+        assert_eq!(place(&blocks, Strategy::FirstFit), Ok(vec![0, 0x1A, 0x34, 0x10, 0x40, 0x13]));
+    }
+
+    #[test]
+    fn best_fit_takes_the_smallest_gap() {
+        let blocks = with_gaps(vec![rel("x", 3), rel("y", 8), rel("z", 4)]);
+        assert_eq!(place(&blocks, Strategy::BestFit), Ok(vec![0, 0x1A, 0x34, 0x30, 0x10, 0x40]));
+    }
+
+    #[test]
+    fn largest_first_places_big_sections_first() {
+        let blocks = with_gaps(vec![rel("x", 3), rel("y", 8), rel("z", 3)]);
+        assert_eq!(place(&blocks, Strategy::LargestFirst), Ok(vec![0, 0x1A, 0x34, 0x30, 0x10, 0x40]));
+        assert_eq!(place(&blocks, Strategy::FirstFit), Ok(vec![0, 0x1A, 0x34, 0x10, 0x40, 0x13]));
+
+        // and keeps the order they were declared in when they're the same size
+        let blocks = vec![rel("x", 2), rel("y", 2)];
+        assert_eq!(place(&blocks, Strategy::LargestFirst), Ok(vec![0, 2]));
+    }
+
+    #[test]
+    fn alignment() {
+        let blocks = vec![abs("start", 0, 3), Block { align: 8, ..rel("x", 4) }, Block { align: 3, ..rel("y", 1) }];
+        assert_eq!(place(&blocks, Strategy::FirstFit), Ok(vec![0, 8, 3]));
+
+        // a gap that's big enough, but not once the start is aligned
+        let blocks = vec![abs("start", 0, 1), abs("a", 8, 1), Block { align: 4, ..rel("x", 5) }];
+        assert_eq!(place(&blocks, Strategy::FirstFit), Ok(vec![0, 8, 12]));
+    }
+
+    #[test]
+    fn overlaps_and_running_out_of_room() {
+        let blocks = vec![abs("start", 0, 0x10), abs("a", 0x0F, 2)];
+        assert_eq!(place(&blocks, Strategy::FirstFit),
+            Err(String::from("Sections \"start\" (0x00000000..0x00000010) and \"a\" (0x0000000f..0x00000011) overlap")));
+
+        // empty sections don't take up anything
+        let blocks = vec![abs("start", 0, 0x10), abs("a", 4, 0), rel("x", 0)];
+        assert_eq!(place(&blocks, Strategy::FirstFit), Ok(vec![0, 4, 0]));
+
+        let blocks = vec![abs("start", 0xFFFF_FFF0, 0x20)];
+        assert_eq!(place(&blocks, Strategy::FirstFit),
+            Err(String::from("Section \"start\" (0xfffffff0..0x100000010) runs past the end of memory")));
+
+        let blocks = vec![abs("start", 0, 0xFFFF_FFF0), rel("x", 0x20)];
+        assert_eq!(place(&blocks, Strategy::BestFit), Err(String::from("No room for section \"x\" (32 words)")));
+    }
+
+    #[test]
+    fn the_image_has_to_fit() {
+        let blocks = [abs("start", 0, 0x10), abs("top", 0x3F_FFF0, 0x10)];
+        assert_eq!(check_image(&blocks, &[0, 0x3F_FFF0]), Ok(()));
+
+        let blocks = [abs("start", 0, 0x10), abs("slow", 0x8000_0000, 1), rel("x", 4)];
+        let error = check_image(&blocks, &[0, 0x8000_0000, 0x10]).unwrap_err();
+        assert!(error.starts_with("Section \"slow\" ends at 0x80000001, but"), "{}", error);
+        assert!(check_image(&[], &[]).is_ok());
+    }
+}
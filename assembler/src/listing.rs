@@ -0,0 +1,155 @@
+use std::collections::BTreeMap;
+
+use isa::symbols::SymbolTable;
+
+use crate::source::{Loc, Sources};
+
+/*
+LISTINGS AND MAPS
+
+The listing is the source with what every line turned into next to it:
+
+     line  address     word  binary               source
+        4  0x00000020  4008  0100_0000_0000_1000  main: MOV #0, B
+        5                                         loop:
+
+A line that makes more than one word gets a row for each of the others under it. The map lists
+the sections by where they ended up, then every label by address, with the section it is in.
+*/
+
+
+/// Where a section ended up.
+pub struct Placed<'a> {
+    pub name: &'a str,
+    pub start: u32,
+    pub len: u32,
+}
+
+
+fn binary(word: u16) -> String {
+    let bits = format!("{:016b}", word);
+    format!("{}_{}_{}_{}", &bits[0..4], &bits[4..8], &bits[8..12], &bits[12..16])
+}
+
+
+/// `words` gives the address and word of everything each line assembled to. Each file gets its
+/// own listing, one after the other, and a file that was included twice is listed twice.
+pub fn listing(sources: &Sources, words: &BTreeMap<Loc, Vec<(u32, u16)>>) -> String {
+    let mut out = String::new();
+    for (file, source) in sources.files.iter().enumerate() {
+        if file > 0 {
+            out += "\n";
+        }
+        out += &format!("{}:\n", source.path);
+        out += &listing_file(file, &source.lines, words);
+    }
+    out
+}
+
+
+fn listing_file(file: usize, source: &[String], words: &BTreeMap<Loc, Vec<(u32, u16)>>) -> String {
+    let mut out = String::from(" line  address     word  binary               source\n");
+
+    for (num, text) in source.iter().enumerate() {
+        let num = num as u32 + 1;
+        let line_words = words.get(&Loc { file, line: num }).map_or(&[][..], |w| w.as_slice());
+
+        match line_words.split_first() {
+            Some(((addr, word), rest)) => {
+                out += &format!("{:>5}  {:#010x}  {:04x}  {}  {}\n", num, addr, word, binary(*word), text);
+                for (addr, word) in rest {
+                    out += &format!("       {:#010x}  {:04x}  {}\n", addr, word, binary(*word));
+                }
+            },
+            None => out += &format!("{:>5}  {:37}  {}\n", num, "", text),
+        }
+    }
+
+    out
+}
+
+
+pub fn map(sections: &[Placed], symbols: &SymbolTable) -> String {
+    let mut sorted: Vec<&Placed> = sections.iter().collect();
+    sorted.sort_by_key(|s| s.start);
+
+    let width = sorted.iter().map(|s| s.name.len()).max().unwrap_or(0).max(7);
+    let mut out = format!("Sections:\n  {:width$}  start       end         size\n", "section");
+    for sec in &sorted {
+        out += &format!("  {:width$}  {:#010x}  {:#010x}  {}\n",
+            sec.name, sec.start, sec.start as u64 + sec.len as u64, sec.len);
+    }
+
+    out += "\nSymbols:\n";
+    for (addr, name) in symbols.iter() {
+        let section = sorted.iter().rev()
+            .find(|s| s.start <= addr && (addr as u64) < s.start as u64 + s.len.max(1) as u64)
+            .map_or("", |s| s.name);
+        out += &format!("  {:#010x}  {:width$}  {}\n", addr, section, name);
+    }
+
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::SourceFile;
+
+    fn file(path: &str, lines: &[&str]) -> SourceFile {
+        SourceFile { path: String::from(path), lines: lines.iter().map(|l| String::from(*l)).collect() }
+    }
+
+    #[test]
+    fn listing_lines() {
+        let sources = Sources { files: vec![
+            file("main.aasm", &["main: MOV #0, B", "loop:", "  .dword 0x12345"]),
+            file("lib.aasm", &["NOP"]),
+        ] };
+        let words = BTreeMap::from([
+            (Loc { file: 0, line: 1 }, vec![(0x20, 0x4008)]),
+            (Loc { file: 0, line: 3 }, vec![(0x21, 0x0001), (0x22, 0x2345)]),
+            (Loc { file: 1, line: 1 }, vec![(0x23, 0x0000)]),
+        ]);
+
+        assert_eq!(listing(&sources, &words), "\
+main.aasm:
+ line  address     word  binary               source
+    1  0x00000020  4008  0100_0000_0000_1000  main: MOV #0, B
+    2                                         loop:
+    3  0x00000021  0001  0000_0000_0000_0001    .dword 0x12345
+       0x00000022  2345  0010_0011_0100_0101
+
+lib.aasm:
+ line  address     word  binary               source
+    1  0x00000023  0000  0000_0000_0000_0000  NOP
+");
+    }
+
+    #[test]
+    fn map_of_sections_and_labels() {
+        let sections = [
+            Placed { name: "data", start: 0x100, len: 4 },
+            Placed { name: "start", start: 0, len: 0x10 },
+            Placed { name: "empty", start: 0x10, len: 0 },
+        ];
+        let mut symbols = SymbolTable::new();
+        symbols.insert(0, "main");
+        symbols.insert(0x10, "marker");
+        symbols.insert(0x102, "table");
+
+        assert_eq!(map(&sections, &symbols), "\
+Sections:
+  section  start       end         size
+  start    0x00000000  0x00000010  16
+  empty    0x00000010  0x00000010  0
+  data     0x00000100  0x00000104  4
+
+Symbols:
+  0x00000000  start    main
+  0x00000010  empty    marker
+  0x00000102  data     table
+");
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+
+use regex::{Captures, Regex};
+
+use crate::source::{split_label, strip_comment, unquoted, Line, Loc, Sources};
+
+/*
+MACROS
+
+A macro is a named block of source with parameters, expanded wherever its name is used like an
+instruction:
+
+    .macro PUSH reg, tmp
+      STORE \reg, [K]
+      GETH K0, \tmp
+      ADD \tmp, #1, \tmp
+      PUTH \tmp, K0
+    .endm
+
+      PUSH C, H
+
+\name is replaced with the argument given for that parameter, and \@ with a number that is
+different for every expansion, so a macro can have labels of its own (skip\@:) without them
+clashing. Any other \ is left as it is, so escapes like "hi\n" and '\0' still work. Arguments are
+split at commas, but not ones in quotes or brackets, so FOO ',', (A + B) gives two arguments.
+
+Macros have to be defined before they are used, can use other macros, and can hold anything a
+line of source can, including labels and section headers. Everything an expansion makes is put
+down to the line it was used on. A macro can be used in any file after the one it is defined in.
+
+This runs before anything else, so the rest of the assembler only ever sees plain source lines.
+*/
+
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<String>,
+    loc: Loc, // where it was defined, for errors
+}
+
+
+/// How deep macros can use other macros before it's taken to be a macro using itself.
+const MAX_DEPTH: usize = 64;
+
+
+pub struct Expander {
+    macros: HashMap<String, Macro>,
+    expansions: u32, // for \@
+    param_re: Regex,
+}
+
+
+/// The line with an error, and what is wrong with it.
+pub type ExpandError = (Loc, String);
+
+
+/// Splits the arguments of a macro at the commas that aren't in quotes or brackets.
+fn arguments(text: &str) -> Vec<&str> {
+    if text.trim().is_empty() {
+        return Vec::new();
+    }
+    let mut args = Vec::new();
+    let (mut start, mut depth) = (0, 0);
+    for (i, c) in unquoted(text) {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            ',' if depth == 0 => {
+                args.push(text[start..i].trim());
+                start = i + 1;
+            },
+            _ => {},
+        }
+    }
+    args.push(text[start..].trim());
+    args
+}
+
+
+impl Expander {
+
+    pub fn new() -> Expander {
+        Expander { macros: HashMap::new(), expansions: 0, param_re: Regex::new(r"\\(@|\w+)").unwrap() }
+    }
+
+
+    /// The source with every macro definition taken out and every use expanded, each line with
+    /// the place it came from. Macros defined in one call can be used in the next.
+    pub fn expand(&mut self, source: &[Line], sources: &Sources) -> Result<Vec<Line>, ExpandError> {
+        let mut out = Vec::new();
+        let mut lines = source.iter();
+
+        while let Some(Line { loc, text }) = lines.next() {
+            let code = strip_comment(text);
+            let mut words = code.split_whitespace();
+
+            match words.next() {
+                Some(".macro") => {
+                    let rest = code[".macro".len()..].trim();
+                    let (name, params) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                    if name.is_empty() {
+                        return Err((*loc, String::from("A macro needs a name, as in .macro NAME param, param")));
+                    }
+                    if let Some(other) = self.macros.get(name) {
+                        return Err((*loc, format!("Macro {} is already defined at {}", name, sources.describe(other.loc))));
+                    }
+                    let params: Vec<String> = params.split(',')
+                        .map(|p| p.trim().to_string())
+                        .filter(|p| !p.is_empty())
+                        .collect();
+
+                    let mut body = Vec::new();
+                    loop {
+                        match lines.next() {
+                            Some(l) if strip_comment(&l.text) == ".endm" => break,
+                            Some(l) if strip_comment(&l.text).starts_with(".macro") =>
+                                return Err((l.loc, format!("Macro {} is still open. Macros can't be defined \
+                                    inside other macros", name))),
+                            Some(l) => body.push(l.text.clone()),
+                            None => return Err((*loc, format!("Macro {} has no .endm", name))),
+                        }
+                    }
+                    self.macros.insert(String::from(name), Macro { params, body, loc: *loc });
+                },
+                Some(".endm") => return Err((*loc, String::from(".endm without a .macro"))),
+                _ => self.expand_line(*loc, text, 0, &mut out)?,
+            }
+        }
+
+        Ok(out)
+    }
+
+
+    fn expand_line(&mut self, loc: Loc, line: &str, depth: usize, out: &mut Vec<Line>)
+        -> Result<(), ExpandError> {
+
+        let code = strip_comment(line);
+
+        // a label can come before a macro, as it can before an instruction
+        let (label, rest) = match split_label(code) {
+            Some((label, rest)) if !code.starts_with('.') => (Some(label), rest.trim()),
+            _ => (None, code),
+        };
+        let (name, args) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+
+        if !self.macros.contains_key(name) {
+            out.push(Line { loc, text: String::from(line) });
+            return Ok(());
+        }
+        if depth == MAX_DEPTH {
+            return Err((loc, format!("Macro {} uses itself, or goes more than {} macros deep", name, MAX_DEPTH)));
+        }
+        if let Some(label) = label {
+            out.push(Line { loc, text: format!("{}:", label) });
+        }
+
+        self.expansions += 1;
+        let unique = self.expansions.to_string();
+
+        let mac = &self.macros[name];
+        let args = arguments(args);
+        if args.len() != mac.params.len() {
+            return Err((loc, format!("Macro {} takes {} argument{} ({}), but was given {}", name,
+                mac.params.len(), if mac.params.len() == 1 { "" } else { "s" }, mac.params.join(", "), args.len())));
+        }
+
+        let body: Vec<String> = mac.body.iter().map(|body_line| {
+            self.param_re.replace_all(body_line, |caps: &Captures| {
+                let param = &caps[1];
+                if param == "@" {
+                    return unique.clone();
+                }
+                match mac.params.iter().position(|p| p == param) {
+                    Some(i) => String::from(args[i]),
+                    None => String::from(&caps[0]), // an escape, or just a \ that isn't ours
+                }
+            }).into_owned()
+        }).collect();
+
+        for body_line in body {
+            self.expand_line(loc, &body_line, depth + 1, out)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(source: &[&str]) -> Result<Vec<String>, ExpandError> {
+        let lines: Vec<Line> = source.iter().enumerate()
+            .map(|(i, text)| Line { loc: Loc { file: 0, line: i as u32 + 1 }, text: String::from(*text) })
+            .collect();
+        let out = Expander::new().expand(&lines, &Sources::default())?;
+        Ok(out.into_iter().map(|l| l.text).collect())
+    }
+
+    #[test]
+    fn parameters_and_unique_labels() {
+        let out = expand(&[
+            ".macro SKIP reg",
+            "  JR EQ, skip\\@",
+            "  MOV #1, \\reg",
+            "skip\\@:",
+            ".endm",
+            "SKIP C",
+            "here: SKIP D",
+        ]).unwrap();
+        assert_eq!(out, [
+            "  JR EQ, skip1", "  MOV #1, C", "skip1:",
+            "here:", "  JR EQ, skip2", "  MOV #1, D", "skip2:",
+        ]);
+    }
+
+    #[test]
+    fn other_escapes_are_left_alone() {
+        let out = expand(&[
+            ".macro GREET name",
+            "  .string \"hi \\name\\n\"",
+            "  MOV #'\\0', C",
+            "  .word \\nope",
+            ".endm",
+            "GREET bob",
+        ]).unwrap();
+        assert_eq!(out, ["  .string \"hi bob\\n\"", "  MOV #'\\0', C", "  .word \\nope"]);
+    }
+
+    #[test]
+    fn commas_in_quotes_and_brackets() {
+        let out = expand(&[
+            ".macro THREE a, b, c",
+            "  .word \\a, \\b, \\c",
+            ".endm",
+            "THREE ',', (1 + 2) * 3, '\\''",
+            "THREE \"x, y\", [K], lo(@table)",
+        ]).unwrap();
+        assert_eq!(out, ["  .word ',', (1 + 2) * 3, '\\''", "  .word \"x, y\", [K], lo(@table)"]);
+
+        assert_eq!(arguments("','"), ["','"]);
+        assert_eq!(arguments("(A, B), C"), ["(A, B)", "C"]);
+        assert!(arguments("  ").is_empty());
+    }
+
+    #[test]
+    fn wrong_number_of_arguments() {
+        let err = expand(&[".macro TWO a, b", ".endm", "TWO ','"]).unwrap_err();
+        assert_eq!(err, (Loc { file: 0, line: 3 }, String::from("Macro TWO takes 2 arguments (a, b), but was given 1")));
+    }
+}
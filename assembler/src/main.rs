@@ -1,148 +1,253 @@
 extern crate exitcode;
 extern crate hex;
 
-use std::fs::File;
-use std::io::prelude::*;
+mod data;
+mod expr;
+mod instructions;
+mod layout;
+mod listing;
+mod macros;
+mod source;
+
+use std::collections::{BTreeMap, HashMap};
 use std::path::Path;
+use std::rc::Rc;
 use regex::Regex;
-use std::fmt;
+
+use isa::lines::LineTable;
+use isa::symbols::SymbolTable;
+
+use source::{split_label, strip_comment, Loc, Sources};
 
 
 
 struct Section {
     name: String,
     start: Start,
+    code: Vec<Code>, // every word, assembled once every label has an address
+    lines: Vec<Loc>, // the source line of each word
+    constants: Vec<Rc<Constants>>, // the constants as they were at each word, as .set can change them
+    labels: Vec<(String, u32, Loc)>, // name, offset into the section, source line
+    align: Option<u32>, // for relative sections, what the start has to be a multiple of
+    inner_align: u32, // ... and what .align inside it needs the start to be a multiple of
     machine: Vec<u16>,
 }
 
 impl std::fmt::Debug for Section {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let start = match self.start {
-            Start::abs(st) => format!("{:#010x}", st),
-            Start::rel(st) => format!("#{}", st),
+            Start::Abs(st) => format!("{:#010x}", st),
+            Start::Rel(st) => format!("#{}", st),
         };
         write!(f, "\"{}\":, Start: {}, Machine: {:?}", self.name.as_str(), start, self.machine)
     }
 }
 
 impl Section {
+    fn new(name: &str, start: Start) -> Section {
+        Section { name: String::from(name), start, code: Vec::new(), lines: Vec::new(), constants: Vec::new(), labels: Vec::new(), align: None,
+            inner_align: 1, machine: Vec::new() }
+    }
+
     fn len(&self) -> u32 {
-        self.machine.len() as u32
+        self.code.len() as u32
     }
 
-    fn abs_loc(&self) -> Option<u32> {
-        match self.start {
-            Start::abs(x) => Some(x),
-            Start::rel(_) => None,
-        }
+    fn push(&mut self, code: Code, loc: Loc, constants: &Rc<Constants>) {
+        self.code.push(code);
+        self.lines.push(loc);
+        self.constants.push(Rc::clone(constants));
     }
 }
 
 
+enum Code {
+    Instruction(String), // the text of it
+    Data(data::Item),
+}
+
+
+
+type Constants = HashMap<String, i64>;
+
 
 enum Start {
-    abs(u32),
-    rel(usize),
+    Abs(u32),
+    Rel(usize),
 }
 
 
 
-fn assembler_error(reason: &str, line: &str) -> ! {
-    println!("AASM Interpreter Error: {}\n\t\"{}\" lmao", reason, line);
+fn assembler_error(reason: &str, place: &str, line: &str) -> ! {
+    println!("AASM Interpreter Error: {}: {}\n\t\"{}\" lmao", place, reason, line);
     std::process::exit(1);
 
 }
 
 
-fn main() {
+struct Options {
+    paths: Vec<String>,
+    output: String,
+    fill: u16, // what goes in the words between sections
+    strategy: layout::Strategy, // how relative sections are placed
+    align: u32,
+    listing: Option<String>,
+    map: Option<String>,
+}
 
-    // READ FILE TO STRING
-    let mut code_str = String::new();
 
-    let path_str = match std::env::args().nth(1) { // get file name from args
-        Some(file_path) => file_path,
-        None => {
-            println!("AASM: No file was given, aborting.");
-            std::process::exit(exitcode::USAGE);
-        }
-    };
+const USAGE: &str = "Usage: assembler [options] source.aasm [more.aasm ...]
+  -o FILE             write the program to FILE (default: source.abin, after the first file)
+  --fill WORD         put WORD in memory that no section uses (default 0, which is NOP)
+  --place STRATEGY    how sections without an address are fitted into the gaps: first-fit
+                      (default), best-fit or largest-first
+  --align N           start sections without an address on a multiple of N words (default 1)
+  --listing FILE      write every source line with its address and machine code to FILE
+  --map FILE          write where every section and label ended up to FILE
 
-    let path = Path::new(&path_str);
+Sections with the same name in different files are put together, in the order the files are
+given. The program is written as one image from address 0, so sections have to end below
+0x00400000. Also writes the labels next to the program as FILE.asym, for the debugger, disassembler and
+profiler, and the line table for the emulator's --coverage as FILE.aline";
 
-    let mut file = match File::open(&path) { // open file
-        Err(e) => {
-            println!("AASM: Could not open {}: {}", path.display(), e);
-            std::process::exit(exitcode::IOERR);
-        },
-        Ok(f) => f,
-    };
 
-    match file.read_to_string(&mut code_str) { // read file to string
-        Err(e) => {
-            println!("AASM: Could not read {}: {}", path.display(), e);
-            std::process::exit(exitcode::IOERR);
-        },
-        Ok(_) => {},
-    };
+fn usage_error(reason: &str) -> ! {
+    println!("AASM: {}\n{}", reason, USAGE);
+    std::process::exit(exitcode::USAGE);
+}
 
-    //println!("{}", code_str);
 
-    // SPLIT STRING INTO LINES
+fn parse_number(text: &str) -> Option<u32> {
+    isa::parse_number(text).ok().and_then(|n| u32::try_from(n).ok())
+}
 
-    let lines = code_str.lines();
 
+fn parse_args() -> Options {
+    let mut paths = Vec::new();
+    let mut output = None;
+    let mut fill = 0;
+    let mut strategy = layout::Strategy::FirstFit;
+    let mut align = 1;
+    let mut listing = None;
+    let mut map = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage_error(&format!("{} needs a value", arg)));
+
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(value()),
+            "--fill" => {
+                fill = parse_number(&value())
+                    .and_then(|n| u16::try_from(n).ok())
+                    .unwrap_or_else(|| usage_error("--fill needs a 16 bit word, like 0 or 0xffff"));
+            },
+            "--place" => {
+                strategy = layout::Strategy::from_name(&value())
+                    .unwrap_or_else(|| usage_error("--place needs first-fit, best-fit or largest-first"));
+            },
+            "--align" => {
+                align = parse_number(&value())
+                    .filter(|n| *n > 0)
+                    .unwrap_or_else(|| usage_error("--align needs a number of words"));
+            },
+            "--listing" => listing = Some(value()),
+            "--map" => map = Some(value()),
+            flag if flag.starts_with('-') => usage_error(&format!("Unknown option {}", flag)),
+            _ => paths.push(arg),
+        }
+    }
 
+    if paths.is_empty() {
+        usage_error("No file was given, aborting.");
+    }
+    let output = output.unwrap_or_else(|| Path::new(&paths[0]).with_extension("abin").to_string_lossy().into_owned());
+    Options { paths, output, fill, strategy, align, listing, map }
+}
 
 
+/// The smallest number that is a multiple of both, if it fits.
+fn lcm(a: u32, b: u32) -> Option<u32> {
+    fn gcd(a: u32, b: u32) -> u32 {
+        if b == 0 { a } else { gcd(b, a % b) }
+    }
+    (a as u64 * b as u64 / gcd(a, b) as u64).try_into().ok()
+}
 
 
-    // START OF THE SMART PART
+fn is_label_name(name: &str) -> bool {
+    name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
 
-    let ram: Vec<u16> = Vec::new(); //
+
+fn main() {
+
+    let opts = parse_args();
+
+    // READ FILES, WITH WHAT THEY INCLUDE
+    let (sources, files) = Sources::load(&opts.paths).unwrap_or_else(|e| {
+        println!("AASM: {}", e);
+        std::process::exit(exitcode::IOERR);
+    });
+
+
+    // START OF THE SMART PART
 
     /*
     HOW THIS WORKS:
 
-    1. Gather sections
-    2. Machine code is assembled inside the sections
-    3. Combine sections one after another
-    4. Get memory addresses for labels in RAM
+    0. Expand macros
+    1. Gather sections, and the labels, instructions and data in each one, working out constants as they come
+    2. Lay the sections out in RAM
+    3. Get memory addresses for labels in RAM
+    4. Machine code is assembled inside the sections, now that every label has an address
+    5. Combine sections and write them out
     */
 
 
 
     // REGEXS
-    let sec_re = Regex::new(r"\.(\w+)(?: +0x([0-9a-fA-F_]+))?:").unwrap();
-
-    let re_imm_values = Regex::new(r"(^|\s)#(\d+),?(\s|$)").unwrap();
-    let re_thin_registers = Regex::new(
-        r"(?:\sR(\d{1,2}),?(?:\s|$))|\s([ABCDEFGH])|(?:([PJKL]|PC).?([01])),?(?:\s|$)")
-        .unwrap();
-    let re_wide_registers = Regex::new(r"(?:^|\s)([PJKL]|PC),?(?:\s|$)")
-        .unwrap();
+    let sec_re = Regex::new(r"\.(\w+)(?: +0x([0-9a-fA-F_]+))?(?: +align +(\d+))?:").unwrap();
 
 
 
     // SECTIONS
     let mut sections: Vec<Section> = Vec::new();
 
-    sections.push(
-        Section { // if it's start section, set that up with some defaults
-            name: String::from("start"),
-            start: Start::abs(0x0000_0000),
-            machine: Vec::new(),
-        }
-    );
+    sections.push(Section::new("start", Start::Abs(0x0000_0000))); // start section, with some defaults
 
     let mut current_sec = sections.get_mut(0).unwrap();
 
 
     // ERROR MESSAGES
-    const sections_error_message: &str = "Incorrect format for start of section. Correct \
-        formats include:\n  .[section_name]:    .[section_name] 0x[ram_loc_hex]]:";
+    const SECTIONS_ERROR_MESSAGE: &str = "Incorrect format for start of section. Correct \
+        formats include:\n  .[section_name]:    .[section_name] 0x[ram_loc_hex]]:    .[section_name] align [words]:";
+
+    let error_at = |loc: Loc, reason: &str| -> ! {
+        assembler_error(reason, &sources.describe(loc), sources.text(loc))
+    };
 
     // LETS GET ASSEMBLING
-    for l in lines {
+    let mut expander = macros::Expander::new();
+    let mut expanded = Vec::new();
+    for (i, file) in files.iter().enumerate() {
+        let lines = expander.expand(file, &sources).unwrap_or_else(|(loc, e)| error_at(loc, &e));
+        expanded.extend(lines.into_iter().map(|line| (i, line)));
+    }
+
+    let mut constants: Rc<Constants> = Rc::new(HashMap::new());
+    let mut defined_with_equ: HashMap<String, Loc> = HashMap::new(); // constants that can't change
+
+    let mut file_num = 0;
+    for (file, line) in &expanded {
+        let l = line.text.as_str();
+        let loc = line.loc;
+
+        if *file != file_num { // every file on the command line starts off in .start
+            file_num = *file;
+            current_sec = sections.get_mut(0).unwrap();
+        }
         let mut words = l.split_whitespace();
 
         let first_word = match words.next() {
@@ -154,50 +259,76 @@ fn main() {
         if first_word.starts_with("//") { // ignore comments
             continue;
 
-        } else if first_word.starts_with(".") { // sections
+        } else if first_word == ".equ" || first_word == ".set" { // constants, as .equ NAME, expression
+
+            let code = strip_comment(l);
+            let rest = code[first_word.len()..].trim();
+            let Some((name, value)) = rest.split_once(',') else {
+                error_at(loc, &format!("Expected a name and a value, as in {} NAME, 4", first_word));
+            };
+            let name = name.trim();
+            if !is_label_name(name) {
+                error_at(loc, &format!("Invalid constant name \"{}\". Constants are letters, digits and _, \
+                    and can't start with a digit", name));
+            }
+            if let Some(first) = defined_with_equ.get(name) {
+                error_at(loc, &format!("Constant \"{}\" was defined with .equ at {}, so it can't change. \
+                    Use .set for constants that do", name, sources.describe(*first)));
+            }
+            if first_word == ".equ" && constants.contains_key(name) {
+                error_at(loc, &format!("Constant \"{}\" is already defined. Use .set to change it", name));
+            }
+
+            let scope = expr::Scope { constants: &constants, labels: None, here: None };
+            let value = expr::eval(value, &scope).unwrap_or_else(|e| error_at(loc, &e));
+            Rc::make_mut(&mut constants).insert(String::from(name), value);
+            if first_word == ".equ" {
+                defined_with_equ.insert(String::from(name), loc);
+            }
+
+        } else if first_word.starts_with(".") && !data::DIRECTIVES.contains(&first_word) { // sections
 
 
 
             let section_name = sec_re
                 .captures(l)
-                .unwrap_or_else(|| assembler_error(sections_error_message, l))
+                .unwrap_or_else(|| error_at(loc, SECTIONS_ERROR_MESSAGE))
                 .get(1)
-                .unwrap_or_else(|| assembler_error(sections_error_message, l))
+                .unwrap_or_else(|| error_at(loc, SECTIONS_ERROR_MESSAGE))
                 .as_str();
 
             let ram_loc_hex = sec_re // this is an Option<Match>
                 .captures(l)
-                .unwrap_or_else(|| assembler_error(sections_error_message, l))
+                .unwrap_or_else(|| error_at(loc, SECTIONS_ERROR_MESSAGE))
                 .get(2);
 
+            let align = sec_re
+                .captures(l)
+                .unwrap()
+                .get(3)
+                .map(|m| m.as_str().parse::<u32>().ok().filter(|a| *a > 0)
+                    .unwrap_or_else(|| error_at(loc, "Invalid alignment for section")));
+
+            if align.is_some() && (ram_loc_hex.is_some() || section_name == "start") {
+                error_at(loc, "Only sections without an address can be aligned");
+            }
+
             current_sec = match sections.iter_mut().find(|s: &&mut Section| s.name == section_name) {
                 Some(s) => s, // if section already in our list, give us the reference to it
                 None => { // otherwise, make it
 
                     let s = match section_name {
-                        "start" => Section { // if it's start section, set that up with some defaults
-                            name: String::from("start"),
-                            start: Start::abs(0x0000_0000),
-                            machine: Vec::new(),
-                        },
-                        other => {
+                        "start" => Section::new("start", Start::Abs(0x0000_0000)), // if it's start section, set that up with some defaults
+                        _ => {
                             match ram_loc_hex {
                                 Some(m) => {
-                                    Section {
-                                        name: String::from(section_name),
-                                        start: Start::abs( // parse hex value as we assign it to start
-                                            u32::from_str_radix(&m.as_str().replace("_", ""), 16)
-                                                .unwrap_or_else(|_| assembler_error("Invalid address value for section", l))
-                                        ),
-                                        machine: Vec::new(),
-                                    }
+                                    Section::new(section_name, Start::Abs( // parse hex value as we assign it to start
+                                        u32::from_str_radix(&m.as_str().replace("_", ""), 16)
+                                            .unwrap_or_else(|_| error_at(loc, "Invalid address value for section"))
+                                    ))
                                 },
                                 None => {
-                                    Section {
-                                        name: String::from(section_name),
-                                        start: Start::rel(sections.len()),
-                                        machine: Vec::new(),
-                                    }
+                                    Section::new(section_name, Start::Rel(sections.len()))
                                 }
                             }
                         }
@@ -211,6 +342,10 @@ fn main() {
                 }
             };
 
+            if align.is_some() {
+                current_sec.align = align;
+            }
+
 
             //println!("{:?}", sections);
             //println!("{:?}", current_sec);
@@ -219,104 +354,51 @@ fn main() {
         } else {
 
 
-            // literally just find tokens in the string
-
-            let imm_values_list: Vec<u16> = re_imm_values
-                .captures_iter(l)
-                .map(|x| u16::from_str_radix(x.get(2).unwrap().as_str(), 10)
-                    .unwrap_or_else(|_| assembler_error(
-                        &format!("Invalid immediate value. Not sure what \"{}\" is",
-                            x.get(0).unwrap().as_str()), l)
-                )).collect();
-
-            for i in &imm_values_list {
-                if *i >= 512 {
-                    assembler_error(&format!("Immediate value too large. Architecture only allows \
-                    9 bit immediates within LOADIMM and 6 bit immediates elsewhere. Your immediate \
-                    was {}", i), l);
-                } else if *i >= 64 && first_word != "LOADIMM" {
-                    assembler_error(&format!("Immediate value too large. Architecture only allows 6 \
-                    bit immediates outside of LOADIMM. Your immediate was {}", i), l);
+            let mut code = strip_comment(l); // leave out any comment at the end
+
+            if let Some((label, rest)) = split_label(code) { // a label, maybe with an instruction after it
+                let label = label.trim();
+                if !is_label_name(label) {
+                    error_at(loc, &format!("Invalid label name \"{}\". Labels are letters, digits and _, \
+                        and can't start with a digit", label));
                 }
+                current_sec.labels.push((String::from(label), current_sec.len(), loc));
+                code = rest.trim();
             }
 
-            let thin_register_list: Vec<u16> = re_thin_registers //TODO: Include R numbers in possibilities
-                .captures_iter(l)
-                .map(|x| match x.get(0).unwrap().as_str().chars().nth(1).unwrap() {
-                    'A' => 0b0000u16,
-                    'B' => 0b0001u16,
-                    'C' => 0b0010u16,
-                    'D' => 0b0011u16,
-                    'E' => 0b0100u16,
-                    'F' => 0b0101u16,
-                    'G' => 0b0110u16,
-                    'H' => 0b0111u16,
-                    'J' => 0b1000u16 +
-                            x
-                            .get(4).unwrap().as_str()
-                            .chars()
-                            .next().unwrap()
-                            .to_digit(10).unwrap()
-                            as u16,
-                    'K' => 0b1010u16 +
-                            x
-                            .get(4).unwrap().as_str()
-                            .chars()
-                            .next().unwrap()
-                            .to_digit(10).unwrap()
-                            as u16,
-                    'L' => 0b1100u16 +
-                            x
-                            .get(4).unwrap().as_str()
-                            .chars()
-                            .next().unwrap()
-                            .to_digit(10).unwrap()
-                            as u16,
-                    'P' => 0b1110u16 +
-                            x
-                            .get(4).unwrap().as_str()
-                            .chars()
-                            .next().unwrap()
-                            .to_digit(10).unwrap()
-                            as u16,
-
-                    'R' => x.get(1).unwrap().as_str().parse::<u16>().unwrap(),
-
-                     e => panic!("Unexpected item in bagging area: {}. (this should be an unreachable state.)", x.get(0).unwrap().as_str())
-                })
-                .collect();
-
-            let wide_register_list: Vec<u16> = re_wide_registers
-                .captures_iter(l)
-                .map(|x| match x.get(1).unwrap().as_str().chars().next().unwrap() {
-                    'J' => 0b00u16,
-                    'K' => 0b01u16,
-                    'L' => 0b10u16,
-                    'P' => 0b11u16,
-                     _  => panic!("Unexpected item in bagging area. (this should be an unreachable state.)")
-                })
-                .collect();
-
-
-
-            match first_word {
-                "LOADIMM" => {
-
-                    if imm_values_list.len() == 1 && thin_register_list.len() == 1 {
-                        let imm = imm_values_list.get(0).unwrap();
-                        let reg = thin_register_list.get(0).unwrap();
-
-                        let machine: u16 = 0b0100_0000_0000_0000 | imm << 6; // does not require a source destination
-                        current_sec.machine.push(machine);
-
-                    } else {
-                        assembler_error(
-                            "Pattern doesn't match expected pattern for LOADIMM. Expected pattern \
-                            is:\n  LOADIMM #[imm_value], [thin_register]", l);
-                    }
-
-                },
-                &_ => todo!("Unrecognized word.")
+            let (directive, rest) = code.split_once(char::is_whitespace).unwrap_or((code, ""));
+            if data::DIRECTIVES.contains(&directive) {
+                // a relative section doesn't have an address yet, so this is from its start
+                let at = current_sec.len() as u64 + match current_sec.start {
+                    Start::Abs(start) => start as u64,
+                    Start::Rel(_) => 0,
+                };
+                let room = layout::MAX_IMAGE_WORDS.saturating_sub(at);
+                match data::parse(directive, rest, &constants, room).unwrap_or_else(|e| error_at(loc, &e)) {
+                    data::Data::Words(items) => for item in items {
+                        current_sec.push(Code::Data(item), loc, &constants);
+                    },
+                    data::Data::Align(align) => {
+                        // ... so it has to start aligned too
+                        if let Start::Rel(_) = current_sec.start {
+                            current_sec.inner_align = lcm(current_sec.inner_align, align)
+                                .unwrap_or_else(|| error_at(loc, "The section can't be aligned to all of its .aligns at once"));
+                        }
+                        if at.next_multiple_of(align as u64) > layout::MAX_IMAGE_WORDS {
+                            error_at(loc, &format!("Aligning to {} goes past the end of the image at {:#010x}",
+                                align, layout::MAX_IMAGE_WORDS));
+                        }
+                        for _ in at..at.next_multiple_of(align as u64) {
+                            current_sec.push(Code::Data(data::Item::Value(0)), loc, &constants);
+                        }
+                    },
+                }
+            } else if let Some(lines) = instructions::expand(code) {
+                for line in lines.unwrap_or_else(|e| error_at(loc, &e)) {
+                    current_sec.push(Code::Instruction(line), loc, &constants);
+                }
+            } else if !code.is_empty() {
+                current_sec.push(Code::Instruction(String::from(code)), loc, &constants);
             }
         }
 
@@ -325,42 +407,134 @@ fn main() {
 
     //println!("{:?}", sections);
 
-    let mut ram_prelim: Vec<Option<u16>> = vec![]; // filling this up with 16 bit instructions to eventually be written to file
+    // LAYOUT
 
-    let mut rel_sections: Vec<&Section> = vec![]; // save the sections that can be put anywhere
+    let blocks: Vec<layout::Block> = sections.iter().map(|sec| layout::Block {
+        name: &sec.name,
+        start: match sec.start {
+            Start::Abs(start) => Some(start),
+            Start::Rel(_) => None,
+        },
+        len: sec.len(),
+        align: {
+            let align = sec.align.unwrap_or(opts.align);
+            lcm(align, sec.inner_align).unwrap_or_else(|| {
+                println!("AASM Layout Error: Section \"{}\" can't be aligned to {} and to its .align {} at once",
+                    sec.name, align, sec.inner_align);
+                std::process::exit(1);
+            })
+        },
+    }).collect();
+
+    let starts = layout::place(&blocks, opts.strategy)
+        .and_then(|starts| layout::check_image(&blocks, &starts).map(|()| starts))
+        .unwrap_or_else(|e| {
+            println!("AASM Layout Error: {}", e);
+            std::process::exit(1);
+        });
+
+    // LABELS
+
+    // now that every section has a place, so does every label
+    let mut labels: HashMap<String, u32> = HashMap::new();
+    let mut defined_on: HashMap<String, Loc> = HashMap::new(); // where each label is, for errors
+    let mut symbols = SymbolTable::new();
+
+    for (sec, start) in sections.iter().zip(&starts) {
+        for (name, offset, line) in &sec.labels {
+            if let Some(first) = defined_on.get(name) {
+                error_at(*line, &format!("Duplicate label \"{}\", first defined at {}", name, sources.describe(*first)));
+            }
+            labels.insert(name.clone(), start + offset);
+            defined_on.insert(name.clone(), *line);
+            symbols.insert(start + offset, name);
+        }
+    }
 
-    let mut occupied_space: Vec<(u32, u32)> = vec![]; // store the start and end of the spots of memory taken up by the sections.
 
+    // SECOND PASS
 
-    for sec in &sections { // push rel sections to rel_sections
-        match sec.start {
-            Start::rel(_) => rel_sections.push(sec),
-            Start::abs(start) => {
+    for (sec, start) in sections.iter_mut().zip(&starts) {
+        for (i, code) in sec.code.iter().enumerate() {
+            let ctx = instructions::Context { addr: start + i as u32, labels: &labels, constants: &sec.constants[i] };
+            let loc = sec.lines[i];
 
-                let end = start + sec.len();
-                occupied_space.push((start, end));
-                let mut pos = start as usize;
-                for item in &sec.machine {
-                    match ram_prelim.get(pos) {
-                        None => {
-                            ram_prelim[pos] = Some(*item);
-                        }
-                        Some(_) => assembler_error("Overlapping sections.",
-                            &format!("{:?}", sec)
-                        ),
-                    }
-                }
+            let machine = match code {
+                Code::Instruction(text) => {
+                    let instr = instructions::assemble(text, &ctx).unwrap_or_else(|e| error_at(loc, &e));
+                    isa::encode(&instr).unwrap_or_else(|e| error_at(loc, &e.to_string()))
+                },
+                Code::Data(item) => data::assemble(item, &ctx).unwrap_or_else(|e| error_at(loc, &e)),
+            };
+            sec.machine.push(machine);
+        }
+    }
 
-            },
+    let mut ram_prelim: Vec<Option<u16>> = vec![]; // filling this up with 16 bit instructions to eventually be written to file
+
+    let mut line_table = LineTable::new(); // which source line every word came from, for the emulator
+
+    for (sec, start) in sections.iter().zip(&starts) {
+        for (i, item) in sec.machine.iter().enumerate() {
+            let pos = *start as usize + i;
+            if let Code::Instruction(_) = sec.code[i] { // data never runs, so it has no line to cover
+                line_table.insert(pos as u32, sources.path(sec.lines[i]), sec.lines[i].line);
+            }
+            if ram_prelim.len() <= pos {
+                ram_prelim.resize(pos + 1, None);
+            }
+            ram_prelim[pos] = Some(*item); // layout has already made sure nothing overlaps
         }
     }
 
 
+    // OUTPUT
 
-    //println!("{:?}", occupied_space);
+    // two bytes a word, big end first, which is how the emulator loads it
+    let ram: Vec<u8> = ram_prelim.iter()
+        .flat_map(|word| word.unwrap_or(opts.fill).to_be_bytes())
+        .collect();
 
-    //println!("{:?}", abs_sections);
+    let out_path = Path::new(&opts.output);
+    if let Err(e) = std::fs::write(out_path, &ram) {
+        println!("AASM: Could not write {}: {}", out_path.display(), e);
+        std::process::exit(exitcode::IOERR);
+    }
 
+    let line_path = out_path.with_extension("aline");
+    if let Err(e) = std::fs::write(&line_path, line_table.to_text()) {
+        println!("AASM: Could not write {}: {}", line_path.display(), e);
+        std::process::exit(exitcode::IOERR);
+    }
+
+    let symbol_path = out_path.with_extension("asym");
+    if let Err(e) = std::fs::write(&symbol_path, symbols.to_text()) {
+        println!("AASM: Could not write {}: {}", symbol_path.display(), e);
+        std::process::exit(exitcode::IOERR);
+    }
+
+    if let Some(listing_path) = &opts.listing {
+        let mut words: BTreeMap<Loc, Vec<(u32, u16)>> = BTreeMap::new();
+        for (sec, start) in sections.iter().zip(&starts) {
+            for (i, word) in sec.machine.iter().enumerate() {
+                words.entry(sec.lines[i]).or_default().push((start + i as u32, *word));
+            }
+        }
+        if let Err(e) = std::fs::write(listing_path, listing::listing(&sources, &words)) {
+            println!("AASM: Could not write {}: {}", listing_path, e);
+            std::process::exit(exitcode::IOERR);
+        }
+    }
 
+    if let Some(map_path) = &opts.map {
+        let placed: Vec<listing::Placed> = sections.iter().zip(&starts)
+            .map(|(sec, start)| listing::Placed { name: &sec.name, start: *start, len: sec.len() })
+            .collect();
+        if let Err(e) = std::fs::write(map_path, listing::map(&placed, &symbols)) {
+            println!("AASM: Could not write {}: {}", map_path, e);
+            std::process::exit(exitcode::IOERR);
+        }
+    }
 
+    println!("AASM: Wrote {} words to {}", ram_prelim.len(), out_path.display());
 }
@@ -0,0 +1,182 @@
+use std::path::{Path, PathBuf};
+
+/*
+SOURCE FILES
+
+Every file given on the command line is read in turn, and `.include "path"` pulls another file in
+where it stands, with the path taken from the directory of the file that includes it:
+
+    .include "lib/print.aasm"
+
+A file can be included any number of times, but not from inside itself, even by way of other
+files. Every line keeps the file and line it came from, so errors, listings and the line table can
+point at the right place.
+
+Comments start at // and labels end at :, except in quotes, so .string "http://a: b" and
+MOV #':', C are left whole.
+*/
+
+
+/// Where a line came from: an index into Sources::files, and the line in it, counting from 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Loc {
+    pub file: usize,
+    pub line: u32,
+}
+
+
+#[derive(Clone, Debug)]
+pub struct Line {
+    pub loc: Loc,
+    pub text: String,
+}
+
+
+pub struct SourceFile {
+    pub path: String,
+    pub lines: Vec<String>,
+}
+
+
+#[derive(Default)]
+pub struct Sources {
+    pub files: Vec<SourceFile>,
+}
+
+
+/// The characters of `text` that aren't in a character or string, with where they are.
+pub fn unquoted(text: &str) -> impl Iterator<Item = (usize, char)> + '_ {
+    let (mut quote, mut escaped) = (None, false);
+    text.char_indices().filter(move |&(_, c)| {
+        match quote {
+            _ if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {},
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None => return true,
+        }
+        false
+    })
+}
+
+
+/// The line without the comment at the end of it, if there is one.
+pub fn strip_comment(line: &str) -> &str {
+    let end = unquoted(line)
+        .find(|&(i, c)| c == '/' && line[i + 1..].starts_with('/'))
+        .map_or(line.len(), |(i, _)| i);
+    line[..end].trim()
+}
+
+
+/// The label at the start of a line of code and what comes after it, if it has one.
+pub fn split_label(code: &str) -> Option<(&str, &str)> {
+    let (colon, _) = unquoted(code).find(|&(_, c)| c == ':')?;
+    Some((&code[..colon], &code[colon + 1..]))
+}
+
+
+impl Sources {
+
+    /// Reads every file on the command line, giving the lines of each one with the includes in it
+    /// filled in.
+    pub fn load(paths: &[String]) -> Result<(Sources, Vec<Vec<Line>>), String> {
+        let mut sources = Sources::default();
+        let mut files = Vec::new();
+        for path in paths {
+            let mut lines = Vec::new();
+            sources.read(Path::new(path), None, &mut Vec::new(), &mut lines)?;
+            files.push(lines);
+        }
+        Ok((sources, files))
+    }
+
+
+    /// `file:line`, for pointing at a line in errors.
+    pub fn describe(&self, loc: Loc) -> String {
+        format!("{}:{}", self.files[loc.file].path, loc.line)
+    }
+
+    pub fn path(&self, loc: Loc) -> &str {
+        &self.files[loc.file].path
+    }
+
+    /// The text of the line, as it is in the file.
+    pub fn text(&self, loc: Loc) -> &str {
+        &self.files[loc.file].lines[loc.line as usize - 1]
+    }
+
+
+    fn read(&mut self, path: &Path, from: Option<Loc>, stack: &mut Vec<PathBuf>, out: &mut Vec<Line>)
+        -> Result<(), String> {
+
+        // errors are put down to the .include, if there is one
+        let at = |this: &Sources, msg: String| match from {
+            Some(loc) => format!("{}: {}", this.describe(loc), msg),
+            None => msg,
+        };
+
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| at(self, format!("Could not read {}: {}", path.display(), e)))?;
+
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        if let Some(first) = stack.iter().position(|p| *p == canonical) {
+            let chain: Vec<String> = stack[first..].iter().chain([&canonical])
+                .map(|p| p.display().to_string())
+                .collect();
+            return Err(at(self, format!("{} includes itself: {}", path.display(), chain.join(" -> "))));
+        }
+
+        let file = self.files.len();
+        self.files.push(SourceFile {
+            path: path.display().to_string(),
+            lines: text.lines().map(String::from).collect(),
+        });
+
+        stack.push(canonical);
+        for num in 0..self.files[file].lines.len() {
+            let loc = Loc { file, line: num as u32 + 1 };
+            let line = self.files[file].lines[num].clone();
+            let code = strip_comment(&line);
+
+            match code.strip_prefix(".include") {
+                Some(rest) if rest.is_empty() || rest.starts_with(char::is_whitespace) => {
+                    let included = rest.trim().strip_prefix('"').and_then(|r| r.strip_suffix('"'))
+                        .ok_or_else(|| format!("{}: Expected a path in quotes, as in .include \"lib.aasm\"",
+                            self.describe(loc)))?;
+                    let dir = path.parent().unwrap_or(Path::new(""));
+                    self.read(&dir.join(included), Some(loc), stack, out)?;
+                },
+                _ => out.push(Line { loc, text: line }),
+            }
+        }
+        stack.pop();
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comments_outside_quotes() {
+        assert_eq!(strip_comment("  NOP // stop"), "NOP");
+        assert_eq!(strip_comment(".string \"http://x\" // a link"), ".string \"http://x\"");
+        assert_eq!(strip_comment("MOV #'/', C//"), "MOV #'/', C");
+        assert_eq!(strip_comment(".string \"say \\\"//\\\"\""), ".string \"say \\\"//\\\"\"");
+        assert_eq!(strip_comment("// all of it"), "");
+    }
+
+    #[test]
+    fn labels_outside_quotes() {
+        assert_eq!(split_label("loop: JR loop"), Some(("loop", " JR loop")));
+        assert_eq!(split_label("s: .string \"a: b\""), Some(("s", " .string \"a: b\"")));
+        assert_eq!(split_label(".string \"a: b\""), None);
+        assert_eq!(split_label("MOV #':', A"), None);
+        assert_eq!(split_label(".word '\\'', ':'"), None);
+    }
+}
@@ -0,0 +1,452 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+
+/// The files of one test, in a directory of their own.
+struct Project {
+    dir: PathBuf,
+}
+
+/// What the assembler said, and the words it wrote if it got that far.
+struct Output {
+    ok: bool,
+    stdout: String,
+    words: Vec<u16>,
+}
+
+impl Project {
+    fn new(name: &str, files: &[(&str, &str)]) -> Project {
+        let dir = std::env::temp_dir().join(format!("aasm-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (path, text) in files {
+            std::fs::write(dir.join(path), text).unwrap();
+        }
+        Project { dir }
+    }
+
+    fn read(&self, file: &str) -> String {
+        std::fs::read_to_string(self.dir.join(file)).unwrap()
+    }
+
+    /// Assembles the files given, in order, into out.abin.
+    fn assemble(&self, files: &[&str], options: &[&str]) -> Output {
+        let out = Command::new(env!("CARGO_BIN_EXE_assembler"))
+            .current_dir(&self.dir)
+            .args(files)
+            .args(["-o", "out.abin"])
+            .args(options)
+            .output()
+            .unwrap();
+        let bytes = if out.status.success() { std::fs::read(self.dir.join("out.abin")).unwrap() } else { Vec::new() };
+        Output {
+            ok: out.status.success(),
+            stdout: String::from_utf8_lossy(&out.stdout).into_owned(),
+            words: bytes.chunks(2).map(|b| u16::from_be_bytes([b[0], b[1]])).collect(),
+        }
+    }
+}
+
+impl Drop for Project {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+
+/// Assembles one file, which has to work.
+fn words(name: &str, source: &str) -> Vec<u16> {
+    let project = Project::new(name, &[("main.aasm", source)]);
+    let out = project.assemble(&["main.aasm"], &[]);
+    assert!(out.ok, "{}", out.stdout);
+    out.words
+}
+
+/// Assembles one file, which has to fail, and gives the reason.
+fn error(name: &str, source: &str) -> String {
+    let project = Project::new(name, &[("main.aasm", source)]);
+    let out = project.assemble(&["main.aasm"], &[]);
+    assert!(!out.ok, "assembled, but shouldn't have");
+    out.stdout
+}
+
+fn encode(instr: isa::Instruction) -> u16 {
+    isa::encode(&instr).unwrap()
+}
+
+
+// LABELS
+
+#[test]
+fn undefined_labels() {
+    let out = error("undefined", ".start:\n  MOV #@nowhere.lo, C\n");
+    assert!(out.contains("main.aasm:2: Undefined label \"nowhere\" in \"#@nowhere.lo\""), "{}", out);
+}
+
+#[test]
+fn duplicate_labels() {
+    let out = error("duplicate", ".start:\nx: NOP\n.later:\nx: NOP\n");
+    assert!(out.contains("main.aasm:4: Duplicate label \"x\", first defined at main.aasm:2"), "{}", out);
+
+    // in different files too
+    let project = Project::new("duplicate-files", &[("a.aasm", "x: NOP\n"), ("b.aasm", "x: NOP\n")]);
+    let out = project.assemble(&["a.aasm", "b.aasm"], &[]);
+    assert!(!out.ok);
+    assert!(out.stdout.contains("b.aasm:1: Duplicate label \"x\", first defined at a.aasm:1"), "{}", out.stdout);
+}
+
+#[test]
+fn labels_in_later_sections() {
+    let words = words("forward", "\
+.start:
+    MOV #@later.lo, C
+    .word @later, @far.hi
+    NOP
+.data 0x20:
+later: .word 7
+.high 0x0001_0000:
+far: NOP
+");
+    assert_eq!(&words[..3], &[encode(isa::Instruction::Im { op: 4, dst: 2, a: 0, imm: 0x20 }), 0x20, 1]);
+    assert_eq!(words[0x20], 7);
+    assert_eq!(words.len(), 0x1_0001);
+}
+
+#[test]
+fn address_halves_too_big_for_an_immediate() {
+    let out = error("too-big", ".start:\n  MOV #@far.lo, C\n.data 0x100:\nfar: .word 1\n");
+    assert!(out.contains("The immediate #@far.lo is 256 (0x100), but IM only has 6 bits for it, so it has to \
+        be from 0 to 63. To get an address that big into an address register, use LOADADDR @name, C, J"), "{}", out);
+}
+
+#[test]
+fn address_built_in_a_register() {
+    let words = words("loadaddr", "\
+.start:
+    LOADADDR @table, C, J
+    LOAD [J], D
+    NOP
+.table 0x0001_2345:
+table: .word 1
+");
+    let im = |op, a, imm| encode(isa::Instruction::Im { op, dst: 2, a, imm });
+    let (mul, puth) = (im(7, 2, 8), |half| encode(isa::Instruction::PutH { half, src: 2 }));
+    assert_eq!(&words[..18], &[
+        im(4, 0, 0), mul, mul, im(5, 2, 0), mul, mul, im(5, 2, 1), puth(11),        // J1 = 0x0001
+        im(4, 0, 2), mul, mul, im(5, 2, 0x0D), mul, mul, im(5, 2, 0x05), puth(10),  // J0 = 0x2345
+        encode(isa::Instruction::Load { dst: 3, addr: 10 }),
+        encode(isa::Instruction::Nop),
+    ]);
+}
+
+#[test]
+fn address_halves_from_memory() {
+    // the long way round, with the halves kept as data below 64
+    let words = words("from-memory", "\
+.pointers 0x0030:
+table_at: .word @table.hi, @table.lo
+.start:
+    MOV #table_at, C
+    PUTH C, K0
+    MOV #0, C
+    PUTH C, K1
+    LOAD [K], C
+    PUTH C, J1
+    MOV #table_at + 1, C
+    PUTH C, K0
+    LOAD [K], C
+    PUTH C, J0
+    NOP
+.table 0x0001_2345:
+table: .word 1
+");
+    assert_eq!(&words[0x30..0x32], &[0x0001, 0x2345]);
+    assert_eq!(words[3], encode(isa::Instruction::PutH { half: 13, src: 2 }));
+}
+
+
+#[test]
+fn sections_past_the_end_of_the_image() {
+    let project = Project::new("image-size", &[("main.aasm", ".start:\n  NOP\n.slow 0x8000_0000:\n  .word 1\n")]);
+    let out = project.assemble(&["main.aasm"], &[]);
+    assert!(!out.ok);
+    assert!(out.stdout.contains("Section \"slow\" ends at 0x80000001"), "{}", out.stdout);
+    assert!(!project.dir.join("out.abin").exists());
+}
+
+
+// LINE TABLES
+
+#[test]
+fn line_table_through_macros_and_includes() {
+    let project = Project::new("line-table", &[
+        ("main.aasm", "\
+.macro bump reg
+  ADD \\reg, #1, \\reg
+  ADD \\reg, #1, \\reg
+.endm
+.start:
+  MOV #1, A
+  bump B
+  .include \"lib.aasm\"
+.tail 0x10:
+  NOP
+  .word 7
+"),
+        ("lib.aasm", "\
+.lib 0x20:
+twice:
+  ADD A, A, A
+  NOP
+"),
+    ]);
+    let out = project.assemble(&["main.aasm"], &[]);
+    assert!(out.ok, "{}", out.stdout);
+
+    let lines = isa::lines::LineTable::parse(&project.read("out.aline")).unwrap();
+    let table: Vec<(u32, &str, u32)> = lines.iter().collect();
+    assert_eq!(table, [
+        (0x00, "main.aasm", 6),
+        (0x01, "main.aasm", 7), // both words of the macro go to where it was used
+        (0x02, "main.aasm", 7),
+        (0x10, "main.aasm", 10),
+        (0x20, "lib.aasm", 3),
+        (0x21, "lib.aasm", 4),
+    ]);
+    assert_eq!(lines.line_at(0x11), None); // data
+}
+
+
+// LISTINGS AND MAPS
+
+#[test]
+fn listing_and_map_files() {
+    let project = Project::new("listing", &[("main.aasm", ".start:\nmain: NOP\n.data:\ntable: .word 1, 2\n")]);
+    let out = project.assemble(&["main.aasm"], &["--listing", "out.lst", "--map", "out.map"]);
+    assert!(out.ok, "{}", out.stdout);
+
+    assert_eq!(project.read("out.lst"), "\
+main.aasm:
+ line  address     word  binary               source
+    1                                         .start:
+    2  0x00000000  0000  0000_0000_0000_0000  main: NOP
+    3                                         .data:
+    4  0x00000001  0001  0000_0000_0000_0001  table: .word 1, 2
+       0x00000002  0002  0000_0000_0000_0010
+");
+    assert_eq!(project.read("out.map"), "\
+Sections:
+  section  start       end         size
+  start    0x00000000  0x00000001  1
+  data     0x00000001  0x00000003  2
+
+Symbols:
+  0x00000000  start    main
+  0x00000001  data     table
+");
+}
+
+
+// CONSTANTS
+
+#[test]
+fn set_changes_constants_from_there_on() {
+    let words = words("set", "\
+.set N, 1
+.word N
+.set N, N + 1
+.word N, N * 2
+.equ TOP, N << 8
+.word TOP
+");
+    assert_eq!(words, [1, 2, 4, 0x200]);
+}
+
+#[test]
+fn equ_constants_cant_change() {
+    let out = error("equ-twice", ".equ N, 1\n.equ N, 2\n");
+    assert!(out.contains("main.aasm:2: Constant \"N\" was defined with .equ at main.aasm:1"), "{}", out);
+
+    let out = error("equ-then-set", ".equ N, 1\n.set N, 2\n");
+    assert!(out.contains("main.aasm:2: Constant \"N\" was defined with .equ at main.aasm:1, so it can't change. \
+        Use .set for constants that do"), "{}", out);
+
+    let out = error("set-then-equ", ".set N, 1\n.equ N, 2\n");
+    assert!(out.contains("main.aasm:2: Constant \"N\" is already defined. Use .set to change it"), "{}", out);
+}
+
+#[test]
+fn constants_are_worked_out_where_they_are() {
+    let out = error("later", ".equ A, B + 1\n.equ B, 1\n");
+    assert!(out.contains("\"B\" isn't a constant defined above here"), "{}", out);
+
+    let out = error("label", "start: NOP\n.equ A, start\n");
+    assert!(out.contains("labels can't be used in constants"), "{}", out);
+
+    let out = error("name", ".equ 1A, 1\n");
+    assert!(out.contains("Invalid constant name \"1A\""), "{}", out);
+}
+
+
+// DATA
+
+#[test]
+fn quotes_hide_comments_and_labels() {
+    let words = words("quotes", "\
+.string \"http://x\" // the tail stays
+.string \"a: b\"
+s: .string \"c:\"
+.word ':', '/'
+MOV #':' - 50, A
+");
+    let text = |s: &str| s.chars().map(|c| c as u16).collect::<Vec<u16>>();
+    assert_eq!(&words[..8], &text("http://x")[..]);
+    assert_eq!(&words[8..12], &text("a: b")[..]);
+    assert_eq!(&words[12..16], &[b'c' as u16, b':' as u16, b':' as u16, b'/' as u16]);
+    assert_eq!(words[16], encode(isa::Instruction::Im { op: 4, dst: 0, a: 0, imm: 8 }));
+}
+
+#[test]
+fn data_in_sections() {
+    let words = words("data", "\
+.start:
+    NOP
+.table align 4:
+table: .dword @table, 0x12345
+    .align 8
+end: .word @end - @table
+");
+    // the .align 8 needs the section to start on a multiple of 8 too
+    assert_eq!(words, [0, 0, 0, 0, 0, 0, 0, 0, 0, 8, 1, 0x2345, 0, 0, 0, 0, 8]);
+}
+
+#[test]
+fn data_past_the_end_of_the_image() {
+    // turned away as they're read, without making billions of words first
+    let out = error("huge-space", ".start:
+  NOP
+.big 0x0010_0000:
+  .space 4000000000
+");
+    assert!(out.contains("The count is 4000000000, but there's only room for 3145728 more words before the end \
+        of the image at 0x00400000"), "{}", out);
+    let out = error("huge-fill", ".start:
+  NOP
+  .fill 0x40_0000, 'x'
+");
+    assert!(out.contains("only room for 4194303 more words"), "{}", out);
+    let out = error("huge-align", ".start:
+  NOP
+  .align 0x8000_0000
+");
+    assert!(out.contains("Aligning to 2147483648 goes past the end of the image at 0x00400000"), "{}", out);
+}
+
+
+// LITERALS AND RANGES
+
+#[test]
+fn every_kind_of_literal() {
+    let words = words("literals", "\
+MOV #0x3F, A
+MOV #0b10_1010, B
+MOV #0o17, C
+MOV #'\\n', D
+MOV #'A' - 64, E
+.word 0xBEEF, 0b1111_0000_1111_0000, 0o177777, 'A', '\\\\', ',', '\\''
+");
+    let mov = |dst, imm| encode(isa::Instruction::Im { op: 4, dst, a: 0, imm });
+    assert_eq!(words, [mov(0, 63), mov(1, 42), mov(2, 15), mov(3, 10), mov(4, 1),
+        0xBEEF, 0xF0F0, 0xFFFF, 65, 92, 44, 39]);
+}
+
+#[test]
+fn values_are_checked_against_their_field() {
+    let out = error("imm", "MOV #0x40, C\n");
+    assert!(out.contains("The immediate #0x40 is 64 (0x40), but IM only has 6 bits for it, so it has to be \
+        from 0 to 63"), "{}", out);
+
+    let out = error("negative-imm", "ADD A, #-1, C\n");
+    assert!(out.contains("The immediate #-1 is -1, but IM only has 6 bits for it, so it has to be from 0 to 63"), "{}", out);
+
+    let out = error("jr", "JR $+64\n");
+    assert!(out.contains("The distance to $+64 is 64 (0x40), but JR only has 7 signed bits for it, so it has to be \
+        from -64 to 63"), "{}", out);
+
+    let out = error("op", "TRA #0b1_0000, A, B, C\n");
+    assert!(out.contains("The ALU operation is 16 (0x10), but TRA only has 4 bits for it, so it has to be from 0 to 15"), "{}", out);
+
+    let out = error("word", ".word 0x1_0000\n");
+    assert!(out.contains("0x1_0000 is 65536, which doesn't fit in 16 bits, so it has to be from -32768 to 65535"), "{}", out);
+
+    let out = error("dword", ".dword 0o40000000000\n");
+    assert!(out.contains("0o40000000000 is 4294967296, which doesn't fit in 32 bits"), "{}", out);
+}
+
+#[test]
+fn bad_literals() {
+    let out = error("binary", "MOV #0b102, C\n");
+    assert!(out.contains("Invalid number \"0b102\" in \"#0b102\""), "{}", out);
+
+    let out = error("escape", "MOV #'\\q', C\n");
+    assert!(out.contains("Unknown escape \\q in a character"), "{}", out);
+
+    let out = error("empty", ".word ''\n");
+    assert!(out.contains("A character can't be empty"), "{}", out);
+}
+
+
+// DISASSEMBLY
+
+#[test]
+fn disassembles_with_labels_and_back() {
+    let project = Project::new("disassembly", &[("main.aasm", "\
+.start:
+start:
+  MOV #5, A
+loop:
+  SUB A, #1, A
+  SETFLG -EQ
+  JR EQ, done
+  JR loop
+  JR $+1
+done:
+  STORE A, [K]
+  JA GT|EQ, J
+  PUTH A, J1
+  GETH P0, C
+  NOP
+table:
+  .word 0x0360
+")]);
+    let out = project.assemble(&["main.aasm"], &[]);
+    assert!(out.ok, "{}", out.stdout);
+
+    let symbols = isa::symbols::SymbolTable::parse(&project.read("out.asym")).unwrap();
+    let lines = disassembler::disassemble(&out.words, 0, Some(&symbols));
+    let text: Vec<&str> = lines.iter().map(|l| l.text.as_str()).collect();
+    assert_eq!(text, [
+        "MOV #5, A", "SUB A, #1, A", "SETFLG -EQ", "JR EQ, done", "JR loop", "JR done",
+        "STORE A, [K]", "JA GT|EQ, J", "PUTH A, J1", "GETH P0, C", "NOP", ".word 0x0360",
+    ]);
+    let labels: Vec<(u32, &[String])> = lines.iter()
+        .filter(|l| !l.labels.is_empty())
+        .map(|l| (l.addr, l.labels.as_slice()))
+        .collect();
+    assert_eq!(labels, [
+        (0, &[String::from("start")][..]),
+        (1, &[String::from("loop")][..]),
+        (6, &[String::from("done")][..]),
+        (11, &[String::from("table")][..]),
+    ]);
+
+    // the source it gives back assembles to the same words, and the same labels
+    std::fs::write(project.dir.join("back.aasm"), disassembler::source(&lines)).unwrap();
+    let back = project.assemble(&["back.aasm"], &[]);
+    assert!(back.ok, "{}", back.stdout);
+    assert_eq!(back.words, out.words);
+    assert_eq!(isa::symbols::SymbolTable::parse(&project.read("out.asym")).unwrap().to_text(), symbols.to_text());
+
+    // and without symbols, jumps are relative to the JR
+    let bare = disassembler::disassemble(&out.words, 0, None);
+    assert_eq!((bare[3].text.as_str(), bare[4].text.as_str()), ("JR EQ, $+3", "JR $-3"));
+}
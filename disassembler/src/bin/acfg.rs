@@ -0,0 +1,72 @@
+use std::env::args;
+use std::path::Path;
+
+use disassembler::cfg::Cfg;
+use isa::symbols::SymbolTable;
+
+
+const USAGE: &str = "Usage: acfg [options] program.abin
+  --symbols FILE      label addresses from FILE (default: program.asym, if there is one)
+  --entry ADDR        start from ADDR instead of the reset address, 0
+  -o, --output FILE   write the graph to FILE (default: program.dot)
+
+Prints a report of what is reachable, and writes the control flow graph for Graphviz:
+  dot -Tsvg program.dot -o program.svg";
+
+
+fn usage_error(reason: &str) -> ! {
+    println!("ACFG: {}\n{}", reason, USAGE);
+    std::process::exit(1);
+}
+
+
+fn fail(reason: &str) -> ! {
+    println!("ACFG: {}", reason);
+    std::process::exit(1);
+}
+
+
+fn parse_number(text: &str) -> Option<u32> {
+    isa::parse_number(text).ok().and_then(|n| u32::try_from(n).ok())
+}
+
+
+fn main() {
+    let mut path = None;
+    let mut symbols_path = None;
+    let mut output = None;
+    let mut entry = 0;
+
+    let mut args = args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage_error(&format!("{} needs a value", arg)));
+
+        match arg.as_str() {
+            "--symbols" => symbols_path = Some(value()),
+            "--entry" => entry = parse_number(&value()).unwrap_or_else(|| usage_error("--entry needs an address")),
+            "-o" | "--output" => output = Some(value()),
+            flag if flag.starts_with('-') => usage_error(&format!("Unknown option {}", flag)),
+            _ => path = Some(arg),
+        }
+    }
+
+    let path = path.unwrap_or_else(|| usage_error("No file was given"));
+    let bytes = std::fs::read(&path).unwrap_or_else(|e| fail(&format!("Could not read {}: {}", path, e)));
+    let words: Vec<u16> = bytes.chunks(2)
+        .map(|pair| ((pair[0] as u16) << 8) | *pair.get(1).unwrap_or(&0) as u16)
+        .collect();
+
+    let symbols_path = symbols_path.or_else(|| {
+        let default = Path::new(&path).with_extension("asym");
+        default.exists().then(|| default.to_string_lossy().into_owned())
+    });
+    let symbols = symbols_path.map(|p| SymbolTable::load(&p).unwrap_or_else(|e| fail(&e)));
+
+    let cfg = Cfg::build(&words, entry);
+    print!("{}", cfg.report(&words, symbols.as_ref()));
+
+    let output = output.unwrap_or_else(|| Path::new(&path).with_extension("dot").to_string_lossy().into_owned());
+    std::fs::write(&output, cfg.to_dot(&words, symbols.as_ref()))
+        .unwrap_or_else(|e| fail(&format!("Could not write {}: {}", output, e)));
+    println!("\nWrote the graph to {}", output);
+}
@@ -0,0 +1,73 @@
+use std::env::args;
+use std::path::Path;
+
+use isa::symbols::SymbolTable;
+
+
+const USAGE: &str = "Usage: adis [options] program.abin
+  --symbols FILE      label addresses from FILE (default: program.asym, if there is one)
+  --from ADDR         start at word ADDR (default 0)
+  --count N           disassemble N words (default: to the end of the file)
+  --source            leave out addresses and raw words, giving source the assembler can read";
+
+
+fn usage_error(reason: &str) -> ! {
+    println!("ADIS: {}\n{}", reason, USAGE);
+    std::process::exit(1);
+}
+
+
+fn parse_number(text: &str) -> Option<u32> {
+    isa::parse_number(text).ok().and_then(|n| u32::try_from(n).ok())
+}
+
+
+fn main() {
+    let mut path = None;
+    let mut symbols_path = None;
+    let mut from = 0;
+    let mut count = None;
+    let mut source = false;
+
+    let mut args = args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage_error(&format!("{} needs a value", arg)));
+
+        match arg.as_str() {
+            "--symbols" => symbols_path = Some(value()),
+            "--from" => from = parse_number(&value()).unwrap_or_else(|| usage_error("--from needs an address")),
+            "--count" => count = Some(parse_number(&value()).unwrap_or_else(|| usage_error("--count needs a number"))),
+            "--source" => source = true,
+            flag if flag.starts_with('-') => usage_error(&format!("Unknown option {}", flag)),
+            _ => path = Some(arg),
+        }
+    }
+
+    let path = path.unwrap_or_else(|| usage_error("No file was given"));
+    let bytes = std::fs::read(&path).unwrap_or_else(|e| {
+        println!("ADIS: Could not read {}: {}", path, e);
+        std::process::exit(1);
+    });
+    let words: Vec<u16> = bytes.chunks(2)
+        .map(|pair| ((pair[0] as u16) << 8) | *pair.get(1).unwrap_or(&0) as u16)
+        .collect();
+
+    let symbols_path = symbols_path.or_else(|| {
+        let default = Path::new(&path).with_extension("asym");
+        default.exists().then(|| default.to_string_lossy().into_owned())
+    });
+    let symbols = symbols_path.map(|p| SymbolTable::load(&p).unwrap_or_else(|e| {
+        println!("ADIS: {}", e);
+        std::process::exit(1);
+    }));
+
+    let start = (from as usize).min(words.len());
+    let end = count.map_or(words.len(), |n| (start + n as usize).min(words.len()));
+    let lines = disassembler::disassemble(&words[start..end], from, symbols.as_ref());
+
+    if source {
+        print!("{}", disassembler::source(&lines));
+    } else {
+        print!("{}", disassembler::listing(&lines));
+    }
+}
@@ -0,0 +1,397 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use isa::symbols::SymbolTable;
+use isa::{decode, Instruction, COND_ALWAYS};
+
+use crate::disassemble_word;
+
+/*
+CONTROL FLOW GRAPHS
+
+Starting from the reset address, every instruction that can run is found by following fall
+through, JR targets and the JA targets that can be worked out without running anything. Those are
+then cut into basic blocks.
+
+JA jumps to an address register, so its target depends on what was put in J, K or L (with PUTH)
+beforehand. To find it, registers are tracked as constants along every path: MOV, ADD, SUB and MUL
+of known values give known values, GETH of P gives the address of the GETH, and LOAD gives
+something unknown. Where two paths meet, a register stays known only if both paths agree on it.
+Flags aren't tracked, so a conditional jump can always go either way. A JA whose register isn't
+known on every path into it is reported as unresolved, and anything only it could reach will show
+up as unreachable.
+*/
+
+
+/// What is known about the registers, by register number. P isn't tracked, as it is the address.
+type State = [Option<u16>; 16];
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EdgeKind {
+    Fall,  // on to the next instruction
+    Taken, // a jump
+}
+
+
+#[derive(Debug)]
+pub struct BasicBlock {
+    pub start: u32,
+    pub end: u32, // exclusive
+    pub successors: Vec<(u32, EdgeKind)>,
+}
+
+
+#[derive(Debug, Default)]
+pub struct Cfg {
+    pub entry: u32,
+    pub reachable: BTreeSet<u32>,
+    pub blocks: BTreeMap<u32, BasicBlock>,
+    pub unresolved: Vec<u32>, // JAs with a target that couldn't be worked out
+}
+
+
+fn word_at(words: &[u16], addr: u32) -> u16 {
+    words.get(addr as usize).copied().unwrap_or(0) // past the end reads as 0, like RAM does
+}
+
+
+fn alu(op: u8, a: Option<u16>, b: Option<u16>) -> Option<u16> {
+    match op {
+        4 => b,
+        5 => Some(a?.wrapping_add(b?)),
+        6 => Some(a?.wrapping_sub(b?)),
+        7 => Some(a?.wrapping_mul(b?)),
+        _ => None,
+    }
+}
+
+
+fn transfer(state: &State, instr: &Instruction, addr: u32) -> State {
+    let mut out = *state;
+    match *instr {
+        Instruction::Tra { op, dst, a, b } => {
+            out[dst as usize] = alu(op, state[a as usize], state[b as usize]);
+        },
+        Instruction::Im { op, dst, a, imm } => {
+            out[dst as usize] = alu(op, state[a as usize], Some(imm as u16));
+        },
+        Instruction::Load { dst, .. } => out[dst as usize] = None,
+        Instruction::PutH { half, src } => out[half as usize] = state[src as usize],
+        Instruction::GetH { dst, half: 8 } => out[dst as usize] = Some(addr as u16),
+        Instruction::GetH { dst, half: 9 } => out[dst as usize] = Some((addr >> 16) as u16),
+        Instruction::GetH { dst, half } => out[dst as usize] = state[half as usize],
+        _ => {},
+    }
+    out
+}
+
+
+/// Where an instruction can go next. Gives None for the target of a JA that isn't known.
+fn successors(instr: &Instruction, addr: u32, state: &State) -> Vec<(Option<u32>, EdgeKind)> {
+    let next = (Some(addr.wrapping_add(1)), EdgeKind::Fall);
+    let branch = |cond: u8, target: Option<u32>| match cond {
+        0 => vec![next],
+        COND_ALWAYS => vec![(target, EdgeKind::Taken)],
+        _ => vec![next, (target, EdgeKind::Taken)],
+    };
+
+    match *instr {
+        Instruction::Nop | Instruction::Unknown(_) => Vec::new(), // halts, or faults
+        Instruction::Tra { op, .. } | Instruction::Im { op, .. } if alu(op, Some(0), Some(0)).is_none() => Vec::new(),
+
+        Instruction::Jr { cond, offset } => branch(cond, Some(addr.wrapping_add(offset as u32))),
+        Instruction::Ja { cond, addr: reg } => {
+            let target = match (reg, state[reg as usize], state[reg as usize + 1]) {
+                (8, _, _) => Some(addr), // P is the address of the JA itself, as for GETH
+                (_, Some(lo), Some(hi)) => Some((hi as u32) << 16 | lo as u32),
+                _ => None,
+            };
+            branch(cond, target)
+        },
+
+        _ => vec![next],
+    }
+}
+
+
+fn meet(old: &State, new: &State) -> State {
+    let mut out = *old;
+    for (o, n) in out.iter_mut().zip(new) {
+        if *o != *n {
+            *o = None;
+        }
+    }
+    out
+}
+
+
+impl Cfg {
+
+    /// Finds everything reachable from `entry` in a program loaded at address 0.
+    pub fn build(words: &[u16], entry: u32) -> Cfg {
+        // registers all start at 0
+        let mut states: HashMap<u32, State> = HashMap::new();
+        states.insert(entry, [Some(0); 16]);
+        let mut work = vec![entry];
+
+        while let Some(addr) = work.pop() {
+            let instr = decode(word_at(words, addr));
+            let out = transfer(&states[&addr], &instr, addr);
+
+            for (target, _) in successors(&instr, addr, &out) {
+                let Some(target) = target else { continue };
+                let merged = match states.get(&target) {
+                    Some(old) => meet(old, &out),
+                    None => out,
+                };
+                if states.get(&target) != Some(&merged) {
+                    states.insert(target, merged);
+                    work.push(target);
+                }
+            }
+        }
+
+        // the final edges, now that what is known about the registers has settled
+        let mut cfg = Cfg { entry, ..Cfg::default() };
+        let mut edges: BTreeMap<u32, Vec<(u32, EdgeKind)>> = BTreeMap::new();
+        let mut predecessors: HashMap<u32, usize> = HashMap::new();
+
+        for (&addr, state) in states.iter() {
+            let instr = decode(word_at(words, addr));
+            let out = transfer(state, &instr, addr);
+            let mut succ = Vec::new();
+            for (target, kind) in successors(&instr, addr, &out) {
+                match target {
+                    Some(t) => {
+                        succ.push((t, kind));
+                        *predecessors.entry(t).or_default() += 1;
+                    },
+                    None => cfg.unresolved.push(addr),
+                }
+            }
+            edges.insert(addr, succ);
+            cfg.reachable.insert(addr);
+        }
+        cfg.unresolved.sort();
+        cfg.unresolved.dedup();
+
+        // a block starts at the entry, at anything with more than one way in, and after anything
+        // that doesn't just fall through to the next instruction, and runs on until the next one.
+        // A JA that wasn't worked out might go anywhere, so it ends its block too
+        let falls_through = |addr: u32, succ: &[(u32, EdgeKind)]| {
+            succ.len() == 1 && succ[0] == (addr.wrapping_add(1), EdgeKind::Fall)
+                && cfg.unresolved.binary_search(&addr).is_err()
+        };
+        let mut leaders: BTreeSet<u32> = cfg.reachable.iter().copied()
+            .filter(|a| *a == entry || predecessors.get(a).copied().unwrap_or(0) != 1)
+            .collect();
+        for (addr, succ) in &edges {
+            if !falls_through(*addr, succ) {
+                leaders.extend(succ.iter().map(|(target, _)| *target));
+            }
+        }
+
+        for &start in &leaders {
+            let mut end = start;
+            loop {
+                let succ = &edges[&end];
+                let next = end.wrapping_add(1);
+                if !falls_through(end, succ) || leaders.contains(&next) {
+                    cfg.blocks.insert(start, BasicBlock { start, end: next, successors: succ.clone() });
+                    break;
+                }
+                end = next;
+            }
+        }
+
+        cfg
+    }
+
+
+    /// Ranges of the program that nothing reaches, leaving out runs that are all zeros (padding).
+    pub fn unreachable(&self, words: &[u16]) -> Vec<(u32, u32)> {
+        let mut ranges = Vec::new();
+        let mut start = None;
+
+        for addr in 0..=words.len() as u32 {
+            let dead = (addr as usize) < words.len() && !self.reachable.contains(&addr);
+            match (dead, start) {
+                (true, None) => start = Some(addr),
+                (false, Some(s)) => {
+                    if words[s as usize..addr as usize].iter().any(|w| *w != 0) {
+                        ranges.push((s, addr));
+                    }
+                    start = None;
+                },
+                _ => {},
+            }
+        }
+
+        ranges
+    }
+
+
+    pub fn report(&self, words: &[u16], symbols: Option<&SymbolTable>) -> String {
+        let describe = |addr: u32| match symbols.and_then(|s| s.containing(addr)) {
+            Some((start, name)) if start == addr => format!("{:#010x} <{}>", addr, name),
+            Some((start, name)) => format!("{:#010x} <{}+{}>", addr, name, addr - start),
+            None => format!("{:#010x}", addr),
+        };
+
+        let plural = |n: usize| if n == 1 { "" } else { "s" };
+        let mut out = format!("{} instruction{} reachable from {} in {} basic block{}\n",
+            self.reachable.len(), plural(self.reachable.len()), describe(self.entry),
+            self.blocks.len(), plural(self.blocks.len()));
+
+        if !self.unresolved.is_empty() {
+            out += "\nJumps through address registers that couldn't be worked out:\n";
+            for addr in &self.unresolved {
+                out += &format!("  {}  {}\n", describe(*addr), disassemble_word(word_at(words, *addr), *addr, symbols));
+            }
+        }
+
+        let unreachable = self.unreachable(words);
+        if unreachable.is_empty() {
+            out += "\nNo unreachable code\n";
+        } else {
+            out += "\nUnreachable code:\n";
+            for (start, end) in unreachable {
+                out += &format!("  {} .. {:#010x}  ({} words)\n", describe(start), end, end - start);
+            }
+            if !self.unresolved.is_empty() {
+                out += "Some of it may be reached through the jumps that couldn't be worked out.\n";
+            }
+        }
+
+        out
+    }
+
+
+    /// The graph in Graphviz's DOT language, one box per basic block.
+    pub fn to_dot(&self, words: &[u16], symbols: Option<&SymbolTable>) -> String {
+        let node = |addr: u32| format!("\"b{:08x}\"", addr);
+        let mut out = String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+
+        for block in self.blocks.values() {
+            let mut label = String::new();
+            for addr in block.start..block.end {
+                for name in symbols.map_or(&[][..], |s| s.names_at(addr)) {
+                    label += &format!("{}:\\l", name);
+                }
+                let text = disassemble_word(word_at(words, addr), addr, symbols);
+                label += &format!("{:#010x}  {}\\l", addr, text.replace('"', "\\\""));
+            }
+            out += &format!("    {} [label=\"{}\"];\n", node(block.start), label);
+
+            for (target, kind) in &block.successors {
+                let style = match kind {
+                    EdgeKind::Fall => " [style=dashed]",
+                    EdgeKind::Taken => "",
+                };
+                out += &format!("    {} -> {}{};\n", node(block.start), node(*target), style);
+            }
+            if self.unresolved.contains(&(block.end - 1)) {
+                out += &format!("    \"unknown{:08x}\" [label=\"?\", shape=circle];\n", block.start);
+                out += &format!("    {} -> \"unknown{:08x}\" [color=red];\n", node(block.start), block.start);
+            }
+        }
+
+        out + "}\n"
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GT: u8 = 0b100;
+
+    fn program(instrs: &[Instruction]) -> Vec<u16> {
+        instrs.iter().map(|i| isa::encode(i).unwrap()).collect()
+    }
+
+    fn mov(dst: u8, imm: u8) -> Instruction {
+        Instruction::Im { op: 4, dst, a: 0, imm }
+    }
+
+    fn blocks(cfg: &Cfg) -> Vec<(u32, u32)> {
+        cfg.blocks.values().map(|b| (b.start, b.end)).collect()
+    }
+
+    #[test]
+    fn registers_meet_where_paths_join() {
+        let words = program(&[
+            mov(0, 5),                                   // 0: A is 5 either way
+            Instruction::Jr { cond: GT, offset: 3 },     // 1
+            mov(1, 1),                                   // 2: but B is 1 ...
+            Instruction::Jr { cond: COND_ALWAYS, offset: 2 },
+            mov(1, 2),                                   // 4: ... or 2
+            Instruction::PutH { half: 10, src: 0 },      // 5: J0 = A
+            Instruction::PutH { half: 12, src: 1 },      // 6: K0 = B
+            Instruction::Ja { cond: GT, addr: 12 },      // 7: JA GT, K
+            Instruction::Ja { cond: COND_ALWAYS, addr: 10 }, // 8: JA J
+        ]);
+        let cfg = Cfg::build(&words, 0);
+
+        assert_eq!(cfg.unresolved, vec![7]);
+        assert_eq!(blocks(&cfg), vec![(0, 2), (2, 4), (4, 5), (5, 8), (8, 9)]);
+        assert_eq!(cfg.blocks[&5].successors, vec![(8, EdgeKind::Fall)]); // and the unknown target
+        assert_eq!(cfg.blocks[&8].successors, vec![(5, EdgeKind::Taken)]);
+    }
+
+    #[test]
+    fn ja_through_p_goes_to_itself() {
+        let words = program(&[mov(0, 1), Instruction::Ja { cond: GT, addr: 8 }, Instruction::Nop]);
+        let cfg = Cfg::build(&words, 0);
+
+        assert!(cfg.unresolved.is_empty());
+        assert_eq!(cfg.blocks[&1].successors, vec![(2, EdgeKind::Fall), (1, EdgeKind::Taken)]);
+        assert_eq!(blocks(&cfg), vec![(0, 1), (1, 2), (2, 3)]);
+    }
+
+    #[test]
+    fn jr_loops() {
+        let words = program(&[
+            Instruction::Im { op: 5, dst: 0, a: 0, imm: 1 }, // 0: ADD A, A, #1
+            Instruction::Jr { cond: GT, offset: -1 },
+            Instruction::Nop,
+        ]);
+        let cfg = Cfg::build(&words, 0);
+
+        assert_eq!(blocks(&cfg), vec![(0, 2), (2, 3)]);
+        assert_eq!(cfg.blocks[&0].successors, vec![(2, EdgeKind::Fall), (0, EdgeKind::Taken)]);
+        assert!(cfg.blocks[&2].successors.is_empty());
+    }
+
+    #[test]
+    fn jumps_into_a_block_split_it() {
+        let words = program(&[
+            mov(0, 1),
+            mov(1, 2),
+            mov(2, 3),
+            Instruction::Jr { cond: GT, offset: -2 }, // back to 1
+            Instruction::Nop,
+        ]);
+        let cfg = Cfg::build(&words, 0);
+
+        assert_eq!(blocks(&cfg), vec![(0, 1), (1, 4), (4, 5)]);
+        assert_eq!(cfg.blocks[&0].successors, vec![(1, EdgeKind::Fall)]);
+    }
+
+    #[test]
+    fn padding_isnt_unreachable() {
+        let mut words = program(&[
+            Instruction::Jr { cond: COND_ALWAYS, offset: 3 }, // 0: over 1 and 2
+            mov(0, 1),
+            Instruction::Nop,
+            Instruction::Jr { cond: COND_ALWAYS, offset: 3 }, // 3: over the padding
+        ]);
+        words.extend([0, 0]);
+        words.extend(program(&[Instruction::Nop]));
+        let cfg = Cfg::build(&words, 0);
+
+        assert_eq!(cfg.reachable.iter().copied().collect::<Vec<u32>>(), vec![0, 3, 6]);
+        assert_eq!(cfg.unreachable(&words), vec![(1, 3)]);
+    }
+}
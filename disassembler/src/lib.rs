@@ -0,0 +1,178 @@
+use isa::symbols::SymbolTable;
+use isa::{decode, FlagOp, Instruction, ADDR_REGISTER_NAMES, ALU_OP_NAMES, COND_ALWAYS, CONDITION_FLAGS,
+    REGISTER_NAMES};
+
+pub mod cfg;
+
+/*
+ASSEMBLY SYNTAX
+
+Instructions come out in the syntax the assembler reads. Sources come before destinations, as in
+LOADIMM #4, A:
+
+    NOP
+    SETFLG +GT -EQ ~LS        set gt, clear eq, toggle ls (flags that aren't named are kept)
+    JA J                      jump to the address in J
+    JA GT|EQ, J               ... if gt or eq is set (NEVER for a condition of 000)
+    STORE C, [K]              write C to the address in K
+    LOAD [K], C               read the address in K into C
+    PUTH C, J1                copy C into the top half of J
+    GETH P0, C                copy the bottom half of P into C
+    JR loop                   jump to a label, or to $+N / $-N words from the JR itself
+    JR LS, $-3
+    MOV B, C                  C = B                      (TRA)
+    ADD A, B, C               C = A + B                  (TRA, also SUB and MUL)
+    MOV #5, C                 C = 5                      (IM)
+    ADD A, #5, C              C = A + 5                  (IM)
+    TRA #9, A, B, C           TRA and IM with an operation that has no name yet, or a MOV
+    IM #9, A, #5, C           that names a register it doesn't use
+    .word 0x0360              anything that isn't an instruction
+*/
+
+
+fn register(num: u8) -> &'static str {
+    REGISTER_NAMES[num as usize]
+}
+
+fn addr_register(num: u8) -> &'static str {
+    ADDR_REGISTER_NAMES[((num >> 1) & 0b11) as usize]
+}
+
+
+fn condition(cond: u8) -> String {
+    if cond == 0 {
+        return String::from("NEVER");
+    }
+    CONDITION_FLAGS.iter()
+        .filter(|(_, bit)| cond & bit != 0)
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join("|")
+}
+
+
+fn flag_ops(ops: [(&str, FlagOp); 4]) -> String {
+    let mut out = String::from("SETFLG");
+    for (name, op) in ops {
+        let sign = match op {
+            FlagOp::Keep => continue,
+            FlagOp::Set => '+',
+            FlagOp::Clear => '-',
+            FlagOp::Toggle => '~',
+        };
+        out += &format!(" {}{}", sign, name);
+    }
+    out
+}
+
+
+/// Where a JR at `addr` goes, as a label if there is one there.
+fn jr_target(addr: u32, offset: i8, symbols: Option<&SymbolTable>) -> String {
+    let target = addr.wrapping_add(offset as u32);
+    match symbols.and_then(|s| s.name_at(target)) {
+        Some(name) => String::from(name),
+        None if offset < 0 => format!("$-{}", -(offset as i32)),
+        None => format!("$+{}", offset),
+    }
+}
+
+
+/// One instruction in assembler syntax. `addr` is where it sits, for working out jump targets.
+pub fn format_instruction(instr: &Instruction, addr: u32, symbols: Option<&SymbolTable>) -> String {
+    match *instr {
+        Instruction::Nop => String::from("NOP"),
+
+        Instruction::SetFlg { gt, eq, ls, ov } => flag_ops([("GT", gt), ("EQ", eq), ("LS", ls), ("OV", ov)]),
+
+        Instruction::Ja { cond: COND_ALWAYS, addr: reg } => format!("JA {}", addr_register(reg)),
+        Instruction::Ja { cond, addr: reg } => format!("JA {}, {}", condition(cond), addr_register(reg)),
+
+        Instruction::Store { src, addr: reg } => format!("STORE {}, [{}]", register(src), addr_register(reg)),
+        Instruction::Load { dst, addr: reg } => format!("LOAD [{}], {}", addr_register(reg), register(dst)),
+
+        Instruction::PutH { half, src } => format!("PUTH {}, {}", register(src), register(half)),
+        Instruction::GetH { dst, half } => format!("GETH {}, {}", register(half), register(dst)),
+
+        Instruction::Jr { cond: COND_ALWAYS, offset } => format!("JR {}", jr_target(addr, offset, symbols)),
+        Instruction::Jr { cond, offset } =>
+            format!("JR {}, {}", condition(cond), jr_target(addr, offset, symbols)),
+
+        Instruction::Tra { op, dst, a, b } => match ALU_OP_NAMES[op as usize] {
+            Some("MOV") if a == 0 => format!("MOV {}, {}", register(b), register(dst)),
+            Some(name) if name != "MOV" =>
+                format!("{} {}, {}, {}", name, register(a), register(b), register(dst)),
+            _ => format!("TRA #{}, {}, {}, {}", op, register(a), register(b), register(dst)),
+        },
+
+        Instruction::Im { op, dst, a, imm } => match ALU_OP_NAMES[op as usize] {
+            Some("MOV") if a == 0 => format!("MOV #{}, {}", imm, register(dst)),
+            Some(name) if name != "MOV" => format!("{} {}, #{}, {}", name, register(a), imm, register(dst)),
+            _ => format!("IM #{}, {}, #{}, {}", op, register(a), imm, register(dst)),
+        },
+
+        Instruction::Unknown(word) => format!(".word {:#06x}", word),
+    }
+}
+
+
+pub fn disassemble_word(word: u16, addr: u32, symbols: Option<&SymbolTable>) -> String {
+    format_instruction(&decode(word), addr, symbols)
+}
+
+
+/// A disassembled word, with the labels that point at it.
+#[derive(Debug)]
+pub struct Line {
+    pub addr: u32,
+    pub word: u16,
+    pub labels: Vec<String>,
+    pub text: String,
+}
+
+
+/// Disassembles `words`, the first of which sits at `start`.
+pub fn disassemble(words: &[u16], start: u32, symbols: Option<&SymbolTable>) -> Vec<Line> {
+    words.iter().enumerate().map(|(i, &word)| {
+        let addr = start.wrapping_add(i as u32);
+        Line {
+            addr,
+            word,
+            labels: symbols.map_or(Vec::new(), |s| s.names_at(addr).to_vec()),
+            text: disassemble_word(word, addr, symbols),
+        }
+    }).collect()
+}
+
+
+/// A listing with addresses and raw words:
+///
+/// ```text
+/// loop:
+///   0x00000004  2a08  ADD A, B, A
+/// ```
+pub fn listing(lines: &[Line]) -> String {
+    let mut out = String::new();
+    for line in lines {
+        for label in &line.labels {
+            out += &format!("{}:\n", label);
+        }
+        out += &format!("  {:#010x}  {:04x}  {}\n", line.addr, line.word, line.text);
+    }
+    out
+}
+
+
+/// Source the assembler can read back in, as one section starting at the first line.
+pub fn source(lines: &[Line]) -> String {
+    let mut out = match lines.first() {
+        Some(first) if first.addr != 0 => format!(".section_{:08x} {:#010x}:\n", first.addr, first.addr),
+        _ => String::from(".start:\n"),
+    };
+    for line in lines {
+        for label in &line.labels {
+            out += &format!("{}:\n", label);
+        }
+        out += &format!("  {}\n", line.text);
+    }
+    out
+}
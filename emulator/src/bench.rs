@@ -0,0 +1,142 @@
+use std::time::{Duration, Instant};
+
+use crate::blocks::BlockEngine;
+use isa::InstrClass;
+use crate::emulator::{self, Cpu};
+
+/*
+Benchmark mode. The whole run is timed with one clock, tracing is off, and the result comes out
+as a single line of JSON so it can be collected from release to release:
+
+    {"version": "0.1.0", "program": "os.abin", "engine": "interp", "instructions": 50000000,
+     "cycles": 50000000, "wall_seconds": 0.98, "mips": 51.02, "halted": false, "fault": null,
+     "mix": {"NOP": 0, "SETFLG": 0, ...}}
+*/
+
+
+pub struct BenchResult {
+    pub instructions: u64,
+    pub cycles: u64,
+    pub wall: Duration,
+    pub halted: bool,
+    pub fault: Option<String>,
+    pub mix: [u64; InstrClass::COUNT],
+}
+
+
+pub fn run(cpu: &mut Cpu, blocks: bool, max: Option<u64>) -> BenchResult {
+    cpu.trace = false;
+    let cycles = cpu.cycles;
+    let mix = cpu.mix;
+
+    let start = Instant::now();
+    let result = if blocks {
+        BlockEngine::new().run(cpu, max)
+    } else {
+        emulator::run(cpu, max)
+    };
+    let wall = start.elapsed();
+
+    let mut run_mix = cpu.mix;
+    for (m, before) in run_mix.iter_mut().zip(mix) {
+        *m -= before;
+    }
+
+    BenchResult {
+        // a fault cuts the count short, but every instruction that ran is in the mix
+        instructions: run_mix.iter().sum(),
+        cycles: cpu.cycles - cycles,
+        wall,
+        halted: cpu.halted,
+        fault: result.err().map(|f| f.to_string()),
+        mix: run_mix,
+    }
+}
+
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+
+impl BenchResult {
+
+    pub fn mips(&self) -> f64 {
+        if self.wall.is_zero() {
+            return 0.0; // JSON has no infinity
+        }
+        self.instructions as f64 / self.wall.as_secs_f64() / 1_000_000.0
+    }
+
+    pub fn to_json(&self, program: &str, engine: &str) -> String {
+        let mix: Vec<String> = InstrClass::ALL.iter()
+            .map(|c| format!("{}: {}", json_string(c.name()), self.mix[*c as usize]))
+            .collect();
+
+        format!("{{\"version\": {}, \"program\": {}, \"engine\": {}, \"instructions\": {}, \"cycles\": {}, \
+            \"wall_seconds\": {:.6}, \"mips\": {:.3}, \"halted\": {}, \"fault\": {}, \"mix\": {{{}}}}}",
+            json_string(env!("CARGO_PKG_VERSION")),
+            json_string(program),
+            json_string(engine),
+            self.instructions,
+            self.cycles,
+            self.wall.as_secs_f64(),
+            self.mips(),
+            self.halted,
+            self.fault.as_deref().map_or(String::from("null"), json_string),
+            mix.join(", "))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use isa::Instruction;
+
+    // ADD A, A, #1 then JR back to it, forever
+    fn counting() -> Cpu {
+        let program = [
+            Instruction::Im { op: 5, dst: 0, a: 0, imm: 1 },
+            Instruction::Jr { cond: 0b111, offset: -1 },
+        ];
+        Cpu::new(program.iter().map(|i| isa::encode(i).unwrap()).collect())
+    }
+
+    #[test]
+    fn json_has_every_field() {
+        for (blocks, engine) in [(false, "interp"), (true, "blocks")] {
+            let mut result = run(&mut counting(), blocks, Some(10));
+            result.wall = Duration::from_millis(500); // the only thing that changes from run to run
+
+            assert_eq!(result.to_json("dir/\"odd\".abin", engine), format!(
+                "{{\"version\": \"{}\", \"program\": \"dir/\\\"odd\\\".abin\", \"engine\": \"{}\", \
+                \"instructions\": 10, \"cycles\": 40, \"wall_seconds\": 0.500000, \"mips\": 0.000, \
+                \"halted\": false, \"fault\": null, \"mix\": {{\"NOP\": 0, \"SETFLG\": 0, \"JA\": 0, \
+                \"STORE\": 0, \"LOAD\": 0, \"PUTH\": 0, \"GETH\": 0, \"JR\": 5, \"TRA\": 0, \"IM\": 5, \
+                \"UNKNOWN\": 0}}}}",
+                env!("CARGO_PKG_VERSION"), engine));
+        }
+    }
+
+    #[test]
+    fn faults_and_instant_runs() {
+        let mut cpu = Cpu::new(vec![0x0380]); // PUTH with no register, unknown
+        let mut result = run(&mut cpu, false, Some(10));
+        result.wall = Duration::ZERO;
+        let json = result.to_json("p.abin", "interp");
+        assert!(json.contains("\"instructions\": 1, "), "{}", json);
+        assert!(json.contains("\"mips\": 0.000, "), "{}", json);
+        assert!(json.contains("\"fault\": \"Do not know what to do with 0x0380\", "), "{}", json);
+    }
+}
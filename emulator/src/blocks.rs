@@ -0,0 +1,482 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use isa::{decode, InstrClass, Instruction};
+use crate::emulator::{AccessKind, Cpu, Fault, CODE_PAGE_BITS};
+
+/*
+THE BLOCK ENGINE
+
+Instead of fetching and decoding every instruction as it runs, straight-line runs of code are
+decoded once into a list of closures with their operands already baked in. A block ends at the
+first JA, JR, NOP or word that doesn't decode, and runs as one unit after that.
+
+Stores that land on a page holding translated code are reported by the CPU in `code_writes`, and
+every block covering a written word is thrown away. If that includes the block that is running,
+it stops right after the store so the new code gets translated before it runs.
+
+The interpreter in emulator.rs stays the reference. Running with `--engine diff` runs both side by
+side and checks that they agree after every block.
+*/
+
+
+// Some(addr) means carry on at addr, None means carry on with the next instruction.
+type Op = Box<dyn Fn(&mut Cpu) -> Result<Option<u32>, Fault>>;
+
+const MAX_BLOCK_LEN: u32 = 64;
+
+// size of the direct mapped cache in front of the block table
+const LOOKUP_BITS: u32 = 12;
+
+
+struct Block {
+    start: u32,
+    len: u32,
+    ops: Vec<Op>,
+    classes: Vec<InstrClass>, // for the instruction mix, one per op that decoded
+    costs: Vec<u64>, // cycles for ops up to and including each one, fetch wait states included
+    ends_in_jump: bool,
+}
+
+
+pub struct BlockEngine {
+    blocks: HashMap<u32, Rc<Block>>,
+    lookup: Vec<Option<Rc<Block>>>, // indexed by the bottom bits of the start address
+    pages: HashMap<u32, Vec<u32>>, // code page -> start of every block that covers part of it
+    pub translated: u64,
+    pub invalidated: u64,
+}
+
+
+fn alu_op(op: u8, a: impl Fn(&Cpu) -> u16 + 'static, b: impl Fn(&Cpu) -> u16 + 'static, dst: u16) -> Op {
+    match op {
+        4 => Box::new(move |cpu| {
+            let v = b(cpu);
+            cpu.set_register(dst, v);
+            Ok(None)
+        }),
+        5 => Box::new(move |cpu| {
+            let (v, ov) = a(cpu).overflowing_add(b(cpu));
+            cpu.ov_flag = ov;
+            cpu.set_register(dst, v);
+            Ok(None)
+        }),
+        6 => Box::new(move |cpu| {
+            let (v, ov) = a(cpu).overflowing_sub(b(cpu));
+            cpu.ov_flag = ov;
+            cpu.set_register(dst, v);
+            Ok(None)
+        }),
+        7 => Box::new(move |cpu| {
+            let (v, ov) = a(cpu).overflowing_mul(b(cpu));
+            cpu.ov_flag = ov;
+            cpu.set_register(dst, v);
+            Ok(None)
+        }),
+        other => Box::new(move |_| Err(Fault::AluOp(other as u16 | 0b10000))),
+    }
+}
+
+
+fn translate_one(instr: Instruction, addr: u32) -> Op {
+    match instr {
+        Instruction::Nop => Box::new(move |cpu| {
+            cpu.halted = true;
+            Ok(Some(addr)) // a halted CPU stays on its NOP
+        }),
+
+        Instruction::SetFlg { gt, eq, ls, ov } => Box::new(move |cpu| {
+            cpu.gt_flag = gt.apply(cpu.gt_flag);
+            cpu.eq_flag = eq.apply(cpu.eq_flag);
+            cpu.ls_flag = ls.apply(cpu.ls_flag);
+            cpu.ov_flag = ov.apply(cpu.ov_flag);
+            Ok(None)
+        }),
+
+        Instruction::Ja { cond, addr: reg } => Box::new(move |cpu| {
+            Ok(cpu.condition_met(cond).then(|| cpu.wide_register(reg as u16)))
+        }),
+
+        Instruction::Jr { cond, offset } => {
+            let target = addr.wrapping_add(offset as u32);
+            Box::new(move |cpu| Ok(cpu.condition_met(cond).then_some(target)))
+        },
+
+        Instruction::Store { src, addr: reg } => Box::new(move |cpu| {
+            let to = cpu.wide_register(reg as u16);
+            let v = cpu.register(src as u16);
+            cpu.write_ram(to, v)?;
+            Ok(None)
+        }),
+
+        Instruction::Load { dst, addr: reg } => Box::new(move |cpu| {
+            let from = cpu.wide_register(reg as u16);
+            let v = cpu.read_ram(from, AccessKind::Read)?;
+            cpu.set_register(dst as u16, v);
+            Ok(None)
+        }),
+
+        Instruction::PutH { half, src } => {
+            alu_op(4, |_| 0, move |cpu| cpu.register(src as u16), half as u16)
+        },
+
+        // the PC isn't kept up to date inside a block, but it is known when translating
+        Instruction::GetH { dst, half: 8 } => alu_op(4, |_| 0, move |_| addr as u16, dst as u16),
+        Instruction::GetH { dst, half: 9 } => alu_op(4, |_| 0, move |_| (addr >> 16) as u16, dst as u16),
+        Instruction::GetH { dst, half } => {
+            alu_op(4, |_| 0, move |cpu| cpu.register(half as u16), dst as u16)
+        },
+
+        Instruction::Tra { op, dst, a, b } => {
+            alu_op(op, move |cpu| cpu.register(a as u16), move |cpu| cpu.register(b as u16), dst as u16)
+        },
+
+        Instruction::Im { op, dst, a, imm } => {
+            alu_op(op, move |cpu| cpu.register(a as u16), move |_| imm as u16, dst as u16)
+        },
+
+        Instruction::Unknown(instr) => Box::new(move |_| Err(Fault::UnknownInstruction(instr))),
+    }
+}
+
+
+fn count_mix(cpu: &mut Cpu, classes: &[InstrClass]) {
+    for class in classes {
+        cpu.mix[*class as usize] += 1;
+    }
+}
+
+
+impl Default for BlockEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+impl BlockEngine {
+
+    pub fn new() -> BlockEngine {
+        BlockEngine {
+            blocks: HashMap::new(),
+            lookup: vec![None; 1 << LOOKUP_BITS],
+            pages: HashMap::new(),
+            translated: 0,
+            invalidated: 0,
+        }
+    }
+
+
+    fn translate(&mut self, cpu: &mut Cpu, start: u32) -> Rc<Block> {
+        let mut ops = Vec::new();
+        let mut classes = Vec::new();
+        let mut costs = Vec::new();
+        let mut cost = 0;
+        let mut addr = start;
+
+        loop {
+            if addr >= 0xF000_0000 {
+                let at = addr;
+                ops.push(Box::new(move |_: &mut Cpu| Err(Fault::Io(at))) as Op);
+                costs.push(cost);
+                break;
+            }
+
+            let instr = decode(cpu.peek(addr));
+            ops.push(translate_one(instr, addr));
+            classes.push(instr.class());
+            cost += cpu.timing.cost(instr.class()) + cpu.timing.wait(addr);
+            costs.push(cost);
+            addr = addr.wrapping_add(1);
+
+            let ends = matches!(instr,
+                Instruction::Ja { .. } | Instruction::Jr { .. } | Instruction::Nop | Instruction::Unknown(_));
+            if ends || ops.len() as u32 == MAX_BLOCK_LEN {
+                break;
+            }
+        }
+
+        let block = Rc::new(Block {
+            start,
+            len: ops.len() as u32,
+            ops,
+            ends_in_jump: matches!(classes.last(), Some(InstrClass::Ja) | Some(InstrClass::Jr)),
+            classes,
+            costs,
+        });
+
+        let first = start >> CODE_PAGE_BITS;
+        let last = (start + block.len - 1) >> CODE_PAGE_BITS;
+        for page in first..=last {
+            if cpu.code_pages.len() <= page as usize {
+                cpu.code_pages.resize(page as usize + 1, false);
+            }
+            cpu.code_pages[page as usize] = true;
+            self.pages.entry(page).or_default().push(start);
+        }
+
+        self.blocks.insert(start, block.clone());
+        self.translated += 1;
+        block
+    }
+
+
+    /// Throws away every block covering a word in `cpu.code_writes`. Says whether the block
+    /// starting at `running` was one of them.
+    fn invalidate(&mut self, cpu: &mut Cpu, running: u32) -> bool {
+        let mut hit_running = false;
+
+        for addr in cpu.code_writes.drain(..) {
+            let Some(starts) = self.pages.get_mut(&(addr >> CODE_PAGE_BITS)) else { continue };
+
+            starts.retain(|start| {
+                let covers = match self.blocks.get(start) {
+                    Some(b) => addr >= b.start && addr < b.start + b.len,
+                    None => return false, // already gone
+                };
+                if covers {
+                    self.blocks.remove(start);
+                    self.lookup[(*start & ((1 << LOOKUP_BITS) - 1)) as usize] = None;
+                    self.invalidated += 1;
+                    hit_running |= *start == running;
+                }
+                !covers
+            });
+        }
+
+        hit_running
+    }
+
+
+    /// Runs the block at PC, or a single instruction if fewer than a block's worth are left in
+    /// `budget`. Gives how many instructions ran, and the fault if one stopped it early.
+    pub fn step_block(&mut self, cpu: &mut Cpu, budget: u64) -> (u64, Result<(), Fault>) {
+        let pc = cpu.pc();
+
+        // anything written since the last block, by a single step below or from outside the
+        // engine, has to be gone before the lookup can hand back a stale block
+        if !cpu.code_writes.is_empty() {
+            self.invalidate(cpu, pc);
+        }
+
+        let slot = (pc & ((1 << LOOKUP_BITS) - 1)) as usize;
+        let block = match &self.lookup[slot] {
+            Some(b) if b.start == pc => b.clone(),
+            _ => {
+                let b = match self.blocks.get(&pc) {
+                    Some(b) => b.clone(),
+                    None => self.translate(cpu, pc),
+                };
+                self.lookup[slot] = Some(b.clone());
+                b
+            },
+        };
+
+        if budget < block.len as u64 {
+            return match cpu.step() {
+                Ok(()) => (1, Ok(())),
+                Err(fault) => (0, Err(fault)),
+            };
+        }
+
+        let mut next = None;
+        for (i, op) in block.ops.iter().enumerate() {
+            let here = block.start.wrapping_add(i as u32);
+
+            match op(cpu) {
+                Ok(target) => next = target,
+                Err(fault) => {
+                    // the interpreter charges for an instruction that faults, but not its jump
+                    cpu.set_pc(here);
+                    cpu.cycles += block.costs[i];
+                    count_mix(cpu, &block.classes[..(i + 1).min(block.classes.len())]);
+                    return (i as u64, Err(fault));
+                },
+            }
+
+            if !cpu.code_writes.is_empty() && self.invalidate(cpu, block.start) && i as u32 + 1 < block.len {
+                // we just wrote over ourselves, so pick up again from freshly translated code
+                cpu.set_pc(here.wrapping_add(1));
+                cpu.cycles += block.costs[i];
+                count_mix(cpu, &block.classes[..i + 1]);
+                return (i as u64 + 1, Ok(()));
+            }
+        }
+
+        cpu.set_pc(next.unwrap_or(block.start.wrapping_add(block.len)));
+        cpu.cycles += block.costs[block.ops.len() - 1];
+        if block.ends_in_jump && next.is_some() {
+            cpu.cycles += cpu.timing.branch_taken;
+        }
+        count_mix(cpu, &block.classes);
+        (block.len as u64, Ok(()))
+    }
+
+
+    /// Like emulator::run, a block at a time.
+    pub fn run(&mut self, cpu: &mut Cpu, max: Option<u64>) -> Result<u64, Fault> {
+        let mut count = 0;
+
+        while !cpu.halted && Some(count) != max {
+            let (n, result) = self.step_block(cpu, max.map_or(u64::MAX, |m| m - count));
+            count += n;
+            result?;
+        }
+
+        Ok(count)
+    }
+}
+
+
+fn compare(reference: &Cpu, fast: &Cpu, full: bool) -> Result<(), String> {
+    let names = ["A", "B", "C", "D", "E", "F", "G", "H", "P", "J", "K", "L"];
+
+    for ((name, r), f) in names.iter().zip(reference.raw_registers()).zip(fast.raw_registers()) {
+        if r != f {
+            return Err(format!("register {} is {:#x} in the interpreter but {:#x} in the block engine", name, r, f));
+        }
+    }
+    if reference.status() != fast.status() {
+        return Err(format!("flags are {:05b} in the interpreter but {:05b} in the block engine",
+            reference.status(), fast.status()));
+    }
+    if reference.mix != fast.mix {
+        return Err(format!("instruction mix is {:?} in the interpreter but {:?} in the block engine",
+            reference.mix, fast.mix));
+    }
+    if reference.cycles != fast.cycles {
+        return Err(format!("cycle count is {} in the interpreter but {} in the block engine",
+            reference.cycles, fast.cycles));
+    }
+
+    if full {
+        let len = reference.ram.len().max(fast.ram.len());
+        if let Some(addr) = (0..len as u32).find(|a| reference.peek(*a) != fast.peek(*a)) {
+            return Err(format!("memory at {:#010x} is {:#06x} in the interpreter but {:#06x} in the block engine",
+                addr, reference.peek(addr), fast.peek(addr)));
+        }
+    }
+
+    Ok(())
+}
+
+
+/// Runs the interpreter and the block engine on the same program in lockstep, checking that
+/// they end up in the same state after every block. Gives the number of instructions run and the
+/// fault both engines stopped on, if there was one, or a description of where they disagreed.
+pub fn differential(start: &Cpu, max: Option<u64>) -> Result<(u64, Option<Fault>), String> {
+    let copy = || {
+        let mut cpu = Cpu::new(start.ram.clone());
+        cpu.set_raw_registers(start.raw_registers());
+        cpu.set_status(start.status());
+        cpu.cycles = start.cycles;
+        cpu.memory_limit = start.memory_limit;
+        cpu.timing = start.timing.clone();
+        cpu
+    };
+    let mut reference = copy();
+    let mut fast = copy();
+    let mut engine = BlockEngine::new();
+
+    let mut count = 0;
+    let mut blocks = 0u64;
+
+    while !fast.halted && Some(count) != max {
+        let start = fast.pc();
+        let (n, result) = engine.step_block(&mut fast, max.map_or(u64::MAX, |m| m - count));
+
+        for _ in 0..n {
+            if let Err(fault) = reference.step() {
+                return Err(format!("the interpreter faulted in the block at {:#010x} but the block engine didn't: {}",
+                    start, fault));
+            }
+        }
+        count += n;
+
+        if let Err(fault) = result {
+            return match reference.step() {
+                Err(f) if f.to_string() == fault.to_string() => {
+                    compare(&reference, &fast, true).map_err(|e| format!("after a fault at {:#010x}, {}", fast.pc(), e))?;
+                    Ok((count, Some(fault)))
+                },
+                _ => Err(format!("the block engine faulted at {:#010x} but the interpreter didn't: {}",
+                    fast.pc(), fault)),
+            };
+        }
+
+        blocks += 1;
+        compare(&reference, &fast, blocks.is_multiple_of(1024))
+            .map_err(|e| format!("after the block at {:#010x}, {}", start, e))?;
+    }
+
+    compare(&reference, &fast, true).map_err(|e| format!("at the end of the run, {}", e))?;
+    Ok((count, None))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add(dst: u8, imm: u8) -> u16 {
+        isa::encode(&Instruction::Im { op: 5, dst, a: dst, imm }).unwrap()
+    }
+
+    fn store_c_at_k() -> u16 {
+        isa::encode(&Instruction::Store { src: 2, addr: 12 }).unwrap()
+    }
+
+    #[test]
+    fn stores_over_the_running_block() {
+        // the store rewrites the last ADD before it runs
+        let ram = vec![store_c_at_k(), add(0, 1), add(0, 1), add(0, 1), 0x0000];
+        let mut cpu = Cpu::new(ram);
+        cpu.set_register(2, add(0, 5)); // C
+        cpu.set_register(0b1100, 3);     // K0
+
+        let mut engine = BlockEngine::new();
+        assert!(matches!(engine.run(&mut cpu, None), Ok(5)));
+        assert_eq!(cpu.register(0), 7);
+        assert_eq!(engine.translated, 2);
+        assert_eq!(engine.invalidated, 1);
+    }
+
+    #[test]
+    fn stores_from_a_single_step() {
+        let mut cpu = Cpu::new(vec![store_c_at_k(), add(1, 1), 0x0000]);
+        cpu.set_register(2, add(1, 5)); // C
+        cpu.set_register(0b1100, 1);     // K0
+        let mut engine = BlockEngine::new();
+
+        // translate the block at 1, then run the store on its own as there's no budget for more
+        cpu.set_pc(1);
+        assert_eq!(engine.step_block(&mut cpu, u64::MAX).0, 2);
+        assert_eq!(cpu.register(1), 1);
+        cpu.halted = false;
+        cpu.set_pc(0);
+        assert_eq!(engine.step_block(&mut cpu, 1).0, 1);
+
+        // the block at 1 is stale now and mustn't be run
+        assert_eq!(engine.step_block(&mut cpu, u64::MAX).0, 2);
+        assert_eq!(cpu.register(1), 6);
+        assert!(cpu.code_writes.is_empty());
+    }
+
+    #[test]
+    fn agrees_with_the_interpreter() {
+        let ram = vec![store_c_at_k(), add(0, 1), add(0, 1), add(0, 1), 0x0000];
+        let mut cpu = Cpu::new(ram);
+        cpu.set_register(2, add(0, 5));
+        cpu.set_register(0b1100, 3);
+        assert!(matches!(differential(&cpu, None), Ok((5, None))));
+    }
+
+    #[test]
+    fn both_engines_get_the_memory_limit() {
+        let mut cpu = Cpu::new(vec![store_c_at_k(), 0x0000]);
+        cpu.set_register(0b1100, 0x40);
+        cpu.memory_limit = 0x20;
+        let result = differential(&cpu, None);
+        assert!(matches!(result, Ok((_, Some(Fault::Memory { addr: 0x40, limit: 0x20 })))));
+    }
+}
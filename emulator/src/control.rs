@@ -0,0 +1,356 @@
+use std::fs;
+
+use isa::{decode, Instruction};
+
+/*
+CONTROL SIGNALS
+
+control_signals() is where an instruction turns into the control lines of the datapath. The
+interpreter drives its buses from it, and the ROM images for the hardware's control unit are made
+by running it over every instruction word, so the two can't disagree.
+
+The control unit is addressed by the instruction word and the flags the jumps look at:
+
+    address bit  18   17   16   15 ... 0
+                 gt   eq   ls   instruction
+
+which is 512K words, each 32 bits wide. That is split over four 512K x 8 ROMs, ROM 0 holding
+bits 7-0 and ROM 3 bits 31-24:
+
+    bits  4-0   alu_op      operation for the ALU (0b1OOOO for TRA and IM)
+    bits  7-5   a_sel       register driven onto the A bus
+    bits 10-8   b_sel       register driven onto the B bus, or onto the RAM bus for STORE
+    bit  11     b_imm       B bus comes from the immediate field (bits 11-6) instead
+    bits 14-12  out_reg     register written by the ALU or by LOAD
+    bit  15     out_write   write the ALU output to out_reg
+    bit  16     mem_read    read RAM into out_reg
+    bit  17     mem_write   write the RAM bus to RAM
+    bits 19-18  addr_sel    address register (P, J, K, L) for memory and JA
+    bit  20     jump        load PC instead of incrementing it
+    bit  21     jump_rel    the new PC is PC plus the offset field (bits 6-0), not addr_sel
+    bit  22     set_flags   update the flags from bits 7-0 of the instruction (SETFLG)
+    bit  23     halt
+    bit  24     fault       the instruction is not one the CPU knows
+    bit  25     b_half      b_sel is a half register (P0 to L1) instead of A to H
+    bit  26     out_half    out_reg is a half register
+    bit  27     alu_fault   the operation in alu_op is not one the ALU has
+    bits 31-28  always 0
+
+PUTH and GETH go through the ALU as a MOV, with one end of it on a half register.
+
+Fields that go from the instruction straight into the datapath (immediates, offsets, SETFLG's
+flag operations) aren't in the ROM, only the lines saying where they go.
+*/
+
+
+pub const ROM_ADDRESS_BITS: u32 = 19;
+pub const ROM_COUNT: usize = 4;
+
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Control {
+    pub alu_op: u8,
+    pub a_sel: u8,
+    pub b_sel: u8,
+    pub b_imm: bool,
+    pub out_reg: u8,
+    pub out_write: bool,
+    pub mem_read: bool,
+    pub mem_write: bool,
+    pub addr_sel: u8,
+    pub jump: bool,
+    pub jump_rel: bool,
+    pub set_flags: bool,
+    pub halt: bool,
+    pub fault: bool,
+    pub b_half: bool,
+    pub out_half: bool,
+    pub alu_fault: bool,
+}
+
+
+/// Whether a JA or JR with condition `cond` jumps. 111 always does, anything else jumps if one
+/// of the flags in the mask is set.
+#[inline]
+pub fn condition_met(cond: u8, gt: bool, eq: bool, ls: bool) -> bool {
+    let flags_num = (gt as u8 * 4) + (eq as u8 * 2) + (ls as u8);
+
+    match cond {
+        0b111 => true,
+        code => code & flags_num != 0,
+    }
+}
+
+
+/// Whether the ALU has the operation a TRA or IM asks for: MOV, ADD, SUB and MUL so far.
+fn alu_has(op: u8) -> bool {
+    (4..=7).contains(&op)
+}
+
+
+/// The address register field of an instruction, from the register number decode gives it.
+fn addr_field(addr: u8) -> u8 {
+    (addr >> 1) & 0b11
+}
+
+
+#[inline]
+pub fn control_signals(instr: &Instruction, gt: bool, eq: bool, ls: bool) -> Control {
+    let mut c = Control::default();
+
+    match *instr {
+        Instruction::Nop => c.halt = true, // for now
+        Instruction::SetFlg { .. } => c.set_flags = true,
+        Instruction::Ja { cond, addr } => {
+            c.addr_sel = addr_field(addr);
+            c.jump = condition_met(cond, gt, eq, ls);
+        },
+        Instruction::Store { src, addr } => {
+            c.b_sel = src;
+            c.addr_sel = addr_field(addr);
+            c.mem_write = true;
+        },
+        Instruction::Load { dst, addr } => {
+            c.out_reg = dst;
+            c.addr_sel = addr_field(addr);
+            c.mem_read = true;
+        },
+        Instruction::PutH { half, src } => {
+            c.alu_op = 0b10100;
+            c.b_sel = src;
+            c.out_reg = half - 8;
+            c.out_half = true;
+            c.out_write = true;
+        },
+        Instruction::GetH { dst, half } => {
+            c.alu_op = 0b10100;
+            c.b_sel = half - 8;
+            c.b_half = true;
+            c.out_reg = dst;
+            c.out_write = true;
+        },
+        Instruction::Jr { cond, .. } => {
+            c.jump = condition_met(cond, gt, eq, ls);
+            c.jump_rel = true;
+        },
+        Instruction::Tra { op, dst, a, b } => {
+            c.alu_op = op | 0b10000;
+            c.a_sel = a;
+            c.b_sel = b;
+            c.out_reg = dst;
+            c.out_write = true;
+            c.alu_fault = !alu_has(op);
+        },
+        Instruction::Im { op, dst, a, .. } => {
+            c.alu_op = op | 0b10000;
+            c.a_sel = a;
+            c.b_imm = true;
+            c.out_reg = dst;
+            c.out_write = true;
+            c.alu_fault = !alu_has(op);
+        },
+        Instruction::Unknown(_) => c.fault = true,
+    }
+
+    c
+}
+
+
+impl Control {
+
+    /// The control word as it is laid out in the ROMs.
+    pub fn word(&self) -> u32 {
+        (self.alu_op as u32 & 0b11111)
+            | (self.a_sel as u32 & 0b111) << 5
+            | (self.b_sel as u32 & 0b111) << 8
+            | (self.b_imm as u32) << 11
+            | (self.out_reg as u32 & 0b111) << 12
+            | (self.out_write as u32) << 15
+            | (self.mem_read as u32) << 16
+            | (self.mem_write as u32) << 17
+            | (self.addr_sel as u32 & 0b11) << 18
+            | (self.jump as u32) << 20
+            | (self.jump_rel as u32) << 21
+            | (self.set_flags as u32) << 22
+            | (self.halt as u32) << 23
+            | (self.fault as u32) << 24
+            | (self.b_half as u32) << 25
+            | (self.out_half as u32) << 26
+            | (self.alu_fault as u32) << 27
+    }
+}
+
+
+/// The contents of the four control ROMs, lowest byte of the control word first.
+pub fn rom_images() -> [Vec<u8>; ROM_COUNT] {
+    let size = 1 << ROM_ADDRESS_BITS;
+    let mut roms = [vec![0; size], vec![0; size], vec![0; size], vec![0; size]];
+
+    for instr in 0..=u16::MAX {
+        let decoded = decode(instr);
+        for flags in 0..8u32 {
+            let (gt, eq, ls) = (flags & 4 != 0, flags & 2 != 0, flags & 1 != 0);
+            let word = control_signals(&decoded, gt, eq, ls).word();
+            let addr = (flags << 16 | instr as u32) as usize;
+            for (n, rom) in roms.iter_mut().enumerate() {
+                rom[addr] = (word >> (8 * n)) as u8;
+            }
+        }
+    }
+
+    roms
+}
+
+
+fn hex_record(kind: u8, addr: u16, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8, (addr >> 8) as u8, addr as u8, kind];
+    bytes.extend_from_slice(data);
+    let checksum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)).wrapping_neg();
+
+    let mut line = String::from(":");
+    for b in bytes.iter().chain([checksum].iter()) {
+        line += &format!("{:02X}", b);
+    }
+    line + "\n"
+}
+
+
+/// An image in Intel HEX, using extended linear address records past the first 64K.
+pub fn intel_hex(image: &[u8]) -> String {
+    let mut out = String::new();
+
+    for (i, chunk) in image.chunks(16).enumerate() {
+        let addr = i * 16;
+        if addr % 0x10000 == 0 && addr != 0 {
+            let upper = (addr >> 16) as u16;
+            out += &hex_record(0x04, 0, &upper.to_be_bytes());
+        }
+        out += &hex_record(0x00, addr as u16, chunk);
+    }
+
+    out + &hex_record(0x01, 0, &[])
+}
+
+
+/// Writes PREFIX0.bin to PREFIX3.bin and the same as .hex. Gives the names of the files written.
+pub fn write_roms(prefix: &str) -> Result<Vec<String>, String> {
+    let mut written = Vec::new();
+
+    for (n, image) in rom_images().iter().enumerate() {
+        let bin = format!("{}{}.bin", prefix, n);
+        fs::write(&bin, image).map_err(|e| format!("Could not write {}: {}", bin, e))?;
+        let hex = format!("{}{}.hex", prefix, n);
+        fs::write(&hex, intel_hex(image)).map_err(|e| format!("Could not write {}: {}", hex, e))?;
+        written.push(bin);
+        written.push(hex);
+    }
+
+    Ok(written)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Fault;
+
+    #[test]
+    fn half_register_moves_have_their_own_lines() {
+        let puth = control_signals(&Instruction::PutH { half: 11, src: 2 }, false, false, false);
+        assert!(puth.out_half && !puth.b_half && puth.out_write);
+        assert_eq!((puth.out_reg, puth.b_sel, puth.alu_op), (3, 2, 0b10100));
+        assert_eq!(puth.word() & (0b11 << 25), 1 << 26);
+
+        let geth = control_signals(&Instruction::GetH { dst: 4, half: 9 }, false, false, false);
+        assert!(geth.b_half && !geth.out_half && geth.out_write);
+        assert_eq!((geth.out_reg, geth.b_sel, geth.alu_op), (4, 1, 0b10100));
+        assert_eq!(geth.word() & (0b11 << 25), 1 << 25);
+
+        // nothing else touches them
+        let mov = control_signals(&Instruction::Im { op: 4, dst: 0, a: 0, imm: 5 }, false, false, false);
+        assert_eq!(mov.word() & (0b11 << 25), 0);
+    }
+
+    // the control word for an address, put back together from the four ROMs
+    fn rom_word(roms: &[Vec<u8>; ROM_COUNT], addr: usize) -> u32 {
+        roms.iter().enumerate().map(|(n, rom)| (rom[addr] as u32) << (8 * n)).sum()
+    }
+
+    #[test]
+    fn rom_faults_agree_with_the_interpreter() {
+        let roms = rom_images();
+
+        for instr in 0..=u16::MAX {
+            let word = rom_word(&roms, instr as usize);
+            let mut cpu = crate::emulator::Cpu::new(vec![instr]);
+            let (fault, alu_fault) = match cpu.step() {
+                Err(Fault::UnknownInstruction(_)) => (true, false),
+                Err(Fault::AluOp(_)) => (true, true),
+                Err(other) => panic!("{:#06x} faulted with {}", instr, other),
+                Ok(()) => (false, false),
+            };
+            assert_eq!(word & (1 << 24) != 0 || word & (1 << 27) != 0, fault, "{:#06x}", instr);
+            assert_eq!(word & (1 << 27) != 0, alu_fault, "{:#06x}", instr);
+        }
+    }
+
+    #[test]
+    fn rom_addressing() {
+        let roms = rom_images();
+        assert!(roms.iter().all(|rom| rom.len() == 1 << 19));
+
+        // STORE C, [K] is the same whatever the flags, and is split across ROMs 0 to 3 lowest first:
+        // b_sel 2 in ROM 1, and mem_write with addr_sel K in ROM 2
+        let store = isa::encode(&Instruction::Store { src: 2, addr: 12 }).unwrap() as usize;
+        for flags in 0..8 {
+            let addr = flags << 16 | store;
+            let bytes: Vec<u8> = roms.iter().map(|rom| rom[addr]).collect();
+            assert_eq!(bytes, [0x00, 0x02, 0x0A, 0x00]);
+        }
+
+        // JA GT, J only jumps when the gt address line is high
+        let ja = isa::encode(&Instruction::Ja { cond: 0b100, addr: 10 }).unwrap() as usize;
+        for flags in 0..8 {
+            let jump = rom_word(&roms, flags << 16 | ja) & (1 << 20) != 0;
+            assert_eq!(jump, flags & 0b100 != 0, "flags {:03b}", flags);
+        }
+
+        // and the ALU fault is in the top ROM
+        let sub_op_0 = isa::encode(&Instruction::Tra { op: 0, dst: 0, a: 0, b: 0 }).unwrap() as usize;
+        assert_eq!(roms[3][sub_op_0], 0b1000);
+    }
+
+    #[test]
+    fn hex_records() {
+        // the example from the Intel HEX specification
+        let data = [0x21, 0x46, 0x01, 0x36, 0x01, 0x21, 0x47, 0x01, 0x36, 0x00, 0x7E, 0xFE, 0x09, 0xD2, 0x19, 0x01];
+        assert_eq!(hex_record(0x00, 0x0100, &data), ":10010000214601360121470136007EFE09D2190140\n");
+        assert_eq!(hex_record(0x01, 0, &[]), ":00000001FF\n");
+        assert_eq!(hex_record(0x04, 0, &[0x00, 0x01]), ":020000040001F9\n");
+    }
+
+    #[test]
+    fn hex_images() {
+        let image: Vec<u8> = (0..0x10014).map(|i| i as u8).collect();
+        let hex = intel_hex(&image);
+        let lines: Vec<&str> = hex.lines().collect();
+
+        // 4096 full records, the jump to the next 64K, one more full and one short record, the end
+        assert_eq!(lines.len(), 0x1000 + 4);
+        assert_eq!(lines[0], ":10000000000102030405060708090A0B0C0D0E0F78");
+        assert_eq!(lines[0xFFF], ":10FFF000F0F1F2F3F4F5F6F7F8F9FAFBFCFDFEFF89");
+        assert_eq!(lines[0x1000], ":020000040001F9");
+        assert_eq!(lines[0x1001], ":10000000000102030405060708090A0B0C0D0E0F78");
+        assert_eq!(lines[0x1002], ":0400100010111213A6");
+        assert_eq!(lines[0x1003], ":00000001FF");
+
+        // every record's bytes add up to 0 with its checksum
+        for line in lines {
+            let bytes: Vec<u8> = (1..line.len()).step_by(2)
+                .map(|i| u8::from_str_radix(&line[i..i + 2], 16).unwrap())
+                .collect();
+            assert_eq!(bytes[0] as usize + 5, bytes.len(), "{}", line);
+            assert_eq!(bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)), 0, "{}", line);
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::{BTreeMap, HashMap};
+
+use isa::lines::LineTable;
+use isa::{Instruction, COND_ALWAYS};
+
+use crate::emulator::{Cpu, Fault};
+
+/*
+CODE COVERAGE
+
+Records how many times every address runs, and which way every conditional jump went, then maps
+them back to source lines with the assembler's line table (.aline). A line is covered if any word
+from it ran. Each conditional JA and JR is two branches, taken and not taken, and a jump that never
+ran counts as neither. Jumps that always or never go are just instructions.
+
+The result is written as an lcov tracefile, which genhtml and most editors can show:
+
+    SF:os.aasm
+    DA:12,4
+    BRDA:13,0,0,3
+    BRDA:13,0,1,1
+    ...
+    end_of_record
+*/
+
+
+/// How many times a conditional jump went each way.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Branch {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+
+#[derive(Debug, Default)]
+pub struct Coverage {
+    pub hits: HashMap<u32, u64>,
+    pub branches: HashMap<u32, Branch>,
+}
+
+
+/// What a source file comes to, line by line.
+#[derive(Default)]
+struct FileCoverage {
+    lines: BTreeMap<u32, u64>,                   // hits, the most of any word on the line
+    branches: BTreeMap<u32, Vec<Option<Branch>>>, // every conditional jump on the line, None if it never ran
+}
+
+
+fn is_conditional(instr: &Instruction) -> bool {
+    match *instr {
+        Instruction::Ja { cond, .. } | Instruction::Jr { cond, .. } => cond != 0 && cond != COND_ALWAYS,
+        _ => false,
+    }
+}
+
+
+fn percent(hit: usize, found: usize) -> f64 {
+    100.0 * hit as f64 / found.max(1) as f64
+}
+
+
+impl Coverage {
+
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    /// Records one instruction that ran at `pc`, and whether it jumped.
+    pub fn record(&mut self, pc: u32, instr: &Instruction, jumped: bool) {
+        *self.hits.entry(pc).or_default() += 1;
+        if is_conditional(instr) {
+            let branch = self.branches.entry(pc).or_default();
+            if jumped {
+                branch.taken += 1;
+            } else {
+                branch.not_taken += 1;
+            }
+        }
+    }
+
+
+    fn by_file(&self, lines: &LineTable, ram: &[u16]) -> BTreeMap<String, FileCoverage> {
+        let mut files: BTreeMap<String, FileCoverage> = BTreeMap::new();
+
+        for (addr, file, line) in lines.iter() {
+            let cov = files.entry(String::from(file)).or_default();
+            let hits = self.hits.get(&addr).copied().unwrap_or(0);
+            let most = cov.lines.entry(line).or_default();
+            *most = (*most).max(hits);
+
+            let instr = isa::decode(ram.get(addr as usize).copied().unwrap_or(0));
+            if is_conditional(&instr) {
+                cov.branches.entry(line).or_default().push(self.branches.get(&addr).copied());
+            }
+        }
+
+        files
+    }
+
+
+    /// The coverage as an lcov tracefile.
+    pub fn to_lcov(&self, lines: &LineTable, ram: &[u16]) -> String {
+        let mut out = String::new();
+
+        for (file, cov) in self.by_file(lines, ram) {
+            out += &format!("TN:\nSF:{}\n", file);
+
+            let (mut found, mut hit) = (0, 0);
+            for (line, branches) in &cov.branches {
+                for (block, branch) in branches.iter().enumerate() {
+                    let counts = match branch {
+                        Some(b) => [b.taken.to_string(), b.not_taken.to_string()],
+                        None => [String::from("-"), String::from("-")],
+                    };
+                    for (num, count) in counts.iter().enumerate() {
+                        out += &format!("BRDA:{},{},{},{}\n", line, block, num, count);
+                        found += 1;
+                        if !matches!(count.as_str(), "-" | "0") {
+                            hit += 1;
+                        }
+                    }
+                }
+            }
+            out += &format!("BRF:{}\nBRH:{}\n", found, hit);
+
+            for (line, hits) in &cov.lines {
+                out += &format!("DA:{},{}\n", line, hits);
+            }
+            out += &format!("LF:{}\nLH:{}\n", cov.lines.len(), cov.lines.values().filter(|h| **h > 0).count());
+            out += "end_of_record\n";
+        }
+
+        out
+    }
+
+
+    /// Lines and branches covered in each file, then the jumps that only ever went one way.
+    pub fn report(&self, lines: &LineTable, ram: &[u16]) -> String {
+        let files = self.by_file(lines, ram);
+        let mut out = String::from("     lines              branches            file\n");
+        let mut one_way = Vec::new();
+
+        for (file, cov) in &files {
+            let lines_hit = cov.lines.values().filter(|h| **h > 0).count();
+            let (mut found, mut hit) = (0, 0);
+            for (line, branches) in &cov.branches {
+                for branch in branches {
+                    found += 2;
+                    let Some(b) = branch else { continue };
+                    hit += (b.taken > 0) as usize + (b.not_taken > 0) as usize;
+                    if b.taken == 0 {
+                        one_way.push(format!("  {}:{}  never taken", file, line));
+                    } else if b.not_taken == 0 {
+                        one_way.push(format!("  {}:{}  always taken", file, line));
+                    }
+                }
+            }
+            out += &format!("{:>5}/{:<5} {:>6.1}%  {:>4}/{:<4} {:>6.1}%  {}\n",
+                lines_hit, cov.lines.len(), percent(lines_hit, cov.lines.len()),
+                hit, found, percent(hit, found), file);
+        }
+
+        if !one_way.is_empty() {
+            out += "\nConditional jumps that only went one way:\n";
+            out += &(one_way.join("\n") + "\n");
+        }
+        out
+    }
+}
+
+
+/// Runs the program on the interpreter, recording every instruction in the coverage.
+pub fn run(cpu: &mut Cpu, coverage: &mut Coverage, max: Option<u64>) -> Result<u64, Fault> {
+    let mut count = 0;
+
+    while !cpu.halted && Some(count) != max {
+        let pc = cpu.pc();
+        let instr = isa::decode(cpu.peek(pc));
+        cpu.step()?;
+        // a jump to the next address is still taken, so this can't be worked out from the new PC
+        coverage.record(pc, &instr, cpu.signals.jump);
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use isa::FlagOp;
+
+    // 0  SETFLG +LS        main.aasm:1
+    // 1  JR LS, $+1        main.aasm:2   taken, to the next word
+    // 2  JR GT, $+2        main.aasm:3   not taken
+    // 3  NOP               main.aasm:4
+    // 4  NOP               main.aasm:4   never runs
+    // 5  JR EQ, $-1        lib.aasm:1    never runs
+    fn program() -> (Cpu, LineTable) {
+        let program = [
+            Instruction::SetFlg { gt: FlagOp::Keep, eq: FlagOp::Keep, ls: FlagOp::Set, ov: FlagOp::Keep },
+            Instruction::Jr { cond: 0b001, offset: 1 },
+            Instruction::Jr { cond: 0b100, offset: 2 },
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::Jr { cond: 0b010, offset: -1 },
+        ];
+        let cpu = Cpu::new(program.iter().map(|i| isa::encode(i).unwrap()).collect());
+        let lines = LineTable::parse("\
+0x0 1 main.aasm
+0x1 2 main.aasm
+0x2 3 main.aasm
+0x3 4 main.aasm
+0x4 4 main.aasm
+0x5 1 lib.aasm
+").unwrap();
+        (cpu, lines)
+    }
+
+    fn covered() -> (Coverage, Cpu, LineTable) {
+        let (mut cpu, lines) = program();
+        let mut coverage = Coverage::new();
+        assert!(matches!(run(&mut cpu, &mut coverage, None), Ok(4)));
+        (coverage, cpu, lines)
+    }
+
+    #[test]
+    fn branches() {
+        let (coverage, _, _) = covered();
+        let branch = |addr| coverage.branches.get(&addr).map(|b| (b.taken, b.not_taken));
+        assert_eq!(branch(1), Some((1, 0)));
+        assert_eq!(branch(2), Some((0, 1)));
+        assert_eq!(branch(5), None);
+        assert_eq!(coverage.branches.len(), 2);
+        assert_eq!((coverage.hits.len(), coverage.hits[&3]), (4, 1));
+    }
+
+    #[test]
+    fn lcov() {
+        let (coverage, cpu, lines) = covered();
+        assert_eq!(coverage.to_lcov(&lines, &cpu.ram), "\
+TN:
+SF:lib.aasm
+BRDA:1,0,0,-
+BRDA:1,0,1,-
+BRF:2
+BRH:0
+DA:1,0
+LF:1
+LH:0
+end_of_record
+TN:
+SF:main.aasm
+BRDA:2,0,0,1
+BRDA:2,0,1,0
+BRDA:3,0,0,0
+BRDA:3,0,1,1
+BRF:4
+BRH:2
+DA:1,1
+DA:2,1
+DA:3,1
+DA:4,1
+LF:4
+LH:4
+end_of_record
+");
+    }
+
+    #[test]
+    fn report() {
+        let (coverage, cpu, lines) = covered();
+        assert_eq!(coverage.report(&lines, &cpu.ram),
+"     lines              branches            file
+    0/1        0.0%     0/2       0.0%  lib.aasm
+    4/4      100.0%     2/4      50.0%  main.aasm
+
+Conditional jumps that only went one way:
+  main.aasm:2  always taken
+  main.aasm:3  never taken
+");
+    }
+}
@@ -0,0 +1,697 @@
+use std::io::{stdin, stdout, BufRead, Write};
+
+use isa::symbols::SymbolTable;
+
+use crate::emulator::{AccessKind, Cpu};
+use crate::expr::{self, Eval, Expr};
+use crate::history::{Delta, History};
+use crate::snapshot;
+
+
+fn pr(statement: &str) {
+    println!("ADBG>>> {}", statement);
+}
+
+
+const HELP: &str = "\
+Commands:
+  s, step [n]               run n instructions (default 1)
+  c, continue               run until a breakpoint, watchpoint, fault or halt
+  rs, reverse-step [n]      take back n instructions (default 1)
+  rc, reverse-continue      run backwards to the previous breakpoint or watched write
+  who-wrote ADDR            find the last recorded instruction that wrote to ADDR
+  b, break ADDR [if COND]   stop before the instruction at ADDR, optionally only when COND holds
+  cond ID [COND]            set or clear the condition of breakpoint ID
+  ignore ID N               let breakpoint ID pass N more times before stopping
+  watch START[..END]        stop after a write to the range (END is exclusive)
+  rwatch START[..END]       stop after a read or instruction fetch from the range
+  awatch START[..END]       stop after any access to the range
+  d, delete ID              remove a breakpoint or watchpoint
+  l, list                   show breakpoints and watchpoints with their hit counts
+  r, regs                   show registers and flags
+  x ADDR [N]                show N words of memory starting at ADDR
+  disas [ADDR [N]]          disassemble N instructions (default 8) from ADDR (default PC)
+  p, print EXPR             evaluate an expression
+  save FILE                 write a snapshot of the machine
+  restore FILE              replace the machine with a snapshot (clears the undo log)
+  q, quit                   leave the debugger
+
+Expressions can use A-H, P0/P1, J0/J1, K0/K1, L0/L1, P (or PC), J, K, L, the flags gt, eq, ls and ov,
+[ADDR] for memory, numbers in decimal, 0x hex, 0b binary or 0o octal, characters like 'A', the usual C
+operators, and lo() and hi() for halves. Where a command takes an address, a label from the symbol file (--symbols) can be given instead.";
+
+
+pub struct Breakpoint {
+    pub id: usize,
+    pub addr: u32,
+    pub condition: Option<(String, Expr)>, // source text, for listing
+    pub ignore: u64, // how many more times to pass before stopping
+    pub hits: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+pub struct Watchpoint {
+    pub id: usize,
+    pub start: u32,
+    pub end: u32, // exclusive
+    pub kind: WatchKind,
+    pub hits: u64,
+}
+
+impl Watchpoint {
+    pub fn triggers(&self, kind: AccessKind, addr: u32) -> bool {
+        let wanted = match self.kind {
+            WatchKind::Read => kind != AccessKind::Write,
+            WatchKind::Write => kind == AccessKind::Write,
+            WatchKind::Access => true,
+        };
+        wanted && addr >= self.start && addr < self.end
+    }
+}
+
+/// One access that matched a watchpoint, recorded by the CPU's memory path.
+pub struct WatchHit {
+    pub id: usize,
+    pub kind: AccessKind,
+    pub addr: u32,
+    pub old: u16,
+    pub new: u16,
+}
+
+
+pub struct Debugger {
+    pub cpu: Cpu,
+    pub breakpoints: Vec<Breakpoint>,
+    pub symbols: Option<SymbolTable>,
+    next_id: usize,
+}
+
+
+
+
+impl Debugger {
+
+    pub fn new(cpu: Cpu) -> Debugger {
+        Debugger {
+            cpu,
+            breakpoints: Vec::new(),
+            symbols: None,
+            next_id: 1,
+        }
+    }
+
+    fn new_id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+
+    /// Checks the breakpoints at the current PC, counting a hit for each whose condition holds.
+    fn breakpoint_hit(&mut self) -> Option<usize> {
+        let pc = self.cpu.pc();
+        let mut stop = None;
+
+        for b in self.breakpoints.iter_mut().filter(|b| b.addr == pc) {
+            let met = match &b.condition {
+                Some((_, cond)) => cond.eval(&self.cpu) != 0,
+                None => true,
+            };
+            if !met {
+                continue;
+            }
+
+            b.hits += 1;
+            if b.ignore > 0 {
+                b.ignore -= 1;
+            } else if stop.is_none() {
+                stop = Some(b.id);
+            }
+        }
+
+        stop
+    }
+
+
+    /// Runs until something worth stopping for, or until `limit` instructions have run.
+    pub fn resume(&mut self, limit: Option<u64>) {
+        let mut steps = 0;
+
+        loop {
+            if self.cpu.halted {
+                pr("Machine is halted.");
+                return;
+            }
+
+            // the breakpoint we're sitting on shouldn't stop us from leaving it
+            if steps > 0 {
+                if let Some(id) = self.breakpoint_hit() {
+                    let hits = self.breakpoints.iter().find(|b| b.id == id).unwrap().hits;
+                    pr(&format!("Breakpoint {} at {:#010x} (hit {} times)", id, self.cpu.pc(), hits));
+                    self.show_location();
+                    return;
+                }
+            }
+
+            let pc = self.cpu.pc();
+            if let Err(fault) = self.cpu.step() {
+                pr(&format!("Fault at {:#010x}: {}", pc, fault));
+                return;
+            }
+            steps += 1;
+
+            if !self.cpu.watch_hits.is_empty() {
+                for hit in self.cpu.watch_hits.drain(..) {
+                    let what = match hit.kind {
+                        AccessKind::Fetch => format!("fetch of {:#06x}", hit.old),
+                        AccessKind::Read => format!("read of {:#06x}", hit.old),
+                        AccessKind::Write => format!("write {:#06x} -> {:#06x}", hit.old, hit.new),
+                    };
+                    pr(&format!("Watchpoint {}: {} at {:#010x} by instruction at {:#010x}",
+                        hit.id, what, hit.addr, pc));
+                }
+                self.show_location();
+                return;
+            }
+
+            if self.cpu.halted {
+                pr(&format!("Halted at {:#010x}", self.cpu.pc()));
+                return;
+            }
+
+            if limit == Some(steps) {
+                self.show_location();
+                return;
+            }
+        }
+    }
+
+
+    /// Undoes instructions until a breakpoint or watched write, or until `limit` are undone.
+    fn reverse(&mut self, limit: Option<u64>) -> Result<(), String> {
+        if self.cpu.history.is_none() {
+            return Err(String::from("History is turned off, start with --history N to use it"));
+        }
+
+        let mut steps = 0;
+        loop {
+            let written: Vec<u32> = match self.cpu.history.as_ref().unwrap().records.back() {
+                Some(record) => record.deltas.iter().filter_map(|d| match d {
+                    Delta::Memory { addr, .. } => Some(*addr),
+                    _ => None,
+                }).collect(),
+                None => {
+                    pr("Reached the start of the recorded history.");
+                    self.show_location();
+                    return Ok(());
+                },
+            };
+
+            self.cpu.undo();
+            steps += 1;
+
+            let watched = self.cpu.watchpoints.iter().find(|w| {
+                w.kind != WatchKind::Read && written.iter().any(|a| *a >= w.start && *a < w.end)
+            });
+            if let Some(w) = watched {
+                pr(&format!("Watchpoint {}: reversed past a write to {:#010x}..{:#010x}",
+                    w.id, w.start, w.end));
+                self.show_location();
+                return Ok(());
+            }
+
+            let pc = self.cpu.pc();
+            let hit = self.breakpoints.iter().find(|b| b.addr == pc && match &b.condition {
+                Some((_, cond)) => cond.eval(&self.cpu) != 0,
+                None => true,
+            });
+            if let Some(b) = hit {
+                pr(&format!("Breakpoint {} at {:#010x}", b.id, pc));
+                self.show_location();
+                return Ok(());
+            }
+
+            if limit == Some(steps) {
+                self.show_location();
+                return Ok(());
+            }
+        }
+    }
+
+
+    /// An address, as a label from the symbol file or an expression.
+    fn eval_addr(&self, text: &str) -> Result<u32, String> {
+        if let Some(addr) = self.symbols.as_ref().and_then(|s| s.lookup(text.trim())) {
+            return Ok(addr);
+        }
+        let value = expr::parse(text)?.eval(&self.cpu);
+        u32::try_from(value).map_err(|_| format!("{} is not an address", value))
+    }
+
+    /// An address with the label it falls under, like `0x00000012 <loop+2>`.
+    fn describe(&self, addr: u32) -> String {
+        match self.symbols.as_ref().and_then(|s| s.containing(addr)) {
+            Some((start, name)) if start == addr => format!("{:#010x} <{}>", addr, name),
+            Some((start, name)) => format!("{:#010x} <{}+{}>", addr, name, addr - start),
+            None => format!("{:#010x}", addr),
+        }
+    }
+
+    fn show_location(&self) {
+        let pc = self.cpu.pc();
+        let word = self.cpu.peek(pc);
+        pr(&format!("{}: {:04x}  {}", self.describe(pc), word,
+            disassembler::disassemble_word(word, pc, self.symbols.as_ref())));
+    }
+
+    fn disassemble(&self, addr: u32, count: u32) {
+        let words: Vec<u16> = (0..count).map(|i| self.cpu.peek(addr.wrapping_add(i))).collect();
+        let pc = self.cpu.pc();
+
+        for line in disassembler::disassemble(&words, addr, self.symbols.as_ref()) {
+            for label in &line.labels {
+                println!("{}:", label);
+            }
+            let marker = if line.addr == pc { "=>" } else { "  " };
+            println!("{} {:#010x}  {:04x}  {}", marker, line.addr, line.word, line.text);
+        }
+    }
+
+
+    fn show_registers(&self) {
+        for num in 0..8 {
+            print!("{}: {:#06x}  ", (b'A' + num as u8) as char, self.cpu.register(num));
+        }
+        println!();
+        for (num, name) in [(0b1000, 'P'), (0b1010, 'J'), (0b1100, 'K'), (0b1110, 'L')] {
+            print!("{}: {:#010x}  ", name, self.cpu.wide_register(num));
+        }
+        println!();
+        println!("gt: {}  eq: {}  ls: {}  ov: {}  cycle: {}",
+            self.cpu.gt_flag as u8, self.cpu.eq_flag as u8, self.cpu.ls_flag as u8, self.cpu.ov_flag as u8,
+            self.cpu.cycles);
+    }
+
+
+    fn add_watchpoint(&mut self, kind: WatchKind, range: &str) -> Result<(), String> {
+        let (start, end) = match range.split_once("..") {
+            Some((start, end)) => (self.eval_addr(start)?, self.eval_addr(end)?),
+            None => {
+                let start = self.eval_addr(range)?;
+                (start, start.saturating_add(1))
+            },
+        };
+        if end <= start {
+            return Err(String::from("Watch range is empty"));
+        }
+
+        let id = self.new_id();
+        self.cpu.watchpoints.push(Watchpoint { id, start, end, kind, hits: 0 });
+        pr(&format!("Watchpoint {}: {} {:#010x}..{:#010x}",
+            id, format!("{:?}", kind).to_lowercase(), start, end));
+        Ok(())
+    }
+
+
+    fn list(&self) {
+        if self.breakpoints.is_empty() && self.cpu.watchpoints.is_empty() {
+            pr("No breakpoints or watchpoints.");
+        }
+        for b in &self.breakpoints {
+            let cond = match &b.condition {
+                Some((text, _)) => format!(" if {}", text),
+                None => String::new(),
+            };
+            let ignore = match b.ignore {
+                0 => String::new(),
+                n => format!(", ignoring next {}", n),
+            };
+            println!("{:>3}  break   {:#010x}{}  (hit {} times{})", b.id, b.addr, cond, b.hits, ignore);
+        }
+        for w in &self.cpu.watchpoints {
+            println!("{:>3}  {:<7} {:#010x}..{:#010x}  (hit {} times)",
+                w.id, format!("{:?}", w.kind).to_lowercase(), w.start, w.end, w.hits);
+        }
+    }
+
+
+    fn breakpoint_mut(&mut self, id: &str) -> Result<&mut Breakpoint, String> {
+        let id: usize = id.parse().map_err(|_| format!("Invalid breakpoint number \"{}\"", id))?;
+        self.breakpoints.iter_mut()
+            .find(|b| b.id == id)
+            .ok_or_else(|| format!("No breakpoint number {}", id))
+    }
+
+
+    /// Runs one line of debugger input. Returns false when it's time to quit.
+    pub fn command(&mut self, line: &str) -> Result<bool, String> {
+        let line = line.trim();
+        let (cmd, rest) = match line.split_once(char::is_whitespace) {
+            Some((cmd, rest)) => (cmd, rest.trim()),
+            None => (line, ""),
+        };
+
+        match cmd {
+            "" => {},
+            "h" | "help" => println!("{}", HELP),
+            "q" | "quit" => return Ok(false),
+
+            "s" | "step" => {
+                let n = match rest {
+                    "" => 1,
+                    n => n.parse().map_err(|_| format!("Invalid step count \"{}\"", n))?,
+                };
+                self.resume(Some(n));
+            },
+            "c" | "continue" => self.resume(None),
+
+            "rs" | "reverse-step" => {
+                let n = match rest {
+                    "" => 1,
+                    n => n.parse().map_err(|_| format!("Invalid step count \"{}\"", n))?,
+                };
+                self.reverse(Some(n))?;
+            },
+            "rc" | "reverse-continue" => self.reverse(None)?,
+            "who-wrote" => {
+                let addr = self.eval_addr(rest)?;
+                let history = self.cpu.history.as_ref().ok_or("History is turned off")?;
+                match history.last_write(addr) {
+                    Some((ago, pc, old, new)) => pr(&format!(
+                        "{:#010x} was last written {} steps ago by the instruction at {:#010x}: {:#06x} -> {:#06x}",
+                        addr, ago, pc, old, new)),
+                    None => pr(&format!("No write to {:#010x} in the last {} steps",
+                        addr, history.records.len())),
+                }
+            },
+
+            "b" | "break" => {
+                let (addr, cond) = match rest.split_once(" if ") {
+                    Some((addr, cond)) => (addr, Some(cond.trim())),
+                    None => (rest, None),
+                };
+                let addr = self.eval_addr(addr)?;
+                let condition = match cond {
+                    Some(text) => Some((String::from(text), expr::parse(text)?)),
+                    None => None,
+                };
+                let id = self.new_id();
+                self.breakpoints.push(Breakpoint { id, addr, condition, ignore: 0, hits: 0 });
+                pr(&format!("Breakpoint {} at {:#010x}", id, addr));
+            },
+            "cond" => {
+                let (id, text) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                let condition = match text.trim() {
+                    "" => None,
+                    text => Some((String::from(text), expr::parse(text)?)),
+                };
+                self.breakpoint_mut(id)?.condition = condition;
+            },
+            "ignore" => {
+                let (id, n) = rest.split_once(char::is_whitespace)
+                    .ok_or_else(|| String::from("Usage: ignore ID N"))?;
+                let n = n.trim().parse().map_err(|_| format!("Invalid count \"{}\"", n.trim()))?;
+                self.breakpoint_mut(id)?.ignore = n;
+            },
+
+            "watch" => self.add_watchpoint(WatchKind::Write, rest)?,
+            "rwatch" => self.add_watchpoint(WatchKind::Read, rest)?,
+            "awatch" => self.add_watchpoint(WatchKind::Access, rest)?,
+
+            "d" | "delete" => {
+                let id: usize = rest.parse().map_err(|_| format!("Invalid number \"{}\"", rest))?;
+                let before = self.breakpoints.len() + self.cpu.watchpoints.len();
+                self.breakpoints.retain(|b| b.id != id);
+                self.cpu.watchpoints.retain(|w| w.id != id);
+                if before == self.breakpoints.len() + self.cpu.watchpoints.len() {
+                    return Err(format!("No breakpoint or watchpoint number {}", id));
+                }
+            },
+            "l" | "list" => self.list(),
+
+            "r" | "regs" => self.show_registers(),
+            "x" => {
+                let mut words = rest.split_whitespace();
+                let addr = self.eval_addr(words.next().ok_or("Usage: x ADDR [N]")?)?;
+                let count: u32 = match words.next() {
+                    Some(n) => n.parse().map_err(|_| format!("Invalid count \"{}\"", n))?,
+                    None => 1,
+                };
+                for row in (0..count).step_by(8) {
+                    print!("{:#010x}:", addr.wrapping_add(row));
+                    for i in row..count.min(row + 8) {
+                        print!(" {:04x}", self.cpu.peek(addr.wrapping_add(i)));
+                    }
+                    println!();
+                }
+            },
+            "disas" => {
+                let mut words = rest.split_whitespace();
+                let addr = match words.next() {
+                    Some(a) => self.eval_addr(a)?,
+                    None => self.cpu.pc(),
+                };
+                let count: u32 = match words.next() {
+                    Some(n) => n.parse().map_err(|_| format!("Invalid count \"{}\"", n))?,
+                    None => 8,
+                };
+                self.disassemble(addr, count);
+            },
+            "p" | "print" => {
+                let value = expr::parse(rest)?.eval(&self.cpu);
+                println!("{} ({:#x})", value, value);
+            },
+
+            "save" => {
+                snapshot::save(&self.cpu, rest)?;
+                pr(&format!("Saved snapshot to {}", rest));
+            },
+            "restore" => {
+                // the snapshot only has the machine's state, so keep how it's set up
+                let mut cpu = snapshot::restore(rest, self.cpu.memory_limit)?;
+                cpu.timing = std::mem::take(&mut self.cpu.timing);
+                cpu.watchpoints = std::mem::take(&mut self.cpu.watchpoints);
+                cpu.history = self.cpu.history.as_ref().map(|h| History::new(h.limit));
+                self.cpu = cpu;
+                self.show_location();
+            },
+
+            other => return Err(format!("Unknown command \"{}\", try help", other)),
+        }
+
+        Ok(true)
+    }
+
+
+    pub fn repl(&mut self) {
+        pr("AustinOS Debugger. Type help for a list of commands.");
+        self.show_location();
+
+        let mut line = String::new();
+        loop {
+            print!("(adbg) ");
+            stdout().flush().unwrap();
+
+            line.clear();
+            match stdin().lock().read_line(&mut line) {
+                Ok(0) | Err(_) => return, // end of input
+                Ok(_) => {},
+            }
+
+            match self.command(&line) {
+                Ok(true) => {},
+                Ok(false) => return,
+                Err(e) => pr(&e),
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use isa::Instruction;
+
+    // 0: ADD A, A, #1
+    // 1: STORE A, [K]
+    // 2: LOAD B, [K]
+    // 3: JR -3
+    fn looping() -> Debugger {
+        let program = [
+            Instruction::Im { op: 5, dst: 0, a: 0, imm: 1 },
+            Instruction::Store { src: 0, addr: 12 },
+            Instruction::Load { dst: 1, addr: 12 },
+            Instruction::Jr { cond: 0b111, offset: -3 },
+        ];
+        let mut ram: Vec<u16> = program.iter().map(|i| isa::encode(i).unwrap()).collect();
+        ram.resize(0x20, 0);
+        let mut cpu = Cpu::new(ram);
+        cpu.set_register(0b1100, 0x10); // K0
+        Debugger::new(cpu)
+    }
+
+    type State = ([u32; 12], u8, Vec<u16>, u64, Vec<u64>);
+
+    // everything a reverse step has to put back
+    fn state(dbg: &Debugger) -> State {
+        let hits = dbg.cpu.watchpoints.iter().map(|w| w.hits).collect();
+        (dbg.cpu.raw_registers(), dbg.cpu.status(), dbg.cpu.ram.clone(), dbg.cpu.cycles, hits)
+    }
+
+    fn run(dbg: &mut Debugger, commands: &[&str]) {
+        for c in commands {
+            assert_eq!(dbg.command(c), Ok(true), "{}", c);
+        }
+    }
+
+    #[test]
+    fn conditional_breakpoints() {
+        let mut dbg = looping();
+        run(&mut dbg, &["break 2 if A == 3", "continue"]);
+        assert_eq!((dbg.cpu.pc(), dbg.cpu.register(0)), (2, 3));
+        assert_eq!(dbg.breakpoints[0].hits, 1); // only counted when the condition holds
+
+        run(&mut dbg, &["cond 1 A % 2 == 0", "continue"]);
+        assert_eq!((dbg.cpu.pc(), dbg.cpu.register(0)), (2, 4));
+
+        run(&mut dbg, &["cond 1", "continue"]);
+        assert_eq!((dbg.cpu.pc(), dbg.cpu.register(0)), (2, 5));
+        assert_eq!(dbg.breakpoints[0].hits, 3);
+
+        assert!(dbg.command("cond 1 A = 1").is_err());
+        assert!(dbg.command("cond 7 A == 1").is_err());
+    }
+
+    #[test]
+    fn ignore_counts() {
+        let mut dbg = looping();
+        run(&mut dbg, &["break 0", "ignore 1 2", "continue"]);
+
+        // passes the first two times it comes back around, but still counts them
+        assert_eq!((dbg.cpu.pc(), dbg.cpu.register(0)), (0, 3));
+        assert_eq!((dbg.breakpoints[0].hits, dbg.breakpoints[0].ignore), (3, 0));
+
+        run(&mut dbg, &["continue"]);
+        assert_eq!(dbg.cpu.register(0), 4);
+    }
+
+    #[test]
+    fn write_watchpoints() {
+        let mut dbg = looping();
+        run(&mut dbg, &["watch 0x10", "continue"]);
+        assert_eq!(dbg.cpu.pc(), 2); // stops after the store
+        run(&mut dbg, &["continue"]);
+        assert_eq!((dbg.cpu.pc(), dbg.cpu.register(0)), (2, 2));
+        assert_eq!(dbg.cpu.watchpoints[0].hits, 2);
+    }
+
+    #[test]
+    fn read_watchpoints() {
+        let mut dbg = looping();
+        run(&mut dbg, &["rwatch 0x10", "continue"]);
+        assert_eq!(dbg.cpu.pc(), 3); // the load, not the store
+        assert_eq!(dbg.cpu.register(1), 1);
+
+        // fetching an instruction counts as a read
+        let mut dbg = looping();
+        run(&mut dbg, &["rwatch 3", "continue"]);
+        assert_eq!(dbg.cpu.pc(), 0);
+    }
+
+    #[test]
+    fn access_watchpoints() {
+        let mut dbg = looping();
+        run(&mut dbg, &["awatch 0x10..0x11", "continue"]);
+        assert_eq!(dbg.cpu.pc(), 2);
+        run(&mut dbg, &["continue"]);
+        assert_eq!(dbg.cpu.pc(), 3);
+        assert_eq!(dbg.cpu.watchpoints[0].hits, 2);
+
+        // outside the range
+        run(&mut dbg, &["delete 1", "awatch 0x11..0x20", "step 8"]);
+        assert_eq!(dbg.cpu.pc(), 3);
+        assert!(dbg.command("awatch 0x11..0x11").is_err());
+    }
+
+    #[test]
+    fn reverse_steps_put_everything_back() {
+        let mut dbg = looping();
+        dbg.cpu.history = Some(History::new(100));
+        run(&mut dbg, &["rwatch 0x10", "step 3"]);
+        let before = state(&dbg);
+        assert_eq!(dbg.cpu.watchpoints[0].hits, 1);
+
+        for _ in 0..7 {
+            run(&mut dbg, &["step"]);
+        }
+        assert_ne!(state(&dbg), before);
+        assert_eq!(dbg.cpu.watchpoints[0].hits, 2);
+
+        run(&mut dbg, &["rs 7"]);
+        assert_eq!(state(&dbg), before);
+
+        run(&mut dbg, &["rs 3"]);
+        assert_eq!(dbg.cpu.pc(), 0);
+        assert_eq!((dbg.cpu.cycles, dbg.cpu.watchpoints[0].hits), (0, 0));
+        assert_eq!(dbg.cpu.peek(0x10), 0);
+    }
+
+    #[test]
+    fn reverse_continue() {
+        let mut dbg = looping();
+        dbg.cpu.history = Some(History::new(100));
+        run(&mut dbg, &["step 6"]);
+        let at_six = state(&dbg);
+        run(&mut dbg, &["step 4", "break 2", "rc"]);
+        assert_eq!(dbg.cpu.pc(), 2);
+        assert_eq!(state(&dbg), at_six);
+
+        // with nothing in the way it goes back to the start of the history
+        let mut dbg = looping();
+        let start = state(&dbg);
+        dbg.cpu.history = Some(History::new(100));
+        run(&mut dbg, &["step 10", "rc"]);
+        assert_eq!(state(&dbg), start);
+
+        // and stops at a watched write on the way, just before the store of 3
+        run(&mut dbg, &["step 10", "watch 0x10", "rc"]);
+        assert_eq!((dbg.cpu.pc(), dbg.cpu.peek(0x10)), (1, 2));
+    }
+
+    #[test]
+    fn who_wrote() {
+        let mut dbg = looping();
+        assert!(dbg.command("who-wrote 0x10").is_err()); // no history
+        dbg.cpu.history = Some(History::new(100));
+
+        run(&mut dbg, &["step 6", "who-wrote 0x10"]);
+        let history = dbg.cpu.history.as_ref().unwrap();
+        assert_eq!(history.last_write(0x10), Some((1, 1, 1, 2)));
+        assert_eq!(history.last_write(0x11), None);
+
+        run(&mut dbg, &["step 2"]);
+        let history = dbg.cpu.history.as_ref().unwrap();
+        assert_eq!(history.last_write(0x10), Some((3, 1, 1, 2)));
+    }
+
+    #[test]
+    fn restore_keeps_the_memory_limit_and_timing() {
+        let path = std::env::temp_dir().join(format!("adbg-restore-{}.astate", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+
+        let mut dbg = looping();
+        dbg.cpu.memory_limit = 0x20;
+        dbg.cpu.timing.branch_taken = 7;
+        run(&mut dbg, &["step 2", &format!("save {}", path), "step 3", &format!("restore {}", path)]);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!((dbg.cpu.pc(), dbg.cpu.peek(0x10)), (2, 1));
+        assert_eq!((dbg.cpu.memory_limit, dbg.cpu.timing.branch_taken), (0x20, 7));
+    }
+}
@@ -1,4 +1,10 @@
-use std::time::Instant;
+use std::fmt;
+
+use crate::control::{self, control_signals};
+use crate::debugger::{WatchHit, Watchpoint};
+use isa::{decode, InstrClass, Instruction};
+use crate::history::{Delta, History};
+use crate::timing::TimingTable;
 
 enum Register {
     Arr(ArrRegister),
@@ -6,13 +12,11 @@ enum Register {
 }
 
 struct ArrRegister {
-    num: usize,
     name: char,
     value: u16,
 }
 
 struct AddrRegister {
-    num: usize,
     name: char,
     value: u32,
 }
@@ -65,29 +69,41 @@ impl Register {
     fn wide_write(&mut self, value: u32) {
         match self {
             Self::Addr(a) => a.value = value,
-            (_) => panic!("Provided an Arithemetic Register to wide_write function."),
+            _ => panic!("Provided an Arithemetic Register to wide_write function."),
         }
     }
 
     fn wide_read(&self) -> u32 {
         match self {
             Self::Addr(a) => a.value,
-            (_) => panic!("Provided an Arithemetic Register to wide_write function."),
+            _ => panic!("Provided an Arithemetic Register to wide_write function."),
+        }
+    }
+
+    fn raw(&self) -> u32 {
+        match self {
+            Self::Arr(a) => a.value as u32,
+            Self::Addr(a) => a.value,
+        }
+    }
+
+    fn set_raw(&mut self, value: u32) {
+        match self {
+            Self::Arr(a) => a.value = value as u16,
+            Self::Addr(a) => a.value = value,
         }
     }
 }
 
-fn init_ArrRegister(num: usize, name: char) -> Register {
+fn init_ArrRegister(name: char) -> Register {
     Register::Arr(ArrRegister {
-        num,
         name,
         value: 0,
     })
 }
 
-fn init_AddrRegister(num: usize, name: char) -> Register {
+fn init_AddrRegister(name: char) -> Register {
     Register::Addr(AddrRegister {
-        num,
         name,
         value: 0,
     })
@@ -96,333 +112,482 @@ fn init_AddrRegister(num: usize, name: char) -> Register {
 
 
 
-fn read_register(num: u16, registers: &[Register; 12]) -> u16 {
+// where register number `num` lives in the register file
+#[inline]
+fn register_index(num: u16) -> usize {
     if num < 8 {
-        registers
-            .get(num as usize)
-            .unwrap()
-            .read(false)
+        num as usize
     } else {
-        let pos = ((num / 2) + 4);
-        registers
-            .get(pos as usize)
-            .unwrap()
-            .read((num % 2) != 0)
+        ((num / 2) + 4) as usize
     }
 }
 
+
+#[inline]
+fn read_register(num: u16, registers: &[Register; 12]) -> u16 {
+    registers[register_index(num)].read(num & 1 != 0)
+}
+
+#[inline]
 fn write_register(num: u16, value: u16, registers: &mut [Register; 12]){
-    if num < 8 {
-        registers
-            .get_mut(num as usize)
-            .unwrap()
-            .write(value, false);
-    } else {
-        let pos = ((num / 2) + 4);
-        registers
-            .get_mut(pos as usize)
-            .unwrap()
-            .write(value, (num % 2) != 0);
-    }
+    registers[register_index(num)].write(value, num & 1 != 0);
 }
 
 
+#[inline]
 fn read_wide_register(num: u16, registers: &[Register; 12]) -> u32 {
-
-    let pos = ((num / 2) + 4);
-    registers
-        .get(pos as usize)
-        .unwrap()
-        .wide_read()
-
+    registers[register_index(num)].wide_read()
 }
 
+#[inline]
 fn write_wide_register(num: u16, value: u32, registers: &mut [Register; 12]){
+    registers[register_index(num)].wide_write(value)
+}
 
-    let pos = ((num / 2) + 4);
-    registers
-        .get_mut(pos as usize)
-        .unwrap()
-        .wide_write(value)
 
+/// Where a memory access came from, so watchpoints can tell an instruction fetch from a LOAD.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AccessKind {
+    Fetch,
+    Read,
+    Write,
 }
 
 
-fn read_ram(addr: u32, ram: &Vec<u16>) -> u16 {
+/// Anything that stops the CPU short of finishing an instruction. These used to be panics.
+#[derive(Debug)]
+pub enum Fault {
+    UnknownInstruction(u16),
+    AluOp(u16),
+    Io(u32),
+    Memory { addr: u32, limit: u32 }, // a write past the end of memory
+}
 
-    if addr < 0xF000_0000 {
-        match ram.get(addr as usize) {
-            Some(val) => *val,
-            None => 0x0000,
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownInstruction(instr) => write!(f, "Do not know what to do with {:#06x}", instr),
+            Self::AluOp(op) => write!(f, "ALU Op {:#06b} not implemented yet", op),
+            Self::Io(addr) => write!(f, "Do not know how to use IO yet. (Address: {:#010x})", addr),
+            Self::Memory { addr, limit } => write!(f, "Write to {:#010x} is past the end of memory, which \
+                is {:#x} words (see --memory)", addr, limit),
         }
-    } else {
-        panic!("Do not know how to use IO yet. (Address: {})", addr)
     }
 }
 
 
-
-fn write_ram(addr: u32, val: u16, ram: &mut Vec<u16>) {
-
-    if addr < 0xF000_0000 {
-        ram[addr as usize] = val;
-    } else {
-        panic!("Do not know how to use IO yet. (Address: {})", addr)
-    }
+/// The buses and control lines of the last instruction to run, as the hardware would see them.
+/// One that faulted only gets its pc and instr, with everything else idle. Only the interpreter
+/// fills these in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Signals {
+    pub pc: u32,
+    pub instr: u16,
+    pub a_bus: u16,
+    pub b_bus: u16,
+    pub out_bus: u16,
+    pub out_reg: u16,
+    pub out_write: bool,
+    pub ram_bus: u16,
+    pub alu_op: u16,
+    pub mem_read: bool,
+    pub mem_write: bool,
+    pub jump: bool,
 }
 
 
+pub struct Cpu {
+    registers: [Register; 12], // our 16 registers
+    pub ram: Vec<u16>,
+    pub memory_limit: u32, // how many words writes can grow RAM to
+    decoded: Vec<Option<Instruction>>, // decode cache, one slot per word of RAM
 
+    pub gt_flag: bool,
+    pub eq_flag: bool,
+    pub ls_flag: bool,
+    pub ov_flag: bool,
 
+    pub halted: bool,
+    pub trace: bool, // print every stage as it happens
+    pub signals: Signals,
 
+    pub cycles: u64,
+    pub timing: TimingTable,
+    pub mix: [u64; InstrClass::COUNT], // instructions run of each class
 
-pub fn run(mut ram: Vec<u16>) {
-
-    let mut registers; // our 16 registers
-    let mut instr: u16; // instruction "register" (only accessable by decode)
-    let mut pc: u32;
-
-    let mut a_bus: u16;
-    let mut b_bus: u16;
-
-    let mut out_bus: u16;
-    let mut out_reg: u16;
-    let mut out_write: bool;
-
-    let mut ram_bus: u16;
-
-    let mut alu_op: u16;
-
-    let mut gt_flag: bool = false;
-    let mut eq_flag: bool = false;
-    let mut ls_flag: bool = false;
-    let mut ov_flag: bool = false;
-
-    let mut mem_read: bool = false;
-    let mut mem_write: bool = false;
+    pub watchpoints: Vec<Watchpoint>,
+    pub watch_hits: Vec<WatchHit>, // filled by the memory path, drained by the debugger
 
+    pub history: Option<History>, // undo log for reverse execution, off unless asked for
 
+    // pages (of 1 << CODE_PAGE_BITS words) holding translated blocks, and writes that hit them
+    pub code_pages: Vec<bool>,
+    pub code_writes: Vec<u32>,
+}
 
-    let mut jump: bool = false;
-
-    registers = [
-        init_ArrRegister(0, 'A'),
-        init_ArrRegister(1, 'B'),
-        init_ArrRegister(2, 'C'),
-        init_ArrRegister(3, 'D'),
-        init_ArrRegister(4, 'E'),
-        init_ArrRegister(5, 'F'),
-        init_ArrRegister(6, 'G'),
-        init_ArrRegister(7, 'H'),
-        init_AddrRegister(0, 'P'),
-        init_AddrRegister(1, 'J'),
-        init_AddrRegister(2, 'K'),
-        init_AddrRegister(3, 'L'),
-    ];
+pub const CODE_PAGE_BITS: u32 = 6;
+
+/// How many words of memory there are unless asked for more, or the program is bigger.
+pub const DEFAULT_MEMORY: u32 = 1 << 20;
+
+
+impl Cpu {
+
+    pub fn new(ram: Vec<u16>) -> Cpu {
+        Cpu {
+            registers: [
+                init_ArrRegister('A'),
+                init_ArrRegister('B'),
+                init_ArrRegister('C'),
+                init_ArrRegister('D'),
+                init_ArrRegister('E'),
+                init_ArrRegister('F'),
+                init_ArrRegister('G'),
+                init_ArrRegister('H'),
+                init_AddrRegister('P'),
+                init_AddrRegister('J'),
+                init_AddrRegister('K'),
+                init_AddrRegister('L'),
+            ],
+            decoded: vec![None; ram.len()],
+            memory_limit: DEFAULT_MEMORY.max(ram.len() as u32),
+            ram,
+            gt_flag: false,
+            eq_flag: false,
+            ls_flag: false,
+            ov_flag: false,
+            halted: false,
+            trace: false,
+            signals: Signals::default(),
+            cycles: 0,
+            timing: TimingTable::default(),
+            mix: [0; InstrClass::COUNT],
+            watchpoints: Vec::new(),
+            watch_hits: Vec::new(),
+            history: None,
+            code_pages: Vec::new(),
+            code_writes: Vec::new(),
+        }
+    }
 
+    pub fn pc(&self) -> u32 {
+        read_wide_register(0b1000, &self.registers)
+    }
 
-    let mut loops = 0;
+    /// Reads a 16 bit register by its instruction number: A-H are 0-7, the halves of P, J, K
+    /// and L are 8-15.
+    #[inline]
+    pub fn register(&self, num: u16) -> u16 {
+        read_register(num, &self.registers)
+    }
 
-    loop {
+    /// Reads a full address register by its instruction number (8, 10, 12 or 14).
+    #[inline]
+    pub fn wide_register(&self, num: u16) -> u32 {
+        read_wide_register(num, &self.registers)
+    }
 
-        loops += 1;
-        if loops == 100 {
-            break;
+    /// The whole register file as raw values, A-H then P, J, K and L.
+    pub fn raw_registers(&self) -> [u32; 12] {
+        let mut raw = [0; 12];
+        for (r, reg) in raw.iter_mut().zip(self.registers.iter()) {
+            *r = reg.raw();
         }
+        raw
+    }
 
-        // time measurement.
-        let now = Instant::now();
-
-
-//-------------------------------------- INSTRUCTION FETCH ----------------------------------------
+    pub fn set_raw_registers(&mut self, raw: [u32; 12]) {
+        for (reg, r) in self.registers.iter_mut().zip(raw) {
+            reg.set_raw(r);
+        }
+    }
 
-        pc = read_wide_register(0b1000, &registers);
-        println!("PC: {:#010x}", pc);
+    /// Flags and the halted bit packed into one byte, so the undo log can save them in one go.
+    pub fn status(&self) -> u8 {
+        (self.gt_flag as u8) << 4 | (self.eq_flag as u8) << 3 | (self.ls_flag as u8) << 2
+            | (self.ov_flag as u8) << 1 | self.halted as u8
+    }
 
-        instr = read_ram(pc, &ram);
+    pub fn set_status(&mut self, status: u8) {
+        self.gt_flag = status & 0b10000 != 0;
+        self.eq_flag = status & 0b01000 != 0;
+        self.ls_flag = status & 0b00100 != 0;
+        self.ov_flag = status & 0b00010 != 0;
+        self.halted = status & 0b00001 != 0;
+    }
 
-        a_bus = 0;
-        b_bus = 0;
-        out_bus = 0;
-        ram_bus = 0;
-        alu_op = 0;
+    pub fn set_register(&mut self, num: u16, value: u16) {
+        if let Some(h) = &mut self.history {
+            let index = register_index(num);
+            h.record(Delta::Register { index, old: self.registers[index].raw() });
+        }
+        write_register(num, value, &mut self.registers);
+    }
 
-        out_reg = 0;
-        out_write = false;
+    pub fn set_pc(&mut self, pc: u32) {
+        if let Some(h) = &mut self.history {
+            h.record(Delta::Register { index: 8, old: self.registers[8].raw() });
+        }
+        write_wide_register(0b1000, pc, &mut self.registers);
+    }
 
-        mem_write = true;
-        mem_read = true;
+    /// Reads memory without going through watchpoints, for the debugger to look around with.
+    pub fn peek(&self, addr: u32) -> u16 {
+        self.ram.get(addr as usize).copied().unwrap_or(0x0000)
+    }
 
-        jump = false;
 
+    pub fn read_ram(&mut self, addr: u32, kind: AccessKind) -> Result<u16, Fault> {
 
-        /*
-        println!("Instr:  0b{:04b}_{:04b}_{:04b}_{:04b}",(instr & 0xF000) >> 12,
-            (instr & 0x0F00) >> 8,(instr & 0x00F0) >> 4,(instr & 0x000F));
-        */
+        if addr >= 0xF000_0000 {
+            return Err(Fault::Io(addr));
+        }
 
+        let val = self.peek(addr);
+        self.cycles += self.timing.wait(addr);
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(kind, addr, val, val);
+        }
+        Ok(val)
+    }
 
 
-//-------------------------------------- DECODE ---------------------------------------------------
+    pub fn write_ram(&mut self, addr: u32, val: u16) -> Result<(), Fault> {
 
-        println!("Instr: 0b {:04b}_{:04b}_{:04b}_{:04b}",(instr & 0xF000) >> 12,
-            (instr & 0x0F00) >> 8,(instr & 0x00F0) >> 4,(instr & 0x000F));
+        if addr >= 0xF000_0000 {
+            return Err(Fault::Io(addr));
+        }
+        if addr as usize >= self.ram.len() && addr >= self.memory_limit {
+            return Err(Fault::Memory { addr, limit: self.memory_limit });
+        }
 
+        self.cycles += self.timing.wait(addr);
 
-        if instr == 0 { // NOP
-            break; // for now
-            //continue;
+        if !self.watchpoints.is_empty() || self.history.is_some() {
+            let old = self.peek(addr);
+            self.check_watchpoints(AccessKind::Write, addr, old, val);
+            if let Some(h) = &mut self.history {
+                h.record(Delta::Memory { addr, old, new: val });
+            }
+        }
 
-        } else if instr < 0x0200 {
-            panic!("Do not know what to do with {:#06x}", instr);
+        if addr as usize >= self.ram.len() {
+            self.ram.resize(addr as usize + 1, 0x0000);
+            self.decoded.resize(addr as usize + 1, None);
+        }
+        self.ram[addr as usize] = val;
+        self.decoded[addr as usize] = None;
 
-        } else if instr < 0x0300 { // SETFLG
+        if let Some(true) = self.code_pages.get((addr >> CODE_PAGE_BITS) as usize) {
+            self.code_writes.push(addr);
+        }
+        Ok(())
+    }
 
 
-            gt_flag = match (instr & 0x00C0) >> 6 {
-                0b00 => false,
-                0b01 => gt_flag,
-                0b10 => !gt_flag,
-                0b11 => true,
-                (e) => {panic!("Incorrect SETFLG value {}, should be unreachable", e)},
-            };
+    fn check_watchpoints(&mut self, kind: AccessKind, addr: u32, old: u16, new: u16) {
+        for w in self.watchpoints.iter_mut() {
+            if w.triggers(kind, addr) {
+                w.hits += 1;
+                self.watch_hits.push(WatchHit { id: w.id, kind, addr, old, new });
+                if let Some(h) = &mut self.history {
+                    h.record(Delta::WatchHit { id: w.id });
+                }
+            }
+        }
+    }
 
-            eq_flag = match (instr & 0x0030) >> 4 {
-                0b00 => false,
-                0b01 => eq_flag,
-                0b10 => !eq_flag,
-                0b11 => true,
-                (e) => {panic!("Incorrect SETFLG value {}, should be unreachable", e)},
-            };
 
-            ls_flag = match (instr & 0x000C) >> 2 {
-                0b00 => false,
-                0b01 => ls_flag,
-                0b10 => !ls_flag,
-                0b11 => true,
-                (e) => {panic!("Incorrect SETFLG value {}, should be unreachable", e)},
-            };
+    #[inline]
+    pub fn condition_met(&self, jump_code: u8) -> bool {
+        control::condition_met(jump_code, self.gt_flag, self.eq_flag, self.ls_flag)
+    }
 
-            ov_flag = match (instr & 0x0003) {
-                0b00 => false,
-                0b01 => ov_flag,
-                0b10 => !ov_flag,
-                0b11 => true,
-                (e) => {panic!("Incorrect SETFLG value {}, should be unreachable", e)},
-            };
 
+    /// Runs a single instruction through every stage.
+    pub fn step(&mut self) -> Result<(), Fault> {
+        if self.history.is_none() {
+            return self.execute();
+        }
 
-            println!("Flags: {} {} {} {}", gt_flag, eq_flag, ls_flag, ov_flag);
+        let pc = self.pc();
+        let status = self.status();
+        let cycles = self.cycles;
 
+        let result = self.execute();
 
-        } else if instr < 0b0000_0011_0010_0000 { // JA
+        let changed = self.status() != status;
+        let h = self.history.as_mut().unwrap();
+        if changed {
+            h.record(Delta::Status { old: status });
+        }
+        h.commit(pc, cycles);
 
+        result
+    }
 
-            let jump_code = (instr & 0b0000_0000_0001_1100) >> 2;
-            let flags_num = (gt_flag as u16 * 4) + (eq_flag as u16 * 2) + (ls_flag as u16 * 1);
 
-            jump = match jump_code {
-                0b111 => true,
-                0b000 => (jump_code % 2) == 1,
-                other => jump_code == other,
-            };
+    /// Takes back the last instruction in the undo log. Gives the PC it had been fetched from.
+    pub fn undo(&mut self) -> Option<u32> {
+        let record = self.history.as_mut()?.pop()?;
 
-            if jump {
-                pc = read_wide_register(((instr & 0x0003) << 1 ) | 0b1000, &registers);
+        for delta in record.deltas.iter().rev() {
+            match *delta {
+                Delta::Register { index, old } => self.registers[index].set_raw(old),
+                Delta::Memory { addr, old, .. } => {
+                    self.ram[addr as usize] = old;
+                    self.decoded[addr as usize] = None;
+                },
+                Delta::Status { old } => self.set_status(old),
+                Delta::Mix { class } => self.mix[class] -= 1,
+                Delta::WatchHit { id } => {
+                    // the watchpoint may have been deleted since
+                    if let Some(w) = self.watchpoints.iter_mut().find(|w| w.id == id) {
+                        w.hits -= 1;
+                    }
+                },
             }
+        }
 
+        self.cycles = record.cycles;
+        Some(record.pc)
+    }
 
-        } else if instr < 0b0000_0011_0100_0000 { // STORE
-
-            write_ram(
-                read_wide_register(
-                    ((instr & 0x0003) << 1) | 0b1000,
-                    &registers
-                ),
-                read_register(
-                    (instr & 0x001C) >> 2,
-                    &registers
-                ),
-                &mut ram
-            );
-
-
-        } else if instr < 0b0000_0011_0110_0000 { // LOAD
 
-            write_register(
-                (instr & 0x001C) >> 2,
-                read_ram(
-                    read_wide_register(
-                        ((instr & 0x0003) << 1) | 0b1000,
-                        &registers
-                    ),
-                    &ram
-                ),
-                &mut registers
-            );
+    /// Decodes the word at `addr`, reusing the last decode if that word hasn't been written since.
+    #[inline]
+    fn decoded(&mut self, addr: u32, instr: u16) -> Instruction {
+        match self.decoded.get_mut(addr as usize) {
+            Some(Some(d)) => *d,
+            Some(slot) => {
+                let d = decode(instr);
+                *slot = Some(d);
+                d
+            },
+            None => decode(instr), // past the end of RAM, where everything reads as 0
+        }
+    }
 
-        } else if instr < 0b0000_0100_0000_0000 {
 
-            panic!("Do not know what to do with {:#06x}", instr);
+    fn execute(&mut self) -> Result<(), Fault> {
 
-        } else if instr < 0b0000_1000_0000_0000 { // JR
+        let mut pc: u32;
 
-            let jump_code = (instr & 0b0000_0011_1000_0000) >> 2;
+        let mut a_bus: u16 = 0;
+        let mut b_bus: u16 = 0;
 
-            jump = match jump_code {
-                0b111 => true,
-                0b000 => (jump_code % 2) == 1,
-                other => jump_code == other,
-            };
+        let mut out_bus: u16 = 0;
+        let mut ram_bus: u16 = 0;
 
-            let relative = (instr & 0b0000_0000_0111_1111);
-
-            if jump {
-                pc = pc + relative;
-            }
+        // the control lines all come from control_signals, after decode
 
 
+//-------------------------------------- INSTRUCTION FETCH ----------------------------------------
 
-        } else if ((instr & 0b1110_0000_0000_0000) >> 13) == 1 { // TRA
+        pc = self.pc();
+        if self.trace {
+            println!("PC: {:#010x}", pc);
+        }
 
-            a_bus = read_register(instr & 0b0000_0000_0000_0111, &registers);
-            b_bus = read_register((instr & 0b0000_0000_0011_1000) >> 3, &registers);
+        let instr: u16 = match self.read_ram(pc, AccessKind::Fetch) { // instruction "register" (only accessable by decode)
+            Ok(instr) => instr,
+            Err(fault) => {
+                self.signals = Signals { pc, ..Signals::default() };
+                return Err(fault);
+            },
+        };
+        // what a fault further on leaves on the lines, until the whole instruction is known
+        self.signals = Signals { pc, instr, ..Signals::default() };
 
 
-            out_reg = (instr & 0b0000_0001_1100_0000) >> 6;
-            out_write = true;
+//-------------------------------------- DECODE ---------------------------------------------------
 
-            alu_op =  ((instr & 0b0001_1110_0000_0000) >> 9) | 0b10000;
+        let decoded = self.decoded(pc, instr);
+        self.mix[decoded.class() as usize] += 1;
+        if let Some(h) = &mut self.history {
+            h.record(Delta::Mix { class: decoded.class() as usize });
+        }
+        self.cycles += self.timing.cost(decoded.class());
 
-            //println!("{:#06x}", a_bus);
-            //println!("{:#06x}", b_bus);
+        if self.trace {
+            println!("Instr: {:04x} {}", instr, disassembler::format_instruction(&decoded, pc, None));
+        }
 
 
+        let ctl = control_signals(&decoded, self.gt_flag, self.eq_flag, self.ls_flag);
 
-        } else if instr > 0b0011_1111_1111_1111{ // IM
+        if ctl.halt {
+            self.halted = true;
+            return Ok(());
+        }
+        if ctl.fault {
+            return Err(Fault::UnknownInstruction(instr));
+        }
+        if ctl.alu_fault {
+            return Err(Fault::AluOp(ctl.alu_op as u16));
+        }
 
-            a_bus = read_register(instr & 0b0000_0000_0000_0111, &registers);
-            b_bus = (instr & 0b0000_1111_1100_0000) >> 6;
+        let alu_op = ctl.alu_op as u16;
+        let out_reg = ctl.out_reg as u16 | if ctl.out_half { 0b1000 } else { 0 };
+        let out_write = ctl.out_write;
+        let mem_read = ctl.mem_read;
+        let mem_write = ctl.mem_write;
+        let jump = ctl.jump;
+
+        // register number of the bottom half of the address register
+        let addr_reg = ((ctl.addr_sel as u16) << 1) | 0b1000;
+
+        if ctl.set_flags {
+            if let Instruction::SetFlg { gt, eq, ls, ov } = decoded {
+                self.gt_flag = gt.apply(self.gt_flag);
+                self.eq_flag = eq.apply(self.eq_flag);
+                self.ls_flag = ls.apply(self.ls_flag);
+                self.ov_flag = ov.apply(self.ov_flag);
+            }
 
-            //println!("{:#06x}", a_bus);
-            //println!("{:#06x}", b_bus);
+            if self.trace {
+                println!("Flags: {} {} {} {}", self.gt_flag, self.eq_flag, self.ls_flag, self.ov_flag);
+            }
+        }
 
+        if jump {
+            pc = match decoded {
+                Instruction::Jr { offset, .. } if ctl.jump_rel => pc.wrapping_add(offset as u32),
+                _ => self.wide_register(addr_reg),
+            };
+        }
 
-            out_reg = (instr & 0b0000_0000_0011_1000) >> 3;
-            out_write = true;
+        if mem_write {
+            let addr = self.wide_register(addr_reg);
+            ram_bus = self.register(ctl.b_sel as u16);
 
-            alu_op =  ((instr & 0b1111_0000_0000_0000) >> 12) | 0b10000;
+            self.write_ram(addr, ram_bus)?;
+        }
 
-            println!("{:#07b}", alu_op);
+        if mem_read {
+            let addr = self.wide_register(addr_reg);
+            ram_bus = self.read_ram(addr, AccessKind::Read)?;
 
+            self.set_register(out_reg, ram_bus);
+        }
 
-        } else { // Unknown
-            panic!("Do not know what to do with {:#06x}", instr);
+        if out_write {
+            a_bus = self.register(ctl.a_sel as u16);
+            b_bus = match decoded {
+                Instruction::Im { imm, .. } if ctl.b_imm => imm as u16,
+                _ if ctl.b_half => self.register(ctl.b_sel as u16 | 0b1000),
+                _ => self.register(ctl.b_sel as u16),
+            };
         }
 
 
+        if jump {
+            self.cycles += self.timing.branch_taken;
+        }
 
-        println!("Jump: {}, PC: {:#010x}", jump, pc);
+        if self.trace {
+            println!("Jump: {}, PC: {:#010x}", jump, pc);
+        }
 
 
 //-------------------------------------- ALU ------------------------------------------------------
@@ -435,54 +600,193 @@ pub fn run(mut ram: Vec<u16>) {
                 },
                 0b10101 => {
                     let r = a_bus.overflowing_add(b_bus);
-                    ov_flag = r.1;
+                    self.ov_flag = r.1;
                     r.0
                 },
                 0b10110 => {
                     let r = a_bus.overflowing_sub(b_bus);
-                    ov_flag = r.1;
+                    self.ov_flag = r.1;
                     r.0
                 },
                 0b10111 => {
                     let r = a_bus.overflowing_mul(b_bus);
-                    ov_flag = r.1;
+                    self.ov_flag = r.1;
                     r.0
                 },
-                _ => {
-                    panic!("ALU Op {:#06b} not implemented yet", alu_op);
-                }
+                _ => unreachable!("the control unit faults on ALU op {:#07b}", alu_op),
             }
         }
 
-        //println!("Busses: {}, {}, {}", a_bus, b_bus, out_bus);
+        if self.trace {
+            println!("Busses: A {:#06x}, B {:#06x}, Out {:#06x}, RAM {:#06x} (read {}, write {})",
+                a_bus, b_bus, out_bus, ram_bus, mem_read, mem_write);
+        }
 
 
 //-------------------------------------- REGISTER ACCESS ------------------------------------------
 
         if out_write {
-            write_register(out_reg, out_bus, &mut registers);
+            self.set_register(out_reg, out_bus);
         }
 
-        println!("Registers: {:#?}", registers);
+        if self.trace {
+            println!("Registers: {:#?}", self.registers);
+        }
 
 
 
 //-------------------------------------- INCREMENT PC ---------------------------------------------
 
+        self.signals = Signals {
+            pc: self.pc(),
+            instr,
+            a_bus,
+            b_bus,
+            out_bus,
+            out_reg,
+            out_write,
+            ram_bus,
+            alu_op,
+            mem_read,
+            mem_write,
+            jump,
+        };
+
         if !jump {
             pc += 1;
         }
 
-        write_wide_register(0b1000, pc, &mut registers);
+        self.set_pc(pc);
+
+        Ok(())
+    }
+}
+
+
+
 
 
 
+/// Runs until the CPU halts or `max` instructions have gone by. Nothing is printed along the way
+/// unless the CPU is tracing. Gives the number of instructions run.
+pub fn run(cpu: &mut Cpu, max: Option<u64>) -> Result<u64, Fault> {
 
+    let mut count = 0;
 
-        // time measurement.
-        let nanos = now.elapsed().as_nanos();
-        let MHz = (1f64 / (nanos as f64)) * 1000.0;
-        println!("{:.04} MHz", MHz);
+    while !cpu.halted && Some(count) != max {
+        cpu.step()?;
+        count += 1;
     }
 
+    Ok(count)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use isa::FlagOp;
+
+    fn jump_from(word: u16, gt: bool, eq: bool, ls: bool) -> u32 {
+        let mut cpu = Cpu::new(vec![word]);
+        (cpu.gt_flag, cpu.eq_flag, cpu.ls_flag) = (gt, eq, ls);
+        write_wide_register(0b1010, 0x40, &mut cpu.registers); // J
+        cpu.step().unwrap();
+        cpu.pc()
+    }
+
+    #[test]
+    fn conditions_are_a_mask_of_the_flags() {
+        assert_eq!(jump_from(0x031D, false, false, false), 0x40); // JA J
+        assert_eq!(jump_from(0x0301, true, true, true), 1);       // JA NEVER, J
+        assert_eq!(jump_from(0x0311, true, false, false), 0x40);  // JA GT, J
+        assert_eq!(jump_from(0x0311, false, true, true), 1);
+
+        // GT|EQ jumps on either
+        assert_eq!(jump_from(0x0319, false, true, false), 0x40);
+        assert_eq!(jump_from(0x0319, true, false, false), 0x40);
+        assert_eq!(jump_from(0x0319, false, false, true), 1);
+    }
+
+    #[test]
+    fn jr_condition_is_bits_9_to_7() {
+        assert_eq!(jump_from(0x0603, true, false, false), 3);  // JR GT, $+3
+        assert_eq!(jump_from(0x0603, false, true, true), 1);
+        assert_eq!(jump_from(0x0503, false, true, false), 3);  // JR EQ, $+3
+        assert_eq!(jump_from(0x0403, true, true, true), 1);    // JR NEVER, $+3
+    }
+
+
+    #[test]
+    fn puth_and_geth_move_halves_of_address_registers() {
+        let puth = |half, src| isa::encode(&Instruction::PutH { half, src }).unwrap();
+        let geth = |half, dst| isa::encode(&Instruction::GetH { dst, half }).unwrap();
+
+        let mut cpu = Cpu::new(vec![puth(11, 2), puth(10, 3), geth(11, 4), geth(8, 5), geth(9, 6)]);
+        write_register(2, 0x1234, &mut cpu.registers); // C
+        write_register(3, 0x5678, &mut cpu.registers); // D
+        for _ in 0..5 {
+            cpu.step().unwrap();
+        }
+
+        assert_eq!(cpu.wide_register(0b1010), 0x1234_5678); // J
+        assert_eq!(cpu.register(4), 0x1234);                 // E = J1
+        assert_eq!(cpu.register(5), 3);                      // F = P0, the address of that GETH
+        assert_eq!(cpu.register(6), 0);                      // G = P1
+        assert_eq!(cpu.pc(), 5);
+    }
+
+    #[test]
+    fn writes_past_the_end_of_memory_fault() {
+        let store = isa::encode(&Instruction::Store { src: 2, addr: 12 }).unwrap(); // STORE C, [K]
+
+        let mut cpu = Cpu::new(vec![store]);
+        write_wide_register(0b1100, 0xE000_0000, &mut cpu.registers);
+        assert!(matches!(cpu.step(), Err(Fault::Memory { addr: 0xE000_0000, limit: DEFAULT_MEMORY })));
+        assert_eq!(cpu.ram.len(), 1);
+
+        // anything below the limit grows RAM as it used to
+        let mut cpu = Cpu::new(vec![store]);
+        cpu.memory_limit = 0x100;
+        write_wide_register(0b1100, 0xFF, &mut cpu.registers);
+        write_register(2, 7, &mut cpu.registers);
+        cpu.step().unwrap();
+        assert_eq!((cpu.ram.len(), cpu.peek(0xFF)), (0x100, 7));
+    }
+
+    #[test]
+    fn undo_puts_everything_back() {
+        let program = [
+            Instruction::Im { op: 4, dst: 2, a: 0, imm: 9 },  // MOV #9, C
+            Instruction::Store { src: 2, addr: 12 },          // STORE C, [K]
+            Instruction::SetFlg { gt: FlagOp::Set, eq: FlagOp::Keep, ls: FlagOp::Keep, ov: FlagOp::Keep },
+        ];
+        let mut cpu = Cpu::new(program.iter().map(|i| isa::encode(i).unwrap()).collect());
+        write_wide_register(0b1100, 0x10, &mut cpu.registers);
+        cpu.history = Some(History::new(10));
+        let (registers, ram, status) = (cpu.raw_registers(), cpu.ram.clone(), cpu.status());
+
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.mix.iter().sum::<u64>(), 3);
+        assert!(cpu.gt_flag);
+
+        assert_eq!(cpu.undo(), Some(2));
+        assert_eq!(cpu.undo(), Some(1));
+        assert_eq!(cpu.undo(), Some(0));
+        assert_eq!(cpu.undo(), None);
+
+        assert_eq!(cpu.raw_registers(), registers);
+        assert_eq!(cpu.status(), status);
+        assert_eq!((cpu.cycles, cpu.mix), (0, [0; InstrClass::COUNT]));
+        assert_eq!(cpu.peek(0x10), 0);
+        assert_eq!(&cpu.ram[..3], &ram[..]);
+    }
+
+    #[test]
+    fn puth_cant_write_the_pc() {
+        let mut cpu = Cpu::new(vec![0x0382]); // what would be PUTH C, P0
+        assert!(matches!(cpu.step(), Err(Fault::UnknownInstruction(0x0382))));
+    }
 }
@@ -0,0 +1,223 @@
+use isa::expr::{self, BinOp, UnOp};
+
+use crate::emulator::Cpu;
+
+/*
+Expressions for breakpoint conditions and the debugger's print command, e.g.
+
+    A == 0x10 && gt
+    [J] != 0 || L1 > 0x8000
+
+Registers are A-H, the halves P0/P1, J0/J1, K0/K1, L0/L1 (0 is the bottom half), and the full
+address registers P (or PC), J, K and L. Flags are gt, eq, ls and ov. [expr] reads memory.
+Comparisons give 1 or 0, and anything that isn't 0 counts as true.
+
+The syntax is read by isa/src/expr.rs, the same as the assembler's, so characters like 'A' and
+lo() and hi() work here too. Labels and $ don't, as they only mean something while assembling.
+*/
+
+
+/// What a name in an expression is, worked out as it's read so a typo is caught before the
+/// condition is ever checked.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Reg(u16),     // 16 bit register by instruction number
+    WideReg(u16), // 32 bit address register by instruction number
+    Flag(Flag),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flag {
+    Gt,
+    Eq,
+    Ls,
+    Ov,
+}
+
+pub type Expr = expr::Expr<Operand>;
+
+
+fn operand(name: &str) -> Option<Operand> {
+    let upper = name.to_ascii_uppercase();
+    let half = |wide: u16, top: &str| match top {
+        "0" => Some(Operand::Reg(wide)),
+        "1" => Some(Operand::Reg(wide + 1)),
+        _ => None,
+    };
+
+    match upper.as_str() {
+        "GT" => Some(Operand::Flag(Flag::Gt)),
+        "EQ" => Some(Operand::Flag(Flag::Eq)),
+        "LS" => Some(Operand::Flag(Flag::Ls)),
+        "OV" => Some(Operand::Flag(Flag::Ov)),
+        "P" | "PC" => Some(Operand::WideReg(0b1000)),
+        "J" => Some(Operand::WideReg(0b1010)),
+        "K" => Some(Operand::WideReg(0b1100)),
+        "L" => Some(Operand::WideReg(0b1110)),
+        r if r.len() == 1 && ('A'..='H').contains(&r.chars().next().unwrap()) => {
+            Some(Operand::Reg(r.chars().next().unwrap() as u16 - 'A' as u16))
+        },
+        r => {
+            let (wide, top) = if let Some(top) = r.strip_prefix("PC") {
+                (0b1000, top)
+            } else {
+                let wide = match r.chars().next()? {
+                    'P' => 0b1000,
+                    'J' => 0b1010,
+                    'K' => 0b1100,
+                    'L' => 0b1110,
+                    _ => return None,
+                };
+                (wide, &r[1..])
+            };
+            half(wide, top)
+        },
+    }
+}
+
+
+/// Reads an expression, turning away what only the assembler has.
+pub fn parse(text: &str) -> Result<Expr, String> {
+    let e = expr::parse(text, |name| operand(name)
+        .ok_or_else(|| format!("Unknown register or flag \"{}\"", name)))?;
+    e.check(&mut |e| match e {
+        Expr::Label(..) => Err(String::from("Labels can't be used in expressions, only where a command \
+            takes an address")),
+        Expr::Here => Err(String::from("There is no $ here, PC is where the program is")),
+        _ => Ok(()),
+    })?;
+    Ok(e)
+}
+
+
+pub trait Eval {
+    fn eval(&self, cpu: &Cpu) -> i64;
+}
+
+impl Eval for Expr {
+
+    fn eval(&self, cpu: &Cpu) -> i64 {
+        match self {
+            Self::Num(n) => *n,
+            Self::Name(Operand::Reg(num)) => cpu.register(*num) as i64,
+            Self::Name(Operand::WideReg(num)) => cpu.wide_register(*num) as i64,
+            Self::Name(Operand::Flag(flag)) => match flag {
+                Flag::Gt => cpu.gt_flag as i64,
+                Flag::Eq => cpu.eq_flag as i64,
+                Flag::Ls => cpu.ls_flag as i64,
+                Flag::Ov => cpu.ov_flag as i64,
+            },
+            Self::Mem(addr) => cpu.peek(addr.eval(cpu) as u32) as i64,
+            Self::Unary(op, e) => {
+                let v = e.eval(cpu);
+                match op {
+                    UnOp::Not => (v == 0) as i64,
+                    UnOp::Neg => v.wrapping_neg(),
+                    UnOp::BitNot => !v,
+                    UnOp::Lo => v & 0xffff,
+                    UnOp::Hi => (v >> 16) & 0xffff,
+                }
+            },
+            Self::Binary(op, lhs, rhs) => {
+                let l = lhs.eval(cpu);
+                // short circuit so conditions like `J != 0 && [J] == 5` behave as written
+                match op {
+                    BinOp::Or if l != 0 => return 1,
+                    BinOp::And if l == 0 => return 0,
+                    _ => {},
+                }
+                let r = rhs.eval(cpu);
+                match op {
+                    BinOp::Or | BinOp::And => (r != 0) as i64,
+                    BinOp::BitOr => l | r,
+                    BinOp::BitXor => l ^ r,
+                    BinOp::BitAnd => l & r,
+                    BinOp::Eq => (l == r) as i64,
+                    BinOp::Ne => (l != r) as i64,
+                    BinOp::Lt => (l < r) as i64,
+                    BinOp::Le => (l <= r) as i64,
+                    BinOp::Gt => (l > r) as i64,
+                    BinOp::Ge => (l >= r) as i64,
+                    BinOp::Shl => l.wrapping_shl(r as u32),
+                    BinOp::Shr => l.wrapping_shr(r as u32),
+                    BinOp::Add => l.wrapping_add(r),
+                    BinOp::Sub => l.wrapping_sub(r),
+                    BinOp::Mul => l.wrapping_mul(r),
+                    BinOp::Div => l.checked_div(r).unwrap_or(0),
+                    BinOp::Rem => l.checked_rem(r).unwrap_or(0),
+                }
+            },
+            Self::Label(..) | Self::Here => unreachable!("parse turns away labels and $"),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(text: &str, cpu: &Cpu) -> i64 {
+        parse(text).unwrap_or_else(|e| panic!("{}: {}", text, e)).eval(cpu)
+    }
+
+    #[test]
+    fn precedence_follows_c() {
+        let cpu = Cpu::new(vec![0]);
+        assert_eq!(eval("1 + 2 * 3", &cpu), 7);
+        assert_eq!(eval("(1 + 2) * 3", &cpu), 9);
+        assert_eq!(eval("1 << 2 + 1", &cpu), 8);
+        assert_eq!(eval("6 & 3 == 3", &cpu), 0); // == binds tighter than &, as in C
+        assert_eq!(eval("1 | 2 ^ 3 & 1", &cpu), 3);
+        assert_eq!(eval("1 < 2 == 1", &cpu), 1);
+        assert_eq!(eval("-2 * -3", &cpu), 6);
+        assert_eq!(eval("!0 + ~0", &cpu), 0);
+        assert_eq!(eval("7 / 2 % 2", &cpu), 1);
+        assert_eq!(eval("0x10 + 0b11 + 0o7 + 1_000", &cpu), 1026);
+    }
+
+    #[test]
+    fn logic_short_circuits_and_gives_1_or_0() {
+        let mut cpu = Cpu::new(vec![5, 0, 0, 0]);
+        assert_eq!(eval("2 && 3", &cpu), 1);
+        assert_eq!(eval("0 || 7", &cpu), 1);
+        assert_eq!(eval("0 && 1 / 0", &cpu), 0);
+        assert_eq!(eval("3 || 1 / 0", &cpu), 1);
+
+        // J is 0, so [J] isn't looked at, though it'd be 5
+        assert_eq!(eval("J != 0 && [J] == 5", &cpu), 0);
+        cpu.set_register(0b1010, 3); // J0
+        assert_eq!(eval("J != 0 && [J] == 0", &cpu), 1);
+        assert_eq!(eval("J == 0 || [J] == 5", &cpu), 0);
+    }
+
+    #[test]
+    fn registers_flags_and_memory() {
+        let mut cpu = Cpu::new(vec![0, 0x1234]);
+        cpu.set_register(1, 0xBEEF);       // B
+        cpu.set_register(0b1011, 0x0001); // J1
+        cpu.set_register(0b1010, 0x0002); // J0
+        cpu.gt_flag = true;
+
+        assert_eq!(eval("b", &cpu), 0xBEEF);
+        assert_eq!(eval("J", &cpu), 0x0001_0002);
+        assert_eq!(eval("J1 == 1 && J0 == 2", &cpu), 1);
+        assert_eq!(eval("gt && !eq", &cpu), 1);
+        assert_eq!(eval("[PC + 1]", &cpu), 0x1234);
+        assert_eq!(eval("hi(J) + lo(J)", &cpu), 3);
+        assert_eq!(eval("'A' + 1", &cpu), 66);
+    }
+
+    #[test]
+    fn bad_expressions() {
+        assert_eq!(parse("A = 1").unwrap_err(), "Use == to compare");
+        assert_eq!(parse("Q1 + 1").unwrap_err(), "Unknown register or flag \"Q1\"");
+        assert_eq!(parse("0x").unwrap_err(), "Invalid number \"0x\"");
+        assert!(parse("(1 + 2").is_err());
+        assert!(parse("1 2").is_err());
+        assert!(parse("[J").is_err());
+        assert_eq!(parse("[@table]").unwrap_err(), "Labels can't be used in expressions, only where a command \
+            takes an address");
+        assert_eq!(parse("$ + 1").unwrap_err(), "There is no $ here, PC is where the program is");
+    }
+}
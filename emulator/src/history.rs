@@ -0,0 +1,79 @@
+use std::collections::VecDeque;
+
+/*
+The undo log. Every time the CPU changes a piece of state it first tells the log what the old
+value was, and at the end of the instruction those changes become one StepRecord. Undoing a step
+just puts the old values back in reverse order. Only the last `limit` steps are kept.
+*/
+
+
+#[derive(Clone, Copy, Debug)]
+pub enum Delta {
+    Register { index: usize, old: u32 }, // position in the register file, old raw value
+    Memory { addr: u32, old: u16, new: u16 },
+    Status { old: u8 }, // flags and the halted bit, see Cpu::status
+    Mix { class: usize }, // the instruction counted in Cpu::mix
+    WatchHit { id: usize }, // a hit counted on the watchpoint with this id
+}
+
+
+pub struct StepRecord {
+    pub pc: u32, // where the instruction was fetched from
+    pub cycles: u64, // cycle count before it ran
+    pub deltas: Vec<Delta>,
+}
+
+
+pub struct History {
+    pub records: VecDeque<StepRecord>,
+    pub limit: usize,
+    current: Vec<Delta>,
+}
+
+
+impl History {
+
+    pub fn new(limit: usize) -> History {
+        History {
+            records: VecDeque::with_capacity(limit.min(4096)),
+            limit,
+            current: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, delta: Delta) {
+        self.current.push(delta);
+    }
+
+    /// Closes off the instruction that started at `pc`, forgetting the oldest one if we're full.
+    pub fn commit(&mut self, pc: u32, cycles: u64) {
+        if self.limit == 0 {
+            self.current.clear();
+        } else if self.records.len() == self.limit {
+            // reuse the oldest record's allocation rather than making a new one every step
+            let mut oldest = self.records.pop_front().unwrap();
+            oldest.pc = pc;
+            oldest.cycles = cycles;
+            oldest.deltas.clear();
+            oldest.deltas.append(&mut self.current);
+            self.records.push_back(oldest);
+        } else {
+            self.records.push_back(StepRecord { pc, cycles, deltas: std::mem::take(&mut self.current) });
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<StepRecord> {
+        self.records.pop_back()
+    }
+
+    /// Finds the most recent recorded write to `addr`. Gives how many steps ago it was (1 is the
+    /// instruction that just ran), the PC of the instruction, and the old and new values.
+    pub fn last_write(&self, addr: u32) -> Option<(usize, u32, u16, u16)> {
+        self.records.iter().rev().enumerate().find_map(|(ago, rec)| {
+            rec.deltas.iter().rev().find_map(|d| match d {
+                Delta::Memory { addr: a, old, new } if *a == addr => Some((ago + 1, rec.pc, *old, *new)),
+                _ => None,
+            })
+        })
+    }
+}
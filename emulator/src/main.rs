@@ -1,6 +1,17 @@
 #[allow(non_snake_case)]
-
 pub mod emulator;
+pub mod bench;
+pub mod control;
+pub mod coverage;
+pub mod blocks;
+pub mod debugger;
+pub mod expr;
+pub mod history;
+pub mod pipeline;
+pub mod profile;
+pub mod snapshot;
+pub mod timing;
+pub mod vcd;
 
 use std::env::args;
 use std::io::*;
@@ -12,7 +23,73 @@ fn pr(statement: &str) {
     println!("AEMU>>> {}", statement);
 }
 
-fn start_emulator(ram_path: &str){
+
+const USAGE: &str = "Usage: aemu [options] [program.abin]
+       aemu --control-rom PREFIX
+  -d, --debug         step through the program in the debugger
+  --engine E          interp (the reference interpreter, default), blocks (translated basic
+                      blocks) or diff (both in lockstep, checking they agree)
+  --trace             print every stage of every instruction (interpreter only)
+  --bench             time the run and print the results as JSON
+  --pipeline          simulate a pipelined CPU running the program and print its statistics
+  --no-forwarding     leave out the ALU forwarding path from the pipeline
+  --vcd FILE          dump the buses, control lines, registers and flags as a waveform
+  --profile FILE      print where the time went, by label, address and class, and write the
+                      call stacks to FILE in the folded format for flame graphs
+  --coverage FILE     print which source lines and branches ran, and write them to FILE in
+                      lcov format
+  --lines FILE        the line table (.aline) for --coverage (default: program.aline)
+  --max N             stop after N instructions
+  --memory WORDS      how big memory can grow when the program writes past its end (default
+                      1048576, or the size of the program if that's bigger)
+  --timing FILE       cycle costs and wait states to use instead of the defaults
+  --symbols FILE      label addresses in the debugger and profiler from a symbol file (.asym)
+  --history N         keep N steps of undo log in the debugger (default 100000, 0 turns it off)
+  --restore FILE      start from a snapshot (.astate) instead of a program
+  --save FILE         write a snapshot when the emulator stops
+  --control-rom PREFIX
+                      write the control unit's ROM images to PREFIX0.bin to PREFIX3.bin, and
+                      the same in Intel HEX, instead of running anything";
+
+
+#[derive(PartialEq)]
+enum Engine {
+    Interp,
+    Blocks,
+    Diff,
+}
+
+
+struct Options {
+    path: Option<String>,
+    restore: Option<String>,
+    save: Option<String>,
+    debug: bool,
+    engine: Engine,
+    trace: bool,
+    bench: bool,
+    pipeline: bool,
+    forwarding: bool,
+    max: Option<u64>,
+    memory: Option<u32>,
+    vcd: Option<String>,
+    profile: Option<String>, // where the folded stacks go
+    coverage: Option<String>, // where the lcov tracefile goes
+    lines: Option<String>,
+    timing: Option<String>,
+    symbols: Option<String>,
+    history: usize, // steps of undo log the debugger keeps
+    control_rom: Option<String>,
+}
+
+
+fn usage_error(reason: &str) -> ! {
+    pr(&format!("{}\n{}", reason, USAGE));
+    std::process::exit(1);
+}
+
+
+fn load_program(ram_path: &str) -> Option<Vec<u16>> {
 
     let mut buff = Vec::new();
 
@@ -20,44 +97,293 @@ fn start_emulator(ram_path: &str){
         Ok(file) => file,
         Err(_) => {
             pr("Failed to open file");
-            return;
+            return None;
         },
     };
 
-    match f.read_to_end(&mut buff) {
-        Err(_) => {
-            pr("Failed to read file");
-            return;
-        },
-        Ok(_) => {},
-    };
+    if f.read_to_end(&mut buff).is_err() {
+        pr("Failed to read file");
+        return None;
+    }
 
     let ram: Vec<u16> = zip(
-        buff.iter().skip(0).step_by(2),
+        buff.iter().step_by(2),
         buff.iter().skip(1).step_by(2),
     ).map(|(x, y)| ((*x as u16) << 8) + (*y as u16)).collect();
 
     //println!("{:?}", ram);
 
-    pr("Starting AustinOS Emulator...");
+    Some(ram)
+}
+
 
-    emulator::run(ram);
+fn load_symbols(opts: &Options) -> Option<isa::symbols::SymbolTable> {
+    opts.symbols.as_ref().map(|path| isa::symbols::SymbolTable::load(path).unwrap_or_else(|e| {
+        pr(&e);
+        std::process::exit(1);
+    }))
 }
 
 
-fn main() {
+/// The line table for coverage: the one asked for, or the one next to the program.
+fn load_lines(opts: &Options) -> isa::lines::LineTable {
+    let program = opts.path.as_ref().or(opts.restore.as_ref()).unwrap();
+    let path = opts.lines.clone()
+        .unwrap_or_else(|| std::path::Path::new(program).with_extension("aline").to_string_lossy().into_owned());
+    isa::lines::LineTable::load(&path).unwrap_or_else(|e| {
+        pr(&format!("{} (--coverage needs the line table the assembler writes, see --lines)", e));
+        std::process::exit(1);
+    })
+}
+
 
-    let path = args().nth(1);
+fn start_emulator(opts: &Options){
 
-    match path {
-        Some(p) => {
-            start_emulator(&p);
+    let mut cpu = match (&opts.restore, &opts.path) {
+        (Some(snap), _) => match snapshot::restore(snap, opts.memory.unwrap_or(emulator::DEFAULT_MEMORY)) {
+            Ok(cpu) => {
+                if !opts.bench {
+                    pr(&format!("Restored {} at PC {:#010x}, cycle {}", snap, cpu.pc(), cpu.cycles));
+                }
+                cpu
+            },
+            Err(e) => {
+                pr(&e);
+                std::process::exit(1);
+            },
         },
-        None => {
-            pr("Please provide a binary file (.abin) to run.");
+        (None, Some(path)) => match load_program(path) {
+            Some(ram) => emulator::Cpu::new(ram),
+            None => return,
+        },
+        (None, None) => unreachable!(),
+    };
+
+    if let Some(words) = opts.memory {
+        cpu.memory_limit = words.max(cpu.ram.len() as u32);
+    }
+
+    if let Some(path) = &opts.timing {
+        cpu.timing = timing::TimingTable::load(path).unwrap_or_else(|e| {
+            pr(&e);
             std::process::exit(1);
+        });
+    }
+
+    if opts.bench {
+        let result = bench::run(&mut cpu, opts.engine == Engine::Blocks, opts.max);
+        let program = opts.restore.as_ref().or(opts.path.as_ref()).unwrap();
+        let engine = if opts.engine == Engine::Blocks { "blocks" } else { "interp" };
+        println!("{}", result.to_json(program, engine));
+    } else if opts.debug {
+        pr("Starting AustinOS Debugger...");
+        if opts.history > 0 {
+            cpu.history = Some(history::History::new(opts.history));
         }
+        let mut dbg = debugger::Debugger::new(cpu);
+        dbg.symbols = load_symbols(opts);
+        dbg.repl();
+        cpu = dbg.cpu;
+    } else {
+        pr("Starting AustinOS Emulator...");
+        cpu.trace = opts.trace;
+
+        let result = match opts.engine {
+            Engine::Interp if opts.pipeline => {
+                let (stats, result) = pipeline::run(&mut cpu, opts.forwarding, opts.max);
+                print!("{}", stats.report());
+                result
+            },
+            Engine::Interp if opts.vcd.is_some() => {
+                let path = opts.vcd.as_ref().unwrap();
+                let dumped = vcd::VcdWriter::create(path).and_then(|w| vcd::run(&mut cpu, w, opts.max));
+                dumped.unwrap_or_else(|e| {
+                    pr(&e);
+                    std::process::exit(1);
+                })
+            },
+            Engine::Interp if opts.profile.is_some() => {
+                let mut profile = profile::Profile::new(load_symbols(opts), cpu.pc());
+                let result = profile::run(&mut cpu, &mut profile, opts.max);
+                print!("{}", profile.report(&cpu.ram, 20));
+
+                let path = opts.profile.as_ref().unwrap();
+                match std::fs::write(path, profile.to_folded()) {
+                    Ok(()) => pr(&format!("Wrote the call stacks to {}", path)),
+                    Err(e) => pr(&format!("Could not write {}: {}", path, e)),
+                }
+                result
+            },
+            Engine::Interp if opts.coverage.is_some() => {
+                let lines = load_lines(opts);
+                let mut coverage = coverage::Coverage::new();
+                let result = coverage::run(&mut cpu, &mut coverage, opts.max);
+                print!("{}", coverage.report(&lines, &cpu.ram));
+
+                let path = opts.coverage.as_ref().unwrap();
+                match std::fs::write(path, coverage.to_lcov(&lines, &cpu.ram)) {
+                    Ok(()) => pr(&format!("Wrote the coverage to {}", path)),
+                    Err(e) => pr(&format!("Could not write {}: {}", path, e)),
+                }
+                result
+            },
+            Engine::Interp => emulator::run(&mut cpu, opts.max),
+            Engine::Blocks => blocks::BlockEngine::new().run(&mut cpu, opts.max),
+            Engine::Diff => {
+                // the differential run makes its own two machines, so there's no one to save
+                match blocks::differential(&cpu, opts.max) {
+                    Ok((count, None)) => pr(&format!("Engines agree after {} instructions", count)),
+                    Ok((count, Some(fault))) => pr(&format!(
+                        "Engines agree after {} instructions, and both faulted: {}", count, fault)),
+                    Err(e) => {
+                        pr(&format!("Engines disagree {}", e));
+                        std::process::exit(1);
+                    },
+                }
+                return;
+            },
+        };
+
+        match result {
+            Ok(count) => {
+                let how = if cpu.halted { "Halted" } else { "Stopped" };
+                pr(&format!("{} at {:#010x} after {} instructions", how, cpu.pc(), count));
+            },
+            Err(fault) => pr(&format!("Fault at {:#010x}: {}", cpu.pc(), fault)),
+        }
+    }
+
+    if let Some(path) = &opts.save {
+        match snapshot::save(&cpu, path) {
+            Ok(()) if opts.bench => {},
+            Ok(()) => pr(&format!("Saved snapshot to {}", path)),
+            Err(e) => pr(&e),
+        }
+    }
+}
+
+
+fn main() {
+
+    let mut opts = Options {
+        path: None,
+        restore: None,
+        save: None,
+        debug: false,
+        engine: Engine::Interp,
+        trace: false,
+        bench: false,
+        pipeline: false,
+        forwarding: true,
+        max: None,
+        memory: None,
+        vcd: None,
+        profile: None,
+        coverage: None,
+        lines: None,
+        timing: None,
+        symbols: None,
+        history: 100_000,
+        control_rom: None,
     };
 
+    let mut args = args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage_error(&format!("{} needs a value", arg)));
+
+        match arg.as_str() {
+            "-d" | "--debug" => opts.debug = true,
+            "--engine" => {
+                opts.engine = match value().as_str() {
+                    "interp" => Engine::Interp,
+                    "blocks" => Engine::Blocks,
+                    "diff" => Engine::Diff,
+                    other => usage_error(&format!("Unknown engine {}", other)),
+                };
+            },
+            "--trace" => opts.trace = true,
+            "--bench" => opts.bench = true,
+            "--pipeline" => opts.pipeline = true,
+            "--no-forwarding" => opts.forwarding = false,
+            "--max" => {
+                opts.max = Some(value().parse()
+                    .unwrap_or_else(|_| usage_error("--max needs a number of instructions")));
+            },
+            "--memory" => {
+                opts.memory = Some(isa::parse_number(&value()).ok().and_then(|n| u32::try_from(n).ok())
+                    .unwrap_or_else(|| usage_error("--memory needs a number of words")));
+            },
+            "--history" => {
+                opts.history = value().parse()
+                    .unwrap_or_else(|_| usage_error("--history needs a number of steps to keep"));
+            },
+            "--vcd" => opts.vcd = Some(value()),
+            "--profile" => opts.profile = Some(value()),
+            "--coverage" => opts.coverage = Some(value()),
+            "--lines" => opts.lines = Some(value()),
+            "--timing" => opts.timing = Some(value()),
+            "--symbols" => opts.symbols = Some(value()),
+            "--restore" => opts.restore = Some(value()),
+            "--save" => opts.save = Some(value()),
+            "--control-rom" => opts.control_rom = Some(value()),
+            flag if flag.starts_with('-') => usage_error(&format!("Unknown option {}", flag)),
+            _ => opts.path = Some(arg),
+        }
+    }
+
+    if opts.restore.is_some() && opts.path.is_some() {
+        usage_error("--restore starts from a snapshot, so it can't be given a program as well");
+    }
+    if opts.debug && opts.engine != Engine::Interp {
+        usage_error("--debug only works with the interpreter");
+    }
+    if opts.save.is_some() && opts.engine == Engine::Diff {
+        usage_error("--save can't be used with --engine diff, which runs two machines of its own");
+    }
+    if opts.trace && opts.engine != Engine::Interp {
+        usage_error("--trace only works with the interpreter");
+    }
+    if opts.bench && (opts.debug || opts.trace || opts.engine == Engine::Diff) {
+        usage_error("--bench can't be used with --debug, --trace or --engine diff");
+    }
+
+    if opts.pipeline && (opts.debug || opts.bench || opts.engine != Engine::Interp) {
+        usage_error("--pipeline only works with the interpreter, and not with --debug or --bench");
+    }
+    if opts.vcd.is_some() && (opts.debug || opts.bench || opts.pipeline || opts.engine != Engine::Interp) {
+        usage_error("--vcd only works with the interpreter, and not with --debug, --bench or --pipeline");
+    }
+    if opts.profile.is_some()
+        && (opts.debug || opts.bench || opts.pipeline || opts.vcd.is_some() || opts.engine != Engine::Interp) {
+        usage_error("--profile only works with the interpreter, and not with --debug, --bench, --pipeline or --vcd");
+    }
+    if opts.coverage.is_some() && (opts.debug || opts.bench || opts.pipeline || opts.vcd.is_some()
+        || opts.profile.is_some() || opts.engine != Engine::Interp) {
+        usage_error("--coverage only works with the interpreter, and not with --debug, --bench, --pipeline, \
+            --vcd or --profile");
+    }
+    if opts.lines.is_some() && opts.coverage.is_none() {
+        usage_error("--lines needs --coverage");
+    }
+    if !opts.forwarding && !opts.pipeline {
+        usage_error("--no-forwarding needs --pipeline");
+    }
+
+    if let Some(prefix) = &opts.control_rom {
+        match control::write_roms(prefix) {
+            Ok(files) => pr(&format!("Wrote {}", files.join(", "))),
+            Err(e) => {
+                pr(&e);
+                std::process::exit(1);
+            },
+        }
+        return;
+    }
+
+    if opts.path.is_none() && opts.restore.is_none() {
+        pr("Please provide a binary file (.abin) to run. Add --debug to step through it.");
+        std::process::exit(1);
+    }
 
+    start_emulator(&opts);
 }
@@ -0,0 +1,305 @@
+use isa::Instruction;
+use crate::emulator::{Cpu, Fault};
+
+/*
+PIPELINE SIMULATION
+
+The stages that Cpu::execute walks through one after another are modelled here as a four stage,
+in-order pipeline, so we can see what the hardware would gain from overlapping them:
+
+    IF  fetch the instruction
+    ID  decode it and read the register file
+    EX  the ALU, the flags, memory access, and resolving jumps
+    WB  write the result back to the register file
+
+The model is driven by the interpreter: every instruction it runs is fed in with the registers it
+reads and writes and whether it jumped, and the cycle each one enters each stage is worked out
+from the ones before it. Nothing here changes what the program does.
+
+Hazards:
+  - Data: an instruction waits in ID until the registers it reads are ready. The register file is
+    written in the first half of a cycle and read in the second, so a value can be read in the
+    same cycle it is written back. Both inputs to the ALU count as read, as the interlock can't
+    know which one the operation uses.
+  - With forwarding, the ALU output is fed straight back into the next EX, so only loads make the
+    next instruction wait: RAM is only wired to the register file's write port (load-use stall).
+  - Control: jumps are resolved in EX, and fetching carries on from the next address until then.
+    A taken JA or JR throws away the two instructions behind it.
+  - The flags are written at the end of EX and only read in EX, so they never stall.
+
+Self-modifying code is not modelled: a store is assumed not to hit anything already fetched.
+*/
+
+
+pub const STAGES: [&str; 4] = ["IF", "ID", "EX", "WB"];
+
+
+/// What happened in one stage over the whole run, in cycles.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StageStats {
+    pub busy: u64,    // working on an instruction that completes
+    pub stalled: u64, // holding an instruction that can't move on yet
+    pub flushed: u64, // holding an instruction that a taken jump threw away
+}
+
+
+#[derive(Debug, Default)]
+pub struct PipelineStats {
+    pub instructions: u64,
+    pub cycles: u64,
+    pub stages: [StageStats; 4],
+    pub data_stalls: u64,     // cycles instructions spent waiting in ID for a register
+    pub load_use_stalls: u64, // the part of those that waited on a LOAD
+    pub taken_jumps: u64,
+    pub jump_penalty: u64,    // cycles lost to fetching down the wrong path
+}
+
+
+/// The cycle an instruction entered each stage.
+#[derive(Clone, Copy, Default)]
+struct Timing {
+    decode: u64,
+    execute: u64,
+}
+
+
+pub struct Pipeline {
+    forwarding: bool,
+    last: Option<Timing>,
+    redirect: u64,      // earliest cycle the next fetch can happen, after a taken jump
+    ready: [u64; 16],   // cycle from which each register can be read in ID
+    from_load: [bool; 16],
+    pub stats: PipelineStats,
+}
+
+
+/// The registers an instruction reads and the one it writes, by register number. Address
+/// registers are read as both halves.
+fn operands(instr: &Instruction) -> (Vec<u8>, Option<u8>) {
+    match *instr {
+        Instruction::Tra { dst, a, b, .. } => (vec![a, b], Some(dst)),
+        Instruction::Im { dst, a, .. } => (vec![a], Some(dst)),
+        Instruction::Store { src, addr } => (vec![src, addr, addr + 1], None),
+        Instruction::Load { dst, addr } => (vec![addr, addr + 1], Some(dst)),
+        Instruction::Ja { addr, .. } => (vec![addr, addr + 1], None),
+        Instruction::PutH { half, src } => (vec![src], Some(half)),
+        Instruction::GetH { dst, half } => (vec![half], Some(dst)),
+        _ => (Vec::new(), None),
+    }
+}
+
+
+impl Pipeline {
+
+    pub fn new(forwarding: bool) -> Pipeline {
+        Pipeline {
+            forwarding,
+            last: None,
+            redirect: 0,
+            ready: [0; 16],
+            from_load: [false; 16],
+            stats: PipelineStats::default(),
+        }
+    }
+
+    /// Adds the next instruction the program ran.
+    pub fn issue(&mut self, instr: &Instruction, jumped: bool) {
+        let (reads, write) = operands(instr);
+
+        // an instruction can only move into a stage once the one ahead of it has moved on
+        let (fetch, decode) = match self.last {
+            Some(last) => {
+                let fetch = last.decode.max(self.redirect);
+                (fetch, (fetch + 1).max(last.execute))
+            },
+            None => (0, 1),
+        };
+        let in_order = match self.last {
+            Some(last) => (decode + 1).max(last.execute + 1),
+            None => decode + 1,
+        };
+
+        let mut execute = in_order;
+        let mut load_use = false;
+        for r in reads {
+            // the register file is read in the last cycle in ID
+            let ready = self.ready[r as usize] + 1;
+            if ready > execute {
+                execute = ready;
+                load_use = self.from_load[r as usize];
+            }
+        }
+        let writeback = execute + 1;
+
+        if let Some(dst) = write {
+            let is_load = matches!(instr, Instruction::Load { .. });
+            self.ready[dst as usize] = if self.forwarding && !is_load { execute } else { writeback };
+            self.from_load[dst as usize] = is_load;
+        }
+
+        let stats = &mut self.stats;
+        stats.instructions += 1;
+        stats.cycles = writeback + 1;
+        for stage in stats.stages.iter_mut() {
+            stage.busy += 1;
+        }
+        stats.stages[0].stalled += decode - fetch - 1;
+        stats.stages[1].stalled += execute - decode - 1;
+
+        let waited = execute - in_order;
+        stats.data_stalls += waited;
+        if load_use {
+            stats.load_use_stalls += waited;
+        }
+
+        if jumped {
+            // the first wrong instruction sits in IF until the jump leaves ID and is thrown away
+            // in ID, and the second is thrown away as soon as it is fetched
+            stats.taken_jumps += 1;
+            stats.jump_penalty += execute + 1 - decode;
+            stats.stages[0].flushed += execute - decode + 1;
+            stats.stages[1].flushed += 1;
+            self.redirect = execute + 1;
+        }
+
+        self.last = Some(Timing { decode, execute });
+    }
+}
+
+
+impl PipelineStats {
+
+    pub fn cpi(&self) -> f64 {
+        self.cycles as f64 / self.instructions.max(1) as f64
+    }
+
+    pub fn report(&self) -> String {
+        // the same one cycle per stage, but with each instruction going through all of them before
+        // the next one starts
+        let unpipelined = self.instructions * STAGES.len() as u64;
+
+        let mut out = format!("{} instructions in {} cycles, CPI {:.2}\n",
+            self.instructions, self.cycles, self.cpi());
+        out += &format!("Without pipelining: {} cycles, CPI {:.2}, so pipelining is {:.2}x faster\n",
+            unpipelined,
+            STAGES.len() as f64,
+            unpipelined as f64 / self.cycles.max(1) as f64);
+        out += &format!("Data hazards: {} stall cycles, {} of them load-use\n",
+            self.data_stalls, self.load_use_stalls);
+        out += &format!("Taken jumps: {}, costing {} cycles\n\n", self.taken_jumps, self.jump_penalty);
+
+        out += "stage      busy   stalled   flushed     empty  occupancy\n";
+        for (name, stage) in STAGES.iter().zip(self.stages) {
+            let empty = self.cycles - stage.busy - stage.stalled - stage.flushed;
+            out += &format!("{:<5} {:>9} {:>9} {:>9} {:>9}  {:>8.1}%\n",
+                name, stage.busy, stage.stalled, stage.flushed, empty,
+                100.0 * stage.busy as f64 / self.cycles.max(1) as f64);
+        }
+        out
+    }
+}
+
+
+/// Runs the program on the interpreter, feeding every instruction through the pipeline model.
+pub fn run(cpu: &mut Cpu, forwarding: bool, max: Option<u64>) -> (PipelineStats, Result<u64, Fault>) {
+    let mut pipeline = Pipeline::new(forwarding);
+    let mut count = 0;
+
+    while !cpu.halted && Some(count) != max {
+        let pc = cpu.pc();
+        let instr = isa::decode(cpu.peek(pc));
+        if let Err(fault) = cpu.step() {
+            return (pipeline.stats, Err(fault));
+        }
+
+        // a jump to the next address still flushes, so this can't be worked out from the new PC
+        pipeline.issue(&instr, cpu.signals.jump);
+        count += 1;
+    }
+
+    (pipeline.stats, Ok(count))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(program: &[Instruction], forwarding: bool) -> PipelineStats {
+        let ram = program.iter().map(|i| isa::encode(i).unwrap()).collect();
+        let (stats, result) = run(&mut Cpu::new(ram), forwarding, None);
+        assert_eq!(result.ok(), Some(program.len() as u64));
+        stats
+    }
+
+    fn jumps(program: &[Instruction]) -> u64 {
+        stats(program, true).taken_jumps
+    }
+
+    const ADD_A: Instruction = Instruction::Im { op: 5, dst: 0, a: 0, imm: 1 };    // ADD A, #1, A
+    const B_FROM_A: Instruction = Instruction::Im { op: 5, dst: 1, a: 0, imm: 1 }; // ADD A, #1, B
+    const C_FROM_C: Instruction = Instruction::Im { op: 5, dst: 2, a: 2, imm: 1 }; // ADD C, #1, C
+
+    #[test]
+    fn taken_jumps_come_from_the_jump_signal() {
+        assert_eq!(jumps(&[Instruction::Jr { cond: 0b111, offset: 1 }, Instruction::Nop]), 1);
+        assert_eq!(jumps(&[Instruction::Jr { cond: 0b000, offset: 1 }, Instruction::Nop]), 0);
+        assert_eq!(jumps(&[
+            Instruction::Im { op: 4, dst: 0, a: 0, imm: 3 }, // MOV A, #3
+            Instruction::PutH { half: 10, src: 0 },          // PUTH J0, A
+            Instruction::Ja { cond: 0b111, addr: 10 },       // JA J, to the next address
+            Instruction::Nop,
+        ]), 1);
+    }
+
+    #[test]
+    fn read_after_write() {
+        // the ALU result is forwarded, so only the register file path has to wait
+        let program = [ADD_A, B_FROM_A, Instruction::Nop];
+        assert_eq!(stats(&program, true).data_stalls, 0);
+        let slow = stats(&program, false);
+        assert_eq!((slow.data_stalls, slow.load_use_stalls), (1, 0));
+        assert_eq!(slow.stages[1].stalled, 1); // B_FROM_A waits in ID
+
+        // one instruction in between is enough either way
+        assert_eq!(stats(&[ADD_A, C_FROM_C, B_FROM_A, Instruction::Nop], false).data_stalls, 0);
+    }
+
+    #[test]
+    fn load_use() {
+        let program = [Instruction::Load { dst: 0, addr: 12 }, B_FROM_A, Instruction::Nop];
+        for forwarding in [true, false] {
+            let stats = stats(&program, forwarding);
+            assert_eq!((stats.data_stalls, stats.load_use_stalls), (1, 1));
+        }
+        let apart = [Instruction::Load { dst: 0, addr: 12 }, C_FROM_C, B_FROM_A, Instruction::Nop];
+        assert_eq!(stats(&apart, true).load_use_stalls, 0);
+    }
+
+    #[test]
+    fn straight_line_occupancy() {
+        // one instruction finishes every cycle once the pipeline is full
+        let stats = stats(&[ADD_A, C_FROM_C, ADD_A, C_FROM_C, Instruction::Nop], true);
+        assert_eq!((stats.instructions, stats.cycles), (5, 8));
+        for stage in stats.stages {
+            assert_eq!((stage.busy, stage.stalled, stage.flushed), (5, 0, 0));
+        }
+    }
+
+    #[test]
+    fn report() {
+        let stats = stats(&[Instruction::Jr { cond: 0b111, offset: 1 }, Instruction::Nop], true);
+        assert_eq!(stats.report(), "\
+2 instructions in 7 cycles, CPI 3.50
+Without pipelining: 8 cycles, CPI 4.00, so pipelining is 1.14x faster
+Data hazards: 0 stall cycles, 0 of them load-use
+Taken jumps: 1, costing 2 cycles
+
+stage      busy   stalled   flushed     empty  occupancy
+IF            2         0         2         3      28.6%
+ID            2         0         1         4      28.6%
+EX            2         0         0         5      28.6%
+WB            2         0         0         5      28.6%
+");
+    }
+}
@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+
+use isa::symbols::SymbolTable;
+use isa::{InstrClass, Instruction};
+
+use crate::emulator::{Cpu, Fault};
+
+/*
+GUEST PROFILER
+
+Counts how many times every address runs and how many cycles of the timing model it takes, then
+adds those up by label (the closest symbol at or below each address) and by class of instruction.
+
+There is no CALL or RET, so calls are inferred from how the OS makes them. A call is a taken JA
+that lands on a symbol, and it is expected to come back to the instruction after the JA. Any
+later JA that lands on the return address of a frame on the stack returns from that frame and
+everything called from it. JR is only used for branches inside a function, so it never counts.
+A JA onto a label that is really just a jump, like a tail call, looks like a call that never
+comes back, and its frame stays until something below it returns.
+The stacks are written in the folded format that flamegraph.pl and inferno read, weighted by
+cycles:
+
+    start;print;putc 1520
+*/
+
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Count {
+    pub instructions: u64,
+    pub cycles: u64,
+}
+
+impl Count {
+    fn add(&mut self, cycles: u64) {
+        self.instructions += 1;
+        self.cycles += cycles;
+    }
+}
+
+
+struct Frame {
+    name: String,
+    return_addr: u32,
+}
+
+
+pub struct Profile {
+    pub total: Count,
+    pub by_addr: HashMap<u32, Count>,
+    pub by_class: [Count; InstrClass::COUNT],
+    pub folded: HashMap<String, u64>, // cycles spent with exactly this stack
+    symbols: Option<SymbolTable>,
+    stack: Vec<Frame>,
+    stack_key: String,
+}
+
+
+/// How an address shows up in the report and the stacks: its label, or its address if it has none.
+fn name_of(symbols: Option<&SymbolTable>, addr: u32) -> String {
+    match symbols.and_then(|s| s.containing(addr)) {
+        Some((_, name)) => String::from(name),
+        None => format!("{:#010x}", addr),
+    }
+}
+
+
+impl Profile {
+
+    pub fn new(symbols: Option<SymbolTable>, entry: u32) -> Profile {
+        let root = Frame { name: name_of(symbols.as_ref(), entry), return_addr: u32::MAX };
+        let mut profile = Profile {
+            total: Count::default(),
+            by_addr: HashMap::new(),
+            by_class: [Count::default(); InstrClass::COUNT],
+            folded: HashMap::new(),
+            symbols,
+            stack: vec![root],
+            stack_key: String::new(),
+        };
+        profile.update_key();
+        profile
+    }
+
+    fn update_key(&mut self) {
+        self.stack_key = self.stack.iter().map(|f| f.name.as_str()).collect::<Vec<_>>().join(";");
+    }
+
+    /// Records one instruction that ran at `pc`, took `cycles`, and left the PC at `next`.
+    pub fn record(&mut self, pc: u32, instr: &Instruction, cycles: u64, next: u32) {
+        self.total.add(cycles);
+        self.by_addr.entry(pc).or_default().add(cycles);
+        self.by_class[instr.class() as usize].add(cycles);
+
+        match self.folded.get_mut(&self.stack_key) {
+            Some(c) => *c += cycles,
+            None => { self.folded.insert(self.stack_key.clone(), cycles); },
+        }
+
+        if !matches!(instr, Instruction::Ja { .. }) || next == pc.wrapping_add(1) {
+            return;
+        }
+        // the root frame never returns, so it is left out of the search
+        if let Some(depth) = self.stack.iter().skip(1).rposition(|f| f.return_addr == next) {
+            self.stack.truncate(depth + 1);
+            self.update_key();
+        } else if let Some(name) = self.symbols.as_ref().and_then(|s| s.name_at(next)) {
+            self.stack.push(Frame { name: String::from(name), return_addr: pc.wrapping_add(1) });
+            self.update_key();
+        }
+    }
+
+
+    fn percent(&self, cycles: u64) -> f64 {
+        100.0 * cycles as f64 / self.total.cycles.max(1) as f64
+    }
+
+
+    /// The hot-spot report: labels, then the hottest addresses, then classes, most cycles first.
+    pub fn report(&self, ram: &[u16], top: usize) -> String {
+        let symbols = self.symbols.as_ref();
+        let mut out = format!("{} instructions in {} cycles\n", self.total.instructions, self.total.cycles);
+
+        let mut by_label: HashMap<String, Count> = HashMap::new();
+        for (addr, count) in &self.by_addr {
+            let label = by_label.entry(name_of(symbols, *addr)).or_default();
+            label.instructions += count.instructions;
+            label.cycles += count.cycles;
+        }
+        let mut labels: Vec<_> = by_label.into_iter().collect();
+        labels.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then_with(|| a.0.cmp(&b.0)));
+
+        out += "\n      cycles       %  instructions  label\n";
+        for (name, count) in labels.iter().take(top) {
+            out += &format!("{:>12} {:>6.2}% {:>13}  {}\n",
+                count.cycles, self.percent(count.cycles), count.instructions, name);
+        }
+
+        let mut addrs: Vec<_> = self.by_addr.iter().collect();
+        addrs.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then_with(|| a.0.cmp(b.0)));
+
+        out += "\n      cycles       %  instructions  address\n";
+        for (addr, count) in addrs.iter().take(top) {
+            let word = ram.get(**addr as usize).copied().unwrap_or(0);
+            let place = match symbols.and_then(|s| s.containing(**addr)) {
+                Some((start, name)) if start == **addr => format!(" <{}>", name),
+                Some((start, name)) => format!(" <{}+{}>", name, **addr - start),
+                None => String::new(),
+            };
+            out += &format!("{:>12} {:>6.2}% {:>13}  {:#010x}{}  {}\n",
+                count.cycles, self.percent(count.cycles), count.instructions, addr, place,
+                disassembler::disassemble_word(word, **addr, symbols));
+        }
+
+        let mut classes: Vec<_> = InstrClass::ALL.iter().zip(self.by_class)
+            .filter(|(_, count)| count.instructions > 0)
+            .collect();
+        classes.sort_by_key(|(_, count)| std::cmp::Reverse(count.cycles));
+
+        out += "\n      cycles       %  instructions  class\n";
+        for (class, count) in classes {
+            out += &format!("{:>12} {:>6.2}% {:>13}  {}\n",
+                count.cycles, self.percent(count.cycles), count.instructions, class.name());
+        }
+        out
+    }
+
+
+    /// One line per stack, sorted so the file comes out the same every time.
+    pub fn to_folded(&self) -> String {
+        let mut stacks: Vec<_> = self.folded.iter().collect();
+        stacks.sort();
+        stacks.iter().map(|(stack, cycles)| format!("{} {}\n", stack, cycles)).collect()
+    }
+}
+
+
+/// Runs the program on the interpreter, recording every instruction in the profile.
+pub fn run(cpu: &mut Cpu, profile: &mut Profile, max: Option<u64>) -> Result<u64, Fault> {
+    let mut count = 0;
+
+    while !cpu.halted && Some(count) != max {
+        let pc = cpu.pc();
+        let instr = isa::decode(cpu.peek(pc));
+        let cycles = cpu.cycles;
+        cpu.step()?;
+        profile.record(pc, &instr, cpu.cycles - cycles, cpu.pc());
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timing::WaitRegion;
+
+    const JA: Instruction = Instruction::Ja { cond: 0b111, addr: 10 };
+    const JR: Instruction = Instruction::Jr { cond: 0b111, offset: 2 };
+    const ADD: Instruction = Instruction::Im { op: 5, dst: 0, a: 0, imm: 1 };
+
+    fn profile() -> Profile {
+        let symbols = SymbolTable::parse("0x00 main\n0x10 f\n0x20 g\n0x30 h\n").unwrap();
+        Profile::new(Some(symbols), 0)
+    }
+
+    fn stack(profile: &Profile) -> &str {
+        &profile.stack_key
+    }
+
+    #[test]
+    fn nested_calls() {
+        let mut p = profile();
+        p.record(0x00, &ADD, 4, 0x01);
+        p.record(0x01, &JA, 3, 0x10);  // call f, which comes back to 0x02
+        assert_eq!(stack(&p), "main;f");
+        p.record(0x10, &ADD, 4, 0x11);
+        p.record(0x11, &JA, 3, 0x20);  // call g, back to 0x12
+        assert_eq!(stack(&p), "main;f;g");
+        p.record(0x20, &ADD, 4, 0x21);
+        p.record(0x21, &JA, 3, 0x12);  // return from g
+        assert_eq!(stack(&p), "main;f");
+        p.record(0x12, &JA, 3, 0x02);  // return from f
+        assert_eq!(stack(&p), "main");
+        p.record(0x02, &ADD, 4, 0x03);
+
+        // a jump's cycles go to the frame it jumps from
+        assert_eq!(p.to_folded(), "main 11\nmain;f 10\nmain;f;g 7\n");
+        assert_eq!((p.total.instructions, p.total.cycles), (8, 28));
+    }
+
+    #[test]
+    fn early_returns_unwind_every_frame_in_between() {
+        let mut p = profile();
+        p.record(0x01, &JA, 3, 0x10);
+        p.record(0x11, &JA, 3, 0x20);
+        p.record(0x21, &JA, 3, 0x30);
+        assert_eq!(stack(&p), "main;f;g;h");
+
+        // h goes straight back to where main called f
+        p.record(0x31, &JA, 3, 0x02);
+        assert_eq!(stack(&p), "main");
+
+        // and calling the same function again from somewhere else gives it a new return address
+        p.record(0x05, &JA, 3, 0x10);
+        p.record(0x11, &JA, 3, 0x02);
+        assert_eq!(stack(&p), "main;f");
+        p.record(0x11, &JA, 3, 0x06);
+        assert_eq!(stack(&p), "main");
+    }
+
+    #[test]
+    fn jumps_that_arent_calls() {
+        let mut p = profile();
+        p.record(0x01, &JA, 3, 0x10);
+
+        // JR never calls or returns, even onto a label or the return address
+        p.record(0x10, &JR, 4, 0x20);
+        p.record(0x11, &JR, 4, 0x02);
+        assert_eq!(stack(&p), "main;f");
+
+        // nor does a JA that lands somewhere without a label, or on the next word
+        p.record(0x12, &JA, 3, 0x14);
+        p.record(0x14, &JA, 3, 0x15);
+        assert_eq!(stack(&p), "main;f");
+
+        // a JA onto a label is taken as a call, even if it never comes back
+        p.record(0x15, &JA, 3, 0x30);
+        assert_eq!(stack(&p), "main;f;h");
+        assert_eq!(p.to_folded(), "main 3\nmain;f 17\n");
+    }
+
+    #[test]
+    fn no_symbols() {
+        let mut p = Profile::new(None, 0x40);
+        p.record(0x40, &JA, 3, 0x10);
+        assert_eq!(p.to_folded(), "0x00000040 3\n");
+        assert_eq!(stack(&p), "0x00000040");
+    }
+
+    #[test]
+    fn cycles_come_from_the_timing_model() {
+        let program = [ADD, Instruction::Load { dst: 1, addr: 12 }, Instruction::Nop];
+        let mut cpu = Cpu::new(program.iter().map(|i| isa::encode(i).unwrap()).collect());
+        cpu.set_register(0b1100, 0x8000); // K0
+        cpu.timing.costs[InstrClass::Im as usize] = 10;
+        cpu.timing.costs[InstrClass::Load as usize] = 20;
+        cpu.timing.wait_states.push(WaitRegion { start: 0x8000, end: 0x8001, cycles: 5 });
+
+        let mut p = profile();
+        assert!(matches!(run(&mut cpu, &mut p, None), Ok(3)));
+
+        let cycles = |addr| p.by_addr[&addr].cycles;
+        assert_eq!((cycles(0), cycles(1), cycles(2)), (10, 25, 2));
+        assert_eq!(p.by_class[InstrClass::Load as usize].cycles, 25);
+        assert_eq!(p.to_folded(), "main 37\n");
+        assert!(p.report(&cpu.ram, 5).starts_with("3 instructions in 37 cycles\n"));
+    }
+}
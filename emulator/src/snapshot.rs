@@ -0,0 +1,301 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+
+use crate::emulator::Cpu;
+
+/*
+SNAPSHOT FILE (.astate)
+
+Everything is big endian, like .abin files.
+
+    magic        8 bytes   "ASTATE\r\n"
+    version      u16       SNAPSHOT_VERSION
+    registers    12 x u32  A-H, P, J, K, L (16 bit registers use the bottom half)
+    status       u8        flags and halted bit, see Cpu::status
+    cycles       u64
+    chunks       u32       number of memory chunks that follow
+      start      u32       address of the first word in the chunk
+      length     u32       number of words
+      words      length x u16
+    devices      u32       number of device blocks that follow
+      name       u16 length, then that many bytes of UTF-8
+      data       u32 length, then that many bytes, owned by the device
+
+Memory is saved as runs of non-zero words, since most of RAM is usually empty. There are no
+devices yet, so the device count is always 0, but the reader already knows how to skip them.
+
+The memory limit and the timing table are how the machine is set up, not what it's doing, so they
+aren't saved. Whoever restores a snapshot gives the memory limit to check the chunks against, and
+--memory and --timing apply to a restored machine just like to a loaded program.
+*/
+
+
+const MAGIC: &[u8; 8] = b"ASTATE\r\n";
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+// a zero run longer than this splits a chunk in two
+const MAX_ZERO_RUN: usize = 8;
+
+
+fn read_u8(r: &mut impl Read) -> Result<u8, String> {
+    let mut buf = [0; 1];
+    r.read_exact(&mut buf).map_err(|e| format!("Snapshot is cut short: {}", e))?;
+    Ok(buf[0])
+}
+
+fn read_u16(r: &mut impl Read) -> Result<u16, String> {
+    let mut buf = [0; 2];
+    r.read_exact(&mut buf).map_err(|e| format!("Snapshot is cut short: {}", e))?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32(r: &mut impl Read) -> Result<u32, String> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf).map_err(|e| format!("Snapshot is cut short: {}", e))?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64(r: &mut impl Read) -> Result<u64, String> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf).map_err(|e| format!("Snapshot is cut short: {}", e))?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+
+/// Splits RAM into (start, words) runs, leaving out long stretches of zeros.
+fn chunks(ram: &[u16]) -> Vec<(usize, &[u16])> {
+    let mut chunks = Vec::new();
+    let mut start = None;
+    let mut zeros = 0;
+
+    for (addr, word) in ram.iter().enumerate() {
+        if *word != 0 {
+            if start.is_none() {
+                start = Some(addr);
+            }
+            zeros = 0;
+        } else if let Some(s) = start {
+            zeros += 1;
+            if zeros > MAX_ZERO_RUN {
+                chunks.push((s, &ram[s..=addr - zeros]));
+                start = None;
+            }
+        }
+    }
+    if let Some(s) = start {
+        chunks.push((s, &ram[s..ram.len() - zeros]));
+    }
+
+    chunks
+}
+
+
+pub fn save(cpu: &Cpu, path: &str) -> Result<(), String> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&SNAPSHOT_VERSION.to_be_bytes());
+    for r in cpu.raw_registers() {
+        out.extend_from_slice(&r.to_be_bytes());
+    }
+    out.push(cpu.status());
+    out.extend_from_slice(&cpu.cycles.to_be_bytes());
+
+    let chunks = chunks(&cpu.ram);
+    out.extend_from_slice(&(chunks.len() as u32).to_be_bytes());
+    for (start, words) in chunks {
+        out.extend_from_slice(&(start as u32).to_be_bytes());
+        out.extend_from_slice(&(words.len() as u32).to_be_bytes());
+        for word in words {
+            out.extend_from_slice(&word.to_be_bytes());
+        }
+    }
+
+    out.extend_from_slice(&0u32.to_be_bytes()); // devices
+
+    std::fs::write(path, out).map_err(|e| format!("Could not write {}: {}", path, e))
+}
+
+
+/// Reads a snapshot back into a machine whose memory ends at `memory_limit`.
+pub fn restore(path: &str, memory_limit: u32) -> Result<Cpu, String> {
+    let file = File::open(path).map_err(|e| format!("Could not open {}: {}", path, e))?;
+    let mut r = BufReader::new(file);
+
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic).map_err(|_| format!("{} is not a snapshot", path))?;
+    if &magic != MAGIC {
+        return Err(format!("{} is not a snapshot", path));
+    }
+
+    let version = read_u16(&mut r)?;
+    if version != SNAPSHOT_VERSION {
+        return Err(format!("{} is snapshot version {}, this emulator reads version {}",
+            path, version, SNAPSHOT_VERSION));
+    }
+
+    let mut registers = [0; 12];
+    for reg in registers.iter_mut() {
+        *reg = read_u32(&mut r)?;
+    }
+    let status = read_u8(&mut r)?;
+    let cycles = read_u64(&mut r)?;
+
+    let mut ram: Vec<u16> = Vec::new();
+    for _ in 0..read_u32(&mut r)? {
+        let start = read_u32(&mut r)?;
+        let len = read_u32(&mut r)?;
+        let end = start.checked_add(len).filter(|end| *end <= memory_limit && *end <= 0xF000_0000);
+        let Some(end) = end else {
+            return Err(format!("Snapshot is corrupt: it has {} words at {:#010x}, past the end of memory at {:#010x}",
+                len, start, memory_limit.min(0xF000_0000)));
+        };
+
+        // read the words before making room, so a bad length runs out of file instead of memory
+        let mut words = Vec::new();
+        for _ in 0..len {
+            words.push(read_u16(&mut r)?);
+        }
+        if ram.len() < end as usize {
+            ram.resize(end as usize, 0x0000);
+        }
+        ram[start as usize..end as usize].copy_from_slice(&words);
+    }
+
+    for _ in 0..read_u32(&mut r)? {
+        let mut name = vec![0; read_u16(&mut r)? as usize];
+        r.read_exact(&mut name).map_err(|e| format!("Snapshot is cut short: {}", e))?;
+        let len = read_u32(&mut r)? as u64;
+        let skipped = std::io::copy(&mut (&mut r).take(len), &mut std::io::sink())
+            .map_err(|e| format!("Snapshot is cut short: {}", e))?;
+        if skipped != len {
+            return Err(String::from("Snapshot is cut short"));
+        }
+        crate::pr(&format!("Snapshot has state for unknown device \"{}\", skipping it",
+            String::from_utf8_lossy(&name)));
+    }
+
+    let mut cpu = Cpu::new(ram);
+    cpu.memory_limit = memory_limit;
+    cpu.set_raw_registers(registers);
+    cpu.set_status(status);
+    cpu.cycles = cycles;
+    Ok(cpu)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::DEFAULT_MEMORY;
+
+    fn temp(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("aemu-{}-{}.astate", name, std::process::id()));
+        path.to_string_lossy().into_owned()
+    }
+
+    fn machine() -> Cpu {
+        let mut ram = vec![0; 0x40];
+        ram[0] = 0x1234;
+        ram[3] = 0xFFFF;
+        ram[0x30] = 0x0001; // far enough from the rest to be its own chunk
+        let mut cpu = Cpu::new(ram);
+        cpu.set_raw_registers([1, 2, 3, 4, 5, 6, 7, 0xFFFF, 0x30, 0x0001_0000, 0xDEAD_BEEF, 9]);
+        (cpu.gt_flag, cpu.ls_flag, cpu.halted) = (true, true, true);
+        cpu.cycles = 1 << 40;
+        cpu
+    }
+
+    fn assert_same(a: &Cpu, b: &Cpu) {
+        assert_eq!(a.raw_registers(), b.raw_registers());
+        assert_eq!(a.status(), b.status());
+        assert_eq!(a.cycles, b.cycles);
+        assert_eq!(a.ram[..0x31], b.ram[..]); // trailing zeros aren't kept
+    }
+
+    #[test]
+    fn save_and_restore() {
+        let cpu = machine();
+        let path = temp("round-trip");
+        save(&cpu, &path).unwrap();
+        let restored = restore(&path, DEFAULT_MEMORY).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_same(&cpu, &restored);
+    }
+
+    #[test]
+    fn zero_runs_split_chunks() {
+        let ram = [1, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0];
+        let split: Vec<(usize, &[u16])> = vec![(0, &ram[0..4]), (13, &ram[13..14])];
+        assert_eq!(chunks(&ram), split);
+        assert!(chunks(&[0; 10]).is_empty());
+    }
+
+    #[test]
+    fn device_state_is_skipped() {
+        let cpu = machine();
+        let path = temp("devices");
+        save(&cpu, &path).unwrap();
+
+        // swap the empty device list for one device this emulator doesn't know
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.truncate(bytes.len() - 4);
+        bytes.extend_from_slice(&1u32.to_be_bytes());
+        bytes.extend_from_slice(&5u16.to_be_bytes());
+        bytes.extend_from_slice(b"timer");
+        bytes.extend_from_slice(&3u32.to_be_bytes());
+        bytes.extend_from_slice(&[7, 8, 9]);
+        std::fs::write(&path, &bytes).unwrap();
+        assert_same(&cpu, &restore(&path, DEFAULT_MEMORY).unwrap());
+
+        // and one that's cut short
+        std::fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        let error = restore(&path, DEFAULT_MEMORY).err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(error.as_deref(), Some("Snapshot is cut short"));
+    }
+
+    #[test]
+    fn not_a_snapshot() {
+        let path = temp("bad");
+        std::fs::write(&path, b"ABIN").unwrap();
+        let error = restore(&path, DEFAULT_MEMORY).err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(error, Some(format!("{} is not a snapshot", path)));
+    }
+
+    #[test]
+    fn chunks_have_to_fit_in_memory() {
+        let path = temp("corrupt");
+        let header = |start: u32, len: u32| {
+            let mut bytes = MAGIC.to_vec();
+            bytes.extend_from_slice(&SNAPSHOT_VERSION.to_be_bytes());
+            bytes.extend_from_slice(&[0; 12 * 4 + 1 + 8]); // registers, status, cycles
+            bytes.extend_from_slice(&1u32.to_be_bytes());
+            bytes.extend_from_slice(&start.to_be_bytes());
+            bytes.extend_from_slice(&len.to_be_bytes());
+            bytes
+        };
+
+        let mut errors = Vec::new();
+        for (start, len, limit) in [
+            (0xFFFF_FFF0, 0x20, u32::MAX), // wraps around
+            (0xEFFF_FFFF, 2, u32::MAX),    // into the I/O space
+            (0x10, 0xFFFF_FFF0, 0x100),    // past the memory limit
+            (0x100, 1, 0x100),
+        ] {
+            std::fs::write(&path, header(start, len)).unwrap();
+            errors.push(restore(&path, limit).err().unwrap());
+        }
+        assert!(errors.iter().all(|e| e.starts_with("Snapshot is corrupt")), "{:?}", errors);
+
+        // right up to the limit is fine, as long as the words are all there
+        let mut bytes = header(0xFF, 1);
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(restore(&path, 0x100).err().unwrap().starts_with("Snapshot is cut short"));
+        bytes.extend_from_slice(&[0x12, 0x34, 0, 0, 0, 0]);
+        std::fs::write(&path, &bytes).unwrap();
+        let cpu = restore(&path, 0x100).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((cpu.ram.len(), cpu.peek(0xFF), cpu.memory_limit), (0x100, 0x1234, 0x100));
+    }
+}
@@ -0,0 +1,173 @@
+use isa::InstrClass;
+
+/*
+THE TIMING MODEL
+
+Every instruction costs a fixed number of cycles for its class, plus one extra cycle when a jump
+is taken, plus wait states for every memory access (including the fetch) that lands in a slow
+region. Cpu::cycles adds these up, and is the clock that statistics and devices run off.
+
+The defaults count the stages each class goes through in emulator.rs: fetch and decode for
+everything, then the ALU, register access or a memory access. They can be replaced with a timing
+file once the real hardware has been measured:
+
+    # comments start with #
+    LOAD = 5
+    STORE = 4
+    branch_taken = 1
+    wait 0x8000_0000..0xF000_0000 = 2     # 2 extra cycles per access to slow RAM
+
+Class names are the ones the benchmark uses: NOP, SETFLG, JA, STORE, LOAD, PUTH, GETH, JR, TRA, IM,
+UNKNOWN.
+*/
+
+
+#[derive(Clone, Debug)]
+pub struct WaitRegion {
+    pub start: u32,
+    pub end: u32, // exclusive
+    pub cycles: u64,
+}
+
+
+#[derive(Clone, Debug)]
+pub struct TimingTable {
+    pub costs: [u64; InstrClass::COUNT],
+    pub branch_taken: u64,
+    pub wait_states: Vec<WaitRegion>,
+}
+
+
+impl Default for TimingTable {
+    fn default() -> Self {
+        let mut costs = [0; InstrClass::COUNT];
+        for class in InstrClass::ALL {
+            costs[class as usize] = match class {
+                InstrClass::Nop => 2,     // fetch, decode
+                InstrClass::SetFlg => 3,  // fetch, decode, flags
+                InstrClass::Ja => 3,      // fetch, decode, PC
+                InstrClass::Jr => 3,
+                InstrClass::Store => 4,   // fetch, decode, register read, memory write
+                InstrClass::Load => 5,    // fetch, decode, memory read, register write, PC
+                InstrClass::PutH => 4,    // fetch, decode, register read, register write
+                InstrClass::GetH => 4,
+                InstrClass::Tra => 4,     // fetch, decode, ALU, register write
+                InstrClass::Im => 4,
+                InstrClass::Unknown => 2,
+            };
+        }
+
+        TimingTable {
+            costs,
+            branch_taken: 1,
+            wait_states: Vec::new(),
+        }
+    }
+}
+
+
+fn parse_value(text: &str) -> Result<u64, String> {
+    isa::parse_number(text).map(|n| n as u64) // never negative, as there's no sign
+}
+
+fn parse_address(text: &str) -> Result<u32, String> {
+    let value = parse_value(text)?;
+    u32::try_from(value).map_err(|_| format!("{} is past the end of the address space", text.trim()))
+}
+
+
+impl TimingTable {
+
+    #[inline]
+    pub fn cost(&self, class: InstrClass) -> u64 {
+        self.costs[class as usize]
+    }
+
+    /// Extra cycles for touching `addr`.
+    #[inline]
+    pub fn wait(&self, addr: u32) -> u64 {
+        if self.wait_states.is_empty() {
+            return 0;
+        }
+        self.wait_states.iter()
+            .find(|r| addr >= r.start && addr < r.end)
+            .map_or(0, |r| r.cycles)
+    }
+
+
+    /// Reads a timing file. Anything it doesn't mention keeps its default.
+    pub fn load(path: &str) -> Result<TimingTable, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+        let mut table = TimingTable::default();
+
+        for (num, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let here = |e: String| format!("{}:{}: {}", path, num + 1, e);
+
+            let (key, value) = line.split_once('=')
+                .ok_or_else(|| here(String::from("Expected NAME = CYCLES")))?;
+            let (key, value) = (key.trim(), parse_value(value).map_err(here)?);
+
+            if let Some(range) = key.strip_prefix("wait ") {
+                let (start, end) = range.split_once("..")
+                    .ok_or_else(|| here(String::from("Expected wait START..END = CYCLES")))?;
+                let start = parse_address(start).map_err(here)?;
+                let end = parse_address(end).map_err(here)?;
+                if end <= start {
+                    return Err(here(format!("The wait region {} is empty", range.trim())));
+                }
+                table.wait_states.push(WaitRegion { start, end, cycles: value });
+
+            } else if key == "branch_taken" {
+                table.branch_taken = value;
+
+            } else {
+                let class = InstrClass::ALL.iter()
+                    .find(|c| c.name().eq_ignore_ascii_case(key))
+                    .ok_or_else(|| here(format!("Unknown instruction class \"{}\"", key)))?;
+                table.costs[*class as usize] = value;
+            }
+        }
+
+        Ok(table)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str, text: &str) -> Result<TimingTable, String> {
+        let path = std::env::temp_dir().join(format!("aemu-{}-{}.timing", name, std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        std::fs::write(&path, text).unwrap();
+        let table = TimingTable::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        table.map_err(|e| String::from(e.split_once(": ").unwrap().1)) // without the file name
+    }
+
+    #[test]
+    fn a_timing_file() {
+        let table = load("file", "# measured\nload = 7\nGETH = 1\nbranch_taken = 0\n\
+            wait 0x8000_0000..0xF000_0000 = 2  # slow RAM\n").unwrap();
+        assert_eq!(table.cost(InstrClass::Load), 7);
+        assert_eq!(table.cost(InstrClass::GetH), 1);
+        assert_eq!(table.cost(InstrClass::Store), 4);
+        assert_eq!(table.branch_taken, 0);
+        assert_eq!((table.wait(0x7FFF_FFFF), table.wait(0x8000_0000), table.wait(0xF000_0000)), (0, 2, 0));
+    }
+
+    #[test]
+    fn wait_regions_have_to_fit_in_the_address_space() {
+        assert_eq!(load("past-end", "wait 0..0x1_0000_0001 = 1").err().as_deref(),
+            Some("0x1_0000_0001 is past the end of the address space"));
+        assert_eq!(load("empty", "wait 0x10..0x10 = 1").err().as_deref(),
+            Some("The wait region 0x10..0x10 is empty"));
+        assert_eq!(load("class", "PUSH = 1").err().as_deref(),
+            Some("Unknown instruction class \"PUSH\""));
+    }
+}
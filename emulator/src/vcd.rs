@@ -0,0 +1,303 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::emulator::{Cpu, Fault};
+
+/*
+VALUE CHANGE DUMP
+
+Writes the buses, control lines, registers and flags out as a VCD file, which any waveform viewer
+(GTKWave and friends) can open next to a dump from the logic simulation of the hardware.
+
+Time is counted in cycles of the timing model, so one instruction lasts as long as Cpu::cycles
+says it does. The signals of an instruction change when it starts, and the registers and flags it
+writes change when it finishes, which is also when the next instruction starts. Only the
+interpreter drives the signals, so this can't be used with the block engine.
+*/
+
+
+struct Var {
+    scope: &'static str,
+    name: &'static str,
+    width: u32,
+}
+
+const fn var(scope: &'static str, name: &'static str, width: u32) -> Var {
+    Var { scope, name, width }
+}
+
+const VARS: [Var; 29] = [
+    var("bus", "pc", 32),
+    var("bus", "instr", 16),
+    var("bus", "a_bus", 16),
+    var("bus", "b_bus", 16),
+    var("bus", "out_bus", 16),
+    var("bus", "ram_bus", 16),
+    var("control", "alu_op", 5),
+    var("control", "out_reg", 4),
+    var("control", "out_write", 1),
+    var("control", "mem_read", 1),
+    var("control", "mem_write", 1),
+    var("control", "jump", 1),
+    var("registers", "A", 16),
+    var("registers", "B", 16),
+    var("registers", "C", 16),
+    var("registers", "D", 16),
+    var("registers", "E", 16),
+    var("registers", "F", 16),
+    var("registers", "G", 16),
+    var("registers", "H", 16),
+    var("registers", "P", 32),
+    var("registers", "J", 32),
+    var("registers", "K", 32),
+    var("registers", "L", 32),
+    var("flags", "gt", 1),
+    var("flags", "eq", 1),
+    var("flags", "ls", 1),
+    var("flags", "ov", 1),
+    var("flags", "halted", 1),
+];
+
+const SIGNALS: usize = 12; // the first VARS, which belong to an instruction rather than the state
+
+
+/// The short name VCD uses for a variable in the value changes.
+fn code(index: usize) -> String {
+    let mut index = index;
+    let mut code = String::new();
+    loop {
+        code.push((b'!' + (index % 94) as u8) as char);
+        index /= 94;
+        if index == 0 {
+            return code;
+        }
+    }
+}
+
+
+fn signal_values(cpu: &Cpu) -> [u64; SIGNALS] {
+    let s = &cpu.signals;
+    [
+        s.pc as u64,
+        s.instr as u64,
+        s.a_bus as u64,
+        s.b_bus as u64,
+        s.out_bus as u64,
+        s.ram_bus as u64,
+        s.alu_op as u64,
+        s.out_reg as u64,
+        s.out_write as u64,
+        s.mem_read as u64,
+        s.mem_write as u64,
+        s.jump as u64,
+    ]
+}
+
+
+fn state_values(cpu: &Cpu) -> [u64; VARS.len() - SIGNALS] {
+    let mut values = [0; VARS.len() - SIGNALS];
+    for (num, value) in values.iter_mut().take(8).enumerate() {
+        *value = cpu.register(num as u16) as u64;
+    }
+    for (i, num) in [0b1000, 0b1010, 0b1100, 0b1110].into_iter().enumerate() {
+        values[8 + i] = cpu.wide_register(num) as u64;
+    }
+    values[12] = cpu.gt_flag as u64;
+    values[13] = cpu.eq_flag as u64;
+    values[14] = cpu.ls_flag as u64;
+    values[15] = cpu.ov_flag as u64;
+    values[16] = cpu.halted as u64;
+    values
+}
+
+
+pub struct VcdWriter {
+    out: BufWriter<File>,
+    time: Option<u64>,
+    values: [Option<u64>; VARS.len()],
+}
+
+
+impl VcdWriter {
+
+    pub fn create(path: &str) -> Result<VcdWriter, String> {
+        let file = File::create(path).map_err(|e| format!("Could not create {}: {}", path, e))?;
+        let mut vcd = VcdWriter {
+            out: BufWriter::new(file),
+            time: None,
+            values: [None; VARS.len()],
+        };
+        vcd.header().map_err(|e| format!("Could not write {}: {}", path, e))?;
+        Ok(vcd)
+    }
+
+    fn header(&mut self) -> std::io::Result<()> {
+        writeln!(self.out, "$version AustinOS emulator {} $end", env!("CARGO_PKG_VERSION"))?;
+        writeln!(self.out, "$timescale 1ns $end")?; // really one cycle, whatever the clock turns out to be
+        writeln!(self.out, "$scope module cpu $end")?;
+
+        let mut scope = "";
+        for (i, v) in VARS.iter().enumerate() {
+            if v.scope != scope {
+                if !scope.is_empty() {
+                    writeln!(self.out, "$upscope $end")?;
+                }
+                writeln!(self.out, "$scope module {} $end", v.scope)?;
+                scope = v.scope;
+            }
+            let kind = if v.width == 1 { "wire" } else { "reg" };
+            writeln!(self.out, "$var {} {} {} {} $end", kind, v.width, code(i), v.name)?;
+        }
+
+        writeln!(self.out, "$upscope $end")?;
+        writeln!(self.out, "$upscope $end")?;
+        writeln!(self.out, "$enddefinitions $end")
+    }
+
+    fn change(&mut self, time: u64, index: usize, value: u64) -> std::io::Result<()> {
+        if self.values[index] == Some(value) {
+            return Ok(());
+        }
+        if self.time != Some(time) {
+            writeln!(self.out, "#{}", time)?;
+            self.time = Some(time);
+        }
+        self.values[index] = Some(value);
+
+        let width = VARS[index].width;
+        if width == 1 {
+            writeln!(self.out, "{}{}", value, code(index))
+        } else {
+            writeln!(self.out, "b{:b} {}", value, code(index))
+        }
+    }
+
+    /// Dumps the signals of the instruction that just ran, from the cycle it started.
+    pub fn signals(&mut self, time: u64, cpu: &Cpu) -> std::io::Result<()> {
+        for (i, value) in signal_values(cpu).into_iter().enumerate() {
+            self.change(time, i, value)?;
+        }
+        Ok(())
+    }
+
+    /// Dumps the registers and flags as they are now.
+    pub fn state(&mut self, time: u64, cpu: &Cpu) -> std::io::Result<()> {
+        for (i, value) in state_values(cpu).into_iter().enumerate() {
+            self.change(time, SIGNALS + i, value)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self, time: u64) -> std::io::Result<()> {
+        if self.time != Some(time) {
+            writeln!(self.out, "#{}", time)?;
+        }
+        self.out.flush()
+    }
+}
+
+
+/// Runs the program on the interpreter, dumping every instruction to `vcd`.
+pub fn run(cpu: &mut Cpu, mut vcd: VcdWriter, max: Option<u64>) -> Result<Result<u64, Fault>, String> {
+    let io = |e: std::io::Error| format!("Could not write the VCD file: {}", e);
+    let mut count = 0;
+
+    vcd.state(cpu.cycles, cpu).map_err(io)?;
+
+    while !cpu.halted && Some(count) != max {
+        let start = cpu.cycles;
+        let result = cpu.step();
+
+        // the faulting instruction is dumped too, as that's usually the one being looked for
+        vcd.signals(start, cpu).map_err(io)?;
+        vcd.state(cpu.cycles, cpu).map_err(io)?;
+        if let Err(fault) = result {
+            vcd.finish(cpu.cycles).map_err(io)?;
+            return Ok(Err(fault));
+        }
+        count += 1;
+    }
+
+    vcd.finish(cpu.cycles).map_err(io)?;
+    Ok(Ok(count))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use isa::Instruction;
+
+    fn dump(name: &str, program: &[u16]) -> (Result<u64, Fault>, String) {
+        let path = std::env::temp_dir().join(format!("aemu-{}-{}.vcd", name, std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let mut cpu = Cpu::new(program.to_vec());
+        let result = run(&mut cpu, VcdWriter::create(&path).unwrap(), None).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        (result, text)
+    }
+
+    #[test]
+    fn codes() {
+        assert_eq!((code(0), code(28), code(93), code(94)), ("!".into(), "=".into(), "~".into(), "!\"".into()));
+    }
+
+    #[test]
+    fn header() {
+        let (_, text) = dump("header", &[0x0000]);
+        let (header, _) = text.split_once("$enddefinitions $end\n").unwrap();
+        let mut lines = header.lines();
+        assert_eq!(lines.next(), Some(format!("$version AustinOS emulator {} $end", env!("CARGO_PKG_VERSION")).as_str()));
+        assert_eq!(lines.next(), Some("$timescale 1ns $end"));
+        assert_eq!(lines.next(), Some("$scope module cpu $end"));
+        assert_eq!(lines.next(), Some("$scope module bus $end"));
+        assert_eq!(lines.next(), Some("$var reg 32 ! pc $end"));
+        assert_eq!(lines.next(), Some("$var reg 16 \" instr $end"));
+
+        for line in ["$var wire 1 , jump $end", "$upscope $end", "$scope module registers $end",
+            "$var reg 16 - A $end", "$var reg 32 5 P $end", "$var wire 1 9 gt $end", "$var wire 1 = halted $end"] {
+            assert!(header.lines().any(|l| l == line), "{}", line);
+        }
+        assert_eq!(header.matches("$var ").count(), VARS.len());
+        assert_eq!(header.matches("$scope ").count(), header.matches("$upscope ").count());
+    }
+
+    #[test]
+    fn changes_at_the_right_time() {
+        let mov = isa::encode(&Instruction::Im { op: 4, dst: 0, a: 0, imm: 5 }).unwrap(); // MOV #5, A
+        let (result, text) = dump("changes", &[mov, 0x0000]);
+        assert!(matches!(result, Ok(2)));
+
+        let (_, changes) = text.split_once("$enddefinitions $end\n").unwrap();
+        let at = |time: &str| -> Vec<&str> {
+            let start = changes.find(&format!("#{}\n", time)).unwrap() + time.len() + 2;
+            changes[start..].lines().take_while(|l| !l.starts_with('#')).collect()
+        };
+
+        // everything starts out 0, then the MOV's signals go out at 0 and A changes when it's done
+        assert!(changes.starts_with("#0\n"));
+        assert!(at("0").contains(&"b0 -") && at("0").contains(&"0="));
+        assert!(at("0").contains(&format!("b{:b} \"", mov).as_str()));
+        assert!(at("0").contains(&"1)")); // out_write
+        assert!(at("4").contains(&"b101 -"));
+        assert!(!at("0").contains(&"b101 -"));
+
+        // then the NOP at 4, which halts at 6
+        assert!(at("4").contains(&"b1 !"));
+        assert!(at("4").contains(&"0)"));
+        assert_eq!(at("6"), ["1="]);
+        assert!(changes.ends_with("#6\n1=\n"));
+    }
+
+    #[test]
+    fn the_faulting_instruction_is_dumped() {
+        let mov = isa::encode(&Instruction::Im { op: 4, dst: 0, a: 0, imm: 5 }).unwrap();
+        let (result, text) = dump("fault", &[mov, 0x0382]); // what would be PUTH C, P0
+        assert!(matches!(result, Err(Fault::UnknownInstruction(0x0382))));
+
+        let (_, changes) = text.split_once("#4\n").unwrap();
+        assert!(changes.contains("b1 !\n"), "{}", changes);
+        assert!(changes.contains(&format!("b{:b} \"\n", 0x0382)), "{}", changes);
+    }
+}
@@ -0,0 +1,350 @@
+use std::fmt;
+
+/*
+EXPRESSIONS
+
+The syntax the assembler (for immediates, JR targets and constants) and the debugger (for
+breakpoint conditions and print) both read, e.g.
+
+    #(UART_BASE + 4) & 0x3f
+    [J] != 0 && gt
+
+This only reads them. What a name means, which parts are allowed and how the result is worked
+out is up to whoever is reading, so each of them checks the tree it gets back and works it out
+itself (see assembler/src/expr.rs and emulator/src/expr.rs).
+
+Numbers are read by parse_number, and 'c' is the number of a character, with the same escapes as
+strings: \n, \t, \0, \\, \' and \". @name is a label, with @name.lo and @name.hi its bottom and top
+halves, $ is wherever the expression is, [expr] is memory, and lo(expr) and hi(expr) are the
+halves of any value. The operators are the ones C has for integers, with C's precedence:
+
+    || && | ^ & == != < <= > >= << >> + - * / %, and unary ! - ~ +
+*/
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr<N> {
+    Num(i64),
+    Name(N),             // whatever the reader made of the name
+    Label(String, Half), // @name
+    Here,                // $
+    Mem(Box<Expr<N>>),   // [expr]
+    Unary(UnOp, Box<Expr<N>>),
+    Binary(BinOp, Box<Expr<N>>, Box<Expr<N>>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Half {
+    Whole,
+    Lo,
+    Hi,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnOp {
+    Not,
+    Neg,
+    BitNot,
+    Lo,
+    Hi,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Or,
+    And,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+
+impl<N> Expr<N> {
+
+    /// Calls `check` on every part of the expression, outside in, stopping at the first error.
+    pub fn check(&self, check: &mut impl FnMut(&Expr<N>) -> Result<(), String>) -> Result<(), String> {
+        check(self)?;
+        match self {
+            Self::Mem(e) | Self::Unary(_, e) => e.check(check),
+            Self::Binary(_, lhs, rhs) => {
+                lhs.check(check)?;
+                rhs.check(check)
+            },
+            Self::Num(_) | Self::Name(_) | Self::Label(..) | Self::Here => Ok(()),
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Num(i64),
+    Ident(String),
+    Label(String, Half),
+    Dollar,
+    Op(&'static str),
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Num(n) => write!(f, "{}", n),
+            Token::Ident(name) => write!(f, "{}", name),
+            Token::Label(name, Half::Whole) => write!(f, "@{}", name),
+            Token::Label(name, Half::Lo) => write!(f, "@{}.lo", name),
+            Token::Label(name, Half::Hi) => write!(f, "@{}.hi", name),
+            Token::Dollar => write!(f, "$"),
+            Token::Op(op) => write!(f, "{}", op),
+            Token::LParen => write!(f, "("),
+            Token::RParen => write!(f, ")"),
+            Token::LBracket => write!(f, "["),
+            Token::RBracket => write!(f, "]"),
+        }
+    }
+}
+
+
+/// What an escape like \n in a character or string stands for.
+pub fn unescape(c: char) -> Option<char> {
+    match c {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        '0' => Some('\0'),
+        '\\' | '\'' | '"' => Some(c),
+        _ => None,
+    }
+}
+
+
+// longest operators first so "<=" isn't read as "<" then "="
+const OPERATORS: [&str; 21] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>",
+    "|", "^", "&", "<", ">", "+", "-", "*", "/", "%", "!", "~", "=",
+];
+
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = text.chars().collect();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if c.is_whitespace() {
+            i += 1;
+
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && is_ident_char(chars[i]) {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            tokens.push(Token::Num(crate::parse_number(&word)?));
+
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && is_ident_char(chars[i]) {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+
+        } else if c == '@' {
+            let start = i + 1;
+            i += 1;
+            while i < chars.len() && is_ident_char(chars[i]) {
+                i += 1;
+            }
+            let name: String = chars[start..i].iter().collect();
+            if name.is_empty() {
+                return Err(String::from("Expected a label name after @"));
+            }
+            let rest: String = chars[i..].iter().take(3).collect();
+            let half = match rest.as_str() {
+                ".lo" => Half::Lo,
+                ".hi" => Half::Hi,
+                _ => Half::Whole,
+            };
+            if half != Half::Whole {
+                i += 3;
+            }
+            tokens.push(Token::Label(name, half));
+
+        } else if c == '\'' {
+            let (value, len) = character(&chars[i..])?;
+            tokens.push(Token::Num(value));
+            i += len;
+
+        } else if let Some(token) = match c {
+            '$' => Some(Token::Dollar),
+            '(' => Some(Token::LParen),
+            ')' => Some(Token::RParen),
+            '[' => Some(Token::LBracket),
+            ']' => Some(Token::RBracket),
+            _ => None,
+        } {
+            tokens.push(token);
+            i += 1;
+
+        } else {
+            let rest: String = chars[i..].iter().take(2).collect();
+            match OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+                Some(op) if *op == "=" => return Err(String::from("Use == to compare")),
+                Some(op) => {
+                    tokens.push(Token::Op(op));
+                    i += op.len();
+                },
+                None => return Err(format!("Unexpected character '{}'", c)),
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+
+/// A character literal at the start of `chars`, and how many chars it took up.
+fn character(chars: &[char]) -> Result<(i64, usize), String> {
+    let (c, len) = match chars {
+        ['\'', '\\', e, '\'', ..] => (unescape(*e).ok_or_else(|| format!("Unknown escape \\{} in a \
+            character. There are \\n, \\t, \\0, \\\\, \\' and \\\"", e))?, 4),
+        ['\'', '\'', ..] => return Err(String::from("A character can't be empty. A ' is written '\\''")),
+        ['\'', c, '\'', ..] => (*c, 3),
+        _ => return Err(String::from("Expected one character in quotes, like 'A' or '\\n'")),
+    };
+    Ok((c as i64, len))
+}
+
+
+struct Parser<F> {
+    tokens: Vec<Token>,
+    pos: usize,
+    name: F,
+}
+
+// binary operators from loosest to tightest binding
+const PRECEDENCE: [&[(&str, BinOp)]; 10] = [
+    &[("||", BinOp::Or)],
+    &[("&&", BinOp::And)],
+    &[("|", BinOp::BitOr)],
+    &[("^", BinOp::BitXor)],
+    &[("&", BinOp::BitAnd)],
+    &[("==", BinOp::Eq), ("!=", BinOp::Ne)],
+    &[("<", BinOp::Lt), ("<=", BinOp::Le), (">", BinOp::Gt), (">=", BinOp::Ge)],
+    &[("<<", BinOp::Shl), (">>", BinOp::Shr)],
+    &[("+", BinOp::Add), ("-", BinOp::Sub)],
+    &[("*", BinOp::Mul), ("/", BinOp::Div), ("%", BinOp::Rem)],
+];
+
+impl<N, F: FnMut(&str) -> Result<N, String>> Parser<F> {
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn close(&mut self, token: Token) -> Result<(), String> {
+        match self.next() {
+            Some(t) if t == token => Ok(()),
+            Some(t) => Err(format!("Expected {}, found {}", token, t)),
+            None => Err(format!("Missing {} at the end", token)),
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr<N>, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+
+        let mut lhs = self.binary(level + 1)?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op(s)) => PRECEDENCE[level].iter().find(|(text, _)| text == s),
+                _ => None,
+            };
+            match op {
+                Some((_, op)) => {
+                    self.pos += 1;
+                    let rhs = self.binary(level + 1)?;
+                    lhs = Expr::Binary(*op, Box::new(lhs), Box::new(rhs));
+                },
+                None => return Ok(lhs),
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr<N>, String> {
+        match self.next() {
+            Some(Token::Op("!")) => Ok(Expr::Unary(UnOp::Not, Box::new(self.unary()?))),
+            Some(Token::Op("-")) => Ok(Expr::Unary(UnOp::Neg, Box::new(self.unary()?))),
+            Some(Token::Op("~")) => Ok(Expr::Unary(UnOp::BitNot, Box::new(self.unary()?))),
+            Some(Token::Op("+")) => self.unary(),
+            Some(Token::Num(n)) => Ok(Expr::Num(n)),
+            Some(Token::Dollar) => Ok(Expr::Here),
+            Some(Token::Label(name, half)) => Ok(Expr::Label(name, half)),
+            Some(Token::Ident(name)) if matches!(self.peek(), Some(Token::LParen)) => {
+                let op = match name.as_str() {
+                    "lo" => UnOp::Lo,
+                    "hi" => UnOp::Hi,
+                    _ => return Err(format!("Unknown function \"{}\". There are lo() and hi()", name)),
+                };
+                self.pos += 1;
+                let e = self.binary(0)?;
+                self.close(Token::RParen)?;
+                Ok(Expr::Unary(op, Box::new(e)))
+            },
+            Some(Token::Ident(name)) => Ok(Expr::Name((self.name)(&name)?)),
+            Some(Token::LParen) => {
+                let e = self.binary(0)?;
+                self.close(Token::RParen)?;
+                Ok(e)
+            },
+            Some(Token::LBracket) => {
+                let e = self.binary(0)?;
+                self.close(Token::RBracket)?;
+                Ok(Expr::Mem(Box::new(e)))
+            },
+            Some(t) => Err(format!("Unexpected {}", t)),
+            None => Err(String::from("Expression ended early")),
+        }
+    }
+}
+
+
+/// Reads an expression, turning each name in it into whatever `name` gives back for it.
+pub fn parse<N>(text: &str, name: impl FnMut(&str) -> Result<N, String>) -> Result<Expr<N>, String> {
+    let mut parser = Parser { tokens: tokenize(text)?, pos: 0, name };
+    let e = parser.binary(0)?;
+    match parser.peek() {
+        None => Ok(e),
+        Some(t) => Err(format!("Unexpected {} after the end", t)),
+    }
+}
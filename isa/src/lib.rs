@@ -0,0 +1,434 @@
+use std::fmt;
+
+pub mod expr;
+pub mod lines;
+pub mod symbols;
+
+/*
+INSTRUCTION FORMATS
+
+    0000_0000_0000_0000  NOP
+    0000_0010_GGEE_LLOO  SETFLG   GG, EE, LL, OO: what to do with gt, eq, ls, ov (see FlagOp)
+    0000_0011_000C_CCWW  JA       jump to address register WW if condition CCC holds
+    0000_0011_001S_SSWW  STORE    write register SSS to the address in WW
+    0000_0011_010D_DDWW  LOAD     read the address in WW into register DDD
+    0000_0011_10HH_HSSS  PUTH     copy register SSS into half register HHH (J0 to L1)
+    0000_0011_11HH_HDDD  GETH     copy half register HHH (P0 to L1) into register DDD
+    0000_01CC_CRRR_RRRR  JR       add RRRRRRR (signed) to PC if condition CCC holds
+    001O_OOOD_DDBB_BAAA  TRA      DDD = AAA op BBB
+    OOOO_IIII_IIDD_DAAA  IM       DDD = AAA op IIIIII, for OOOO of 4 and up
+
+Address registers (WW) are 0: P, 1: J, 2: K, 3: L. Half registers (HHH) are 0: P0, 1: P1, 2: J0
+and so on up to 7: L1, where 0 is the bottom half. PUTH can't write P, which only jumps change, so
+PUTH with HHH of 0 or 1 is unknown. Conditions (CCC) are a mask of gt, eq and ls,
+and the jump happens if any of the flags in the mask is set. 111 always jumps.
+
+Everything that isn't one of these is unknown, and the CPU faults on it. FORMATS below is the same
+table in a form the code can use: both decode() and encode() are driven by it, so the emulator and
+the assembler can't disagree about where a field goes.
+*/
+
+
+/// Names of the ALU operations, by the op field of TRA and IM. The others aren't built yet.
+pub const ALU_OP_NAMES: [Option<&str>; 16] = [
+    None, None, None, None, Some("MOV"), Some("ADD"), Some("SUB"), Some("MUL"),
+    None, None, None, None, None, None, None, None,
+];
+
+pub fn alu_op(name: &str) -> Option<u8> {
+    ALU_OP_NAMES.iter().position(|n| n.is_some_and(|n| n.eq_ignore_ascii_case(name))).map(|op| op as u8)
+}
+
+/// The flags a jump condition can test, with their bit in CCC.
+pub const CONDITION_FLAGS: [(&str, u8); 3] = [("GT", 0b100), ("EQ", 0b010), ("LS", 0b001)];
+
+/// CCC for a jump that is always taken.
+pub const COND_ALWAYS: u8 = 0b111;
+
+
+/// Names of the registers, by register number. 8 to 15 are the halves of the address registers,
+/// bottom half first.
+pub const REGISTER_NAMES: [&str; 16] = [
+    "A", "B", "C", "D", "E", "F", "G", "H",
+    "P0", "P1", "J0", "J1", "K0", "K1", "L0", "L1",
+];
+
+/// Names of the address registers, by their WW field.
+pub const ADDR_REGISTER_NAMES: [&str; 4] = ["P", "J", "K", "L"];
+
+/// The register number of a register or half register name, like "C" or "J1".
+pub fn register_number(name: &str) -> Option<u8> {
+    REGISTER_NAMES.iter().position(|r| r.eq_ignore_ascii_case(name)).map(|n| n as u8)
+}
+
+/// The register number of the bottom half of an address register, like "K". PC is P.
+pub fn addr_register_number(name: &str) -> Option<u8> {
+    let name = if name.eq_ignore_ascii_case("PC") { "P" } else { name };
+    ADDR_REGISTER_NAMES.iter()
+        .position(|r| r.eq_ignore_ascii_case(name))
+        .map(|ww| ((ww as u8) << 1) | 0b1000)
+}
+
+
+/// Reads a number the way every tool writes them: decimal, or hex, binary or octal with 0x, 0b or
+/// 0o in front, with _ anywhere in it, as in 0x0000_9000. No sign, as that's left to whatever is
+/// reading expressions.
+pub fn parse_number(word: &str) -> Result<i64, String> {
+    let lower = word.trim().replace('_', "").to_ascii_lowercase();
+    let (radix, digits) = match lower.get(..2) {
+        Some("0x") => (16, &lower[2..]),
+        Some("0b") => (2, &lower[2..]),
+        Some("0o") => (8, &lower[2..]),
+        _ => (10, lower.as_str()),
+    };
+    if digits.starts_with(['+', '-']) {
+        return Err(format!("Invalid number \"{}\"", word));
+    }
+    i64::from_str_radix(digits, radix).map_err(|e| match e.kind() {
+        std::num::IntErrorKind::PosOverflow => format!("{} is too big. Numbers can be up to {}", word, i64::MAX),
+        _ => format!("Invalid number \"{}\"", word),
+    })
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlagOp {
+    Clear,
+    Keep,
+    Toggle,
+    Set,
+}
+
+impl FlagOp {
+    pub fn from_bits(bits: u16) -> FlagOp {
+        match bits & 0b11 {
+            0b00 => FlagOp::Clear,
+            0b01 => FlagOp::Keep,
+            0b10 => FlagOp::Toggle,
+            _ => FlagOp::Set,
+        }
+    }
+
+    pub fn bits(self) -> u16 {
+        match self {
+            FlagOp::Clear => 0b00,
+            FlagOp::Keep => 0b01,
+            FlagOp::Toggle => 0b10,
+            FlagOp::Set => 0b11,
+        }
+    }
+
+    pub fn apply(self, flag: bool) -> bool {
+        match self {
+            FlagOp::Clear => false,
+            FlagOp::Keep => flag,
+            FlagOp::Toggle => !flag,
+            FlagOp::Set => true,
+        }
+    }
+}
+
+
+/// A decoded instruction word. Register fields hold register numbers as the CPU uses them, so
+/// `addr` is already the number of the bottom half of the address register (8, 10, 12 or 14), and
+/// `half` is from 8 (P0) to 15 (L1).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    Nop,
+    SetFlg { gt: FlagOp, eq: FlagOp, ls: FlagOp, ov: FlagOp },
+    Ja { cond: u8, addr: u8 },
+    Store { src: u8, addr: u8 },
+    Load { dst: u8, addr: u8 },
+    PutH { half: u8, src: u8 },
+    GetH { dst: u8, half: u8 },
+    Jr { cond: u8, offset: i8 },
+    Tra { op: u8, dst: u8, a: u8, b: u8 },
+    Im { op: u8, dst: u8, a: u8, imm: u8 },
+    Unknown(u16),
+}
+
+
+/// The families of instructions, one for each format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InstrClass {
+    Nop,
+    SetFlg,
+    Ja,
+    Store,
+    Load,
+    PutH,
+    GetH,
+    Jr,
+    Tra,
+    Im,
+    Unknown,
+}
+
+impl InstrClass {
+    pub const COUNT: usize = 11;
+
+    pub const ALL: [InstrClass; InstrClass::COUNT] = [
+        InstrClass::Nop,
+        InstrClass::SetFlg,
+        InstrClass::Ja,
+        InstrClass::Store,
+        InstrClass::Load,
+        InstrClass::PutH,
+        InstrClass::GetH,
+        InstrClass::Jr,
+        InstrClass::Tra,
+        InstrClass::Im,
+        InstrClass::Unknown,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            InstrClass::Nop => "NOP",
+            InstrClass::SetFlg => "SETFLG",
+            InstrClass::Ja => "JA",
+            InstrClass::Store => "STORE",
+            InstrClass::Load => "LOAD",
+            InstrClass::PutH => "PUTH",
+            InstrClass::GetH => "GETH",
+            InstrClass::Jr => "JR",
+            InstrClass::Tra => "TRA",
+            InstrClass::Im => "IM",
+            InstrClass::Unknown => "UNKNOWN",
+        }
+    }
+
+    /// The format of this class, or None for unknown instructions.
+    pub fn format(self) -> Option<&'static Format> {
+        FORMATS.iter().find(|f| f.class == self)
+    }
+}
+
+
+/// A field of an instruction word.
+#[derive(Debug)]
+pub struct Field {
+    pub name: &'static str,
+    pub shift: u32,
+    pub width: u32,
+    pub signed: bool,
+    pub min: i32, // smallest value allowed, when that isn't the bottom of the field's range
+}
+
+const fn field(name: &'static str, shift: u32, width: u32) -> Field {
+    Field { name, shift, width, signed: false, min: 0 }
+}
+
+impl Field {
+    pub fn max(&self) -> i32 {
+        if self.signed { (1 << (self.width - 1)) - 1 } else { (1 << self.width) - 1 }
+    }
+
+    pub fn lowest(&self) -> i32 {
+        if self.signed { -(1 << (self.width - 1)) } else { self.min }
+    }
+
+    fn mask(&self) -> u16 {
+        (((1u32 << self.width) - 1) << self.shift) as u16
+    }
+
+    fn extract(&self, word: u16) -> i32 {
+        let raw = ((word & self.mask()) >> self.shift) as i32;
+        if self.signed && raw & (1 << (self.width - 1)) != 0 {
+            raw - (1 << self.width)
+        } else {
+            raw
+        }
+    }
+
+    fn insert(&self, value: i32) -> u16 {
+        ((value as u32 & ((1 << self.width) - 1)) << self.shift) as u16
+    }
+}
+
+
+/// An instruction format: the bits in `mask` must equal `pattern`, and every field must be in
+/// range. The fields are listed from the top of the word down.
+#[derive(Debug)]
+pub struct Format {
+    pub class: InstrClass,
+    pub mask: u16,
+    pub pattern: u16,
+    pub fields: &'static [Field],
+}
+
+/// Every format, in the order decode tries them. IM comes last, as it takes whatever is left
+/// with an operation of 4 and up.
+pub const FORMATS: [Format; 10] = [
+    Format { class: InstrClass::Nop, mask: 0xFFFF, pattern: 0x0000, fields: &[] },
+    Format {
+        class: InstrClass::SetFlg, mask: 0xFF00, pattern: 0x0200,
+        fields: &[field("gt", 6, 2), field("eq", 4, 2), field("ls", 2, 2), field("ov", 0, 2)],
+    },
+    Format {
+        class: InstrClass::Ja, mask: 0xFFE0, pattern: 0x0300,
+        fields: &[field("cond", 2, 3), field("addr", 0, 2)],
+    },
+    Format {
+        class: InstrClass::Store, mask: 0xFFE0, pattern: 0x0320,
+        fields: &[field("src", 2, 3), field("addr", 0, 2)],
+    },
+    Format {
+        class: InstrClass::Load, mask: 0xFFE0, pattern: 0x0340,
+        fields: &[field("dst", 2, 3), field("addr", 0, 2)],
+    },
+    Format {
+        class: InstrClass::PutH, mask: 0xFFC0, pattern: 0x0380,
+        fields: &[Field { name: "half", shift: 3, width: 3, signed: false, min: 2 }, field("src", 0, 3)],
+    },
+    Format {
+        class: InstrClass::GetH, mask: 0xFFC0, pattern: 0x03C0,
+        fields: &[field("half", 3, 3), field("dst", 0, 3)],
+    },
+    Format {
+        class: InstrClass::Jr, mask: 0xFC00, pattern: 0x0400,
+        fields: &[field("cond", 7, 3), Field { name: "offset", shift: 0, width: 7, signed: true, min: 0 }],
+    },
+    Format {
+        class: InstrClass::Tra, mask: 0xE000, pattern: 0x2000,
+        fields: &[field("op", 9, 4), field("dst", 6, 3), field("b", 3, 3), field("a", 0, 3)],
+    },
+    Format {
+        class: InstrClass::Im, mask: 0x0000, pattern: 0x0000,
+        fields: &[
+            Field { name: "op", shift: 12, width: 4, signed: false, min: 4 },
+            field("imm", 6, 6),
+            field("dst", 3, 3),
+            field("a", 0, 3),
+        ],
+    },
+];
+
+
+impl Instruction {
+    pub fn class(&self) -> InstrClass {
+        match self {
+            Instruction::Nop => InstrClass::Nop,
+            Instruction::SetFlg { .. } => InstrClass::SetFlg,
+            Instruction::Ja { .. } => InstrClass::Ja,
+            Instruction::Store { .. } => InstrClass::Store,
+            Instruction::Load { .. } => InstrClass::Load,
+            Instruction::PutH { .. } => InstrClass::PutH,
+            Instruction::GetH { .. } => InstrClass::GetH,
+            Instruction::Jr { .. } => InstrClass::Jr,
+            Instruction::Tra { .. } => InstrClass::Tra,
+            Instruction::Im { .. } => InstrClass::Im,
+            Instruction::Unknown(_) => InstrClass::Unknown,
+        }
+    }
+
+    /// The values of the fields, in the order the format lists them, padded out with zeros.
+    /// Address registers are given as their WW field, which is where the register number has to
+    /// be a bottom half to fit, and half registers as their HHH field.
+    fn field_values(&self) -> Result<[i32; 4], EncodeError> {
+        let ww = |addr: u8| match addr {
+            8 | 10 | 12 | 14 => Ok(((addr >> 1) & 0b11) as i32),
+            _ => Err(EncodeError { class: self.class(), field: "addr", value: addr as i32 }),
+        };
+
+        Ok(match *self {
+            Instruction::Nop | Instruction::Unknown(_) => [0; 4],
+            Instruction::SetFlg { gt, eq, ls, ov } =>
+                [gt.bits() as i32, eq.bits() as i32, ls.bits() as i32, ov.bits() as i32],
+            Instruction::Ja { cond, addr } => [cond as i32, ww(addr)?, 0, 0],
+            Instruction::Store { src, addr } => [src as i32, ww(addr)?, 0, 0],
+            Instruction::Load { dst, addr } => [dst as i32, ww(addr)?, 0, 0],
+            Instruction::PutH { half, src } => [half as i32 - 8, src as i32, 0, 0],
+            Instruction::GetH { dst, half } => [half as i32 - 8, dst as i32, 0, 0],
+            Instruction::Jr { cond, offset } => [cond as i32, offset as i32, 0, 0],
+            Instruction::Tra { op, dst, a, b } => [op as i32, dst as i32, b as i32, a as i32],
+            Instruction::Im { op, dst, a, imm } => [op as i32, imm as i32, dst as i32, a as i32],
+        })
+    }
+
+    fn from_field_values(class: InstrClass, f: &[i32]) -> Instruction {
+        let addr = |ww: i32| ((ww as u8) << 1) | 0b1000;
+        let flag = |bits: i32| FlagOp::from_bits(bits as u16);
+
+        match class {
+            InstrClass::Nop => Instruction::Nop,
+            InstrClass::SetFlg => Instruction::SetFlg { gt: flag(f[0]), eq: flag(f[1]), ls: flag(f[2]), ov: flag(f[3]) },
+            InstrClass::Ja => Instruction::Ja { cond: f[0] as u8, addr: addr(f[1]) },
+            InstrClass::Store => Instruction::Store { src: f[0] as u8, addr: addr(f[1]) },
+            InstrClass::Load => Instruction::Load { dst: f[0] as u8, addr: addr(f[1]) },
+            InstrClass::PutH => Instruction::PutH { half: f[0] as u8 + 8, src: f[1] as u8 },
+            InstrClass::GetH => Instruction::GetH { half: f[0] as u8 + 8, dst: f[1] as u8 },
+            InstrClass::Jr => Instruction::Jr { cond: f[0] as u8, offset: f[1] as i8 },
+            InstrClass::Tra => Instruction::Tra { op: f[0] as u8, dst: f[1] as u8, b: f[2] as u8, a: f[3] as u8 },
+            InstrClass::Im => Instruction::Im { op: f[0] as u8, imm: f[1] as u8, dst: f[2] as u8, a: f[3] as u8 },
+            InstrClass::Unknown => unreachable!("unknown instructions have no format"),
+        }
+    }
+}
+
+
+pub fn decode(instr: u16) -> Instruction {
+    for format in FORMATS.iter() {
+        if instr & format.mask != format.pattern {
+            continue;
+        }
+
+        let mut values = [0; 4];
+        for (value, field) in values.iter_mut().zip(format.fields) {
+            *value = field.extract(instr);
+        }
+        if format.fields.iter().zip(values).any(|(f, v)| v < f.lowest()) {
+            continue;
+        }
+        return Instruction::from_field_values(format.class, &values);
+    }
+
+    Instruction::Unknown(instr)
+}
+
+
+/// A field that doesn't fit in its instruction.
+#[derive(Debug, PartialEq, Eq)]
+pub struct EncodeError {
+    pub class: InstrClass,
+    pub field: &'static str,
+    pub value: i32,
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.field == "addr" {
+            return write!(f, "{} needs an address register (P, J, K or L), not register {}",
+                self.class.name(), self.value);
+        }
+        let format = self.class.format().unwrap();
+        let field = format.fields.iter().find(|x| x.name == self.field).unwrap();
+        if self.field == "half" {
+            let lowest = REGISTER_NAMES[8 + field.lowest() as usize];
+            return write!(f, "{} needs a half register ({} to L1), not register {}",
+                self.class.name(), lowest, self.value + 8);
+        }
+        write!(f, "{} of {} is {}, but has to be from {} to {}",
+            field.name, self.class.name(), self.value, field.lowest(), field.max())
+    }
+}
+
+
+pub fn encode(instr: &Instruction) -> Result<u16, EncodeError> {
+    let format = match instr.class().format() {
+        Some(format) => format,
+        None => match instr {
+            Instruction::Unknown(word) => return Ok(*word), // passed through as it is
+            _ => unreachable!(),
+        },
+    };
+
+    let mut word = format.pattern;
+    for (field, value) in format.fields.iter().zip(instr.field_values()?) {
+        if value < field.lowest() || value > field.max() {
+            return Err(EncodeError { class: format.class, field: field.name, value });
+        }
+        word |= field.insert(value);
+    }
+
+    Ok(word)
+}
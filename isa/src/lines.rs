@@ -0,0 +1,103 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+/*
+LINE TABLES (.aline)
+
+The assembler writes one of these next to the symbols, giving the source line every instruction
+word came from, so the emulator can report coverage (and anything else) by line instead of by
+address. Like the symbol file it is plain text, one word per line, address first:
+
+    # AustinOS line table
+    0x00000000 2 os.aasm
+    0x00000001 3 os.aasm
+    0x00008000 14 lib/print.aasm
+
+Addresses are hex and lines count from 1. The file is everything after the line number, so it can
+have spaces in it, and # only starts a comment at the start of a line. Data words aren't listed.
+*/
+
+
+#[derive(Debug, Default, Clone)]
+pub struct LineTable {
+    files: Vec<String>,
+    by_addr: BTreeMap<u32, (usize, u32)>, // index into files, and the line
+}
+
+
+impl LineTable {
+
+    pub fn new() -> LineTable {
+        LineTable::default()
+    }
+
+    pub fn insert(&mut self, addr: u32, file: &str, line: u32) {
+        let index = match self.files.iter().position(|f| f == file) {
+            Some(i) => i,
+            None => {
+                self.files.push(String::from(file));
+                self.files.len() - 1
+            },
+        };
+        self.by_addr.insert(addr, (index, line));
+    }
+
+    /// The file and line the word at `addr` came from.
+    pub fn line_at(&self, addr: u32) -> Option<(&str, u32)> {
+        self.by_addr.get(&addr).map(|(file, line)| (self.files[*file].as_str(), *line))
+    }
+
+    /// Every word in address order, with its file and line.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &str, u32)> {
+        self.by_addr.iter().map(|(addr, (file, line))| (*addr, self.files[*file].as_str(), *line))
+    }
+
+    /// The source files, in the order they first show up.
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_addr.is_empty()
+    }
+
+    pub fn parse(text: &str) -> Result<LineTable, String> {
+        let mut table = LineTable::new();
+
+        for (num, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let parts = line.split_once(char::is_whitespace)
+                .and_then(|(addr, rest)| rest.trim_start().split_once(char::is_whitespace)
+                    .map(|(source_line, file)| (addr, source_line, file.trim())));
+            let (addr, source_line, file) = match parts {
+                Some(parts) => parts,
+                None => return Err(format!("line {}: expected ADDRESS LINE FILE", num + 1)),
+            };
+            let hex = addr.strip_prefix("0x").unwrap_or(addr).replace('_', "");
+            let addr = u32::from_str_radix(&hex, 16)
+                .map_err(|_| format!("line {}: invalid address \"{}\"", num + 1, addr))?;
+            let source_line = source_line.parse()
+                .map_err(|_| format!("line {}: invalid line number \"{}\"", num + 1, source_line))?;
+            table.insert(addr, file, source_line);
+        }
+
+        Ok(table)
+    }
+
+    pub fn load(path: &str) -> Result<LineTable, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+        LineTable::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut out = String::from("# AustinOS line table\n");
+        for (addr, file, line) in self.iter() {
+            out += &format!("{:#010x} {} {}\n", addr, line, file);
+        }
+        out
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+/*
+SYMBOL FILES (.asym)
+
+The assembler writes one of these next to every .abin, and the tools that show addresses (the
+disassembler, the debugger, the profiler) read it to put names on them. It is plain text, one
+label per line, address first:
+
+    # AustinOS symbols
+    0x00000000 start
+    0x00000004 loop
+    0x00008000 data
+
+Addresses are hex, names are anything without whitespace, and # starts a comment. More than one
+name can share an address.
+*/
+
+
+#[derive(Debug, Default, Clone)]
+pub struct SymbolTable {
+    by_addr: BTreeMap<u32, Vec<String>>,
+}
+
+
+impl SymbolTable {
+
+    pub fn new() -> SymbolTable {
+        SymbolTable::default()
+    }
+
+    pub fn insert(&mut self, addr: u32, name: &str) {
+        self.by_addr.entry(addr).or_default().push(String::from(name));
+    }
+
+    /// The first name given to `addr`.
+    pub fn name_at(&self, addr: u32) -> Option<&str> {
+        self.by_addr.get(&addr).and_then(|names| names.first()).map(|n| n.as_str())
+    }
+
+    /// Every name given to `addr`.
+    pub fn names_at(&self, addr: u32) -> &[String] {
+        self.by_addr.get(&addr).map_or(&[], |names| names.as_slice())
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<u32> {
+        self.iter().find(|(_, n)| *n == name).map(|(addr, _)| addr)
+    }
+
+    /// The closest symbol at or below `addr`, for showing an address as `label+offset`.
+    pub fn containing(&self, addr: u32) -> Option<(u32, &str)> {
+        self.by_addr.range(..=addr).next_back()
+            .and_then(|(a, names)| names.first().map(|n| (*a, n.as_str())))
+    }
+
+    /// Every symbol in address order.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &str)> {
+        self.by_addr.iter().flat_map(|(a, names)| names.iter().map(move |n| (*a, n.as_str())))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_addr.is_empty()
+    }
+
+    pub fn parse(text: &str) -> Result<SymbolTable, String> {
+        let mut table = SymbolTable::new();
+
+        for (num, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            let (addr, name) = match (words.next(), words.next(), words.next()) {
+                (Some(addr), Some(name), None) => (addr, name),
+                _ => return Err(format!("line {}: expected ADDRESS NAME", num + 1)),
+            };
+            let hex = addr.strip_prefix("0x").unwrap_or(addr).replace('_', "");
+            let addr = u32::from_str_radix(&hex, 16)
+                .map_err(|_| format!("line {}: invalid address \"{}\"", num + 1, addr))?;
+            table.insert(addr, name);
+        }
+
+        Ok(table)
+    }
+
+    pub fn load(path: &str) -> Result<SymbolTable, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+        SymbolTable::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut out = String::from("# AustinOS symbols\n");
+        for (addr, name) in self.iter() {
+            out += &format!("{:#010x} {}\n", addr, name);
+        }
+        out
+    }
+}
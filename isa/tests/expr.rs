@@ -0,0 +1,92 @@
+use isa::expr::{parse, BinOp, Expr, Half, UnOp};
+
+
+fn read(text: &str) -> Expr<String> {
+    parse(text, |name| Ok(String::from(name))).unwrap_or_else(|e| panic!("{}: {}", text, e))
+}
+
+fn error(text: &str) -> String {
+    parse(text, |name| Ok(String::from(name))).unwrap_err()
+}
+
+fn num(n: i64) -> Box<Expr<String>> {
+    Box::new(Expr::Num(n))
+}
+
+
+#[test]
+fn precedence_follows_c() {
+    let same = [
+        ("1 + 2 * 3", "1 + (2 * 3)"),
+        ("1 << 2 + 1", "1 << (2 + 1)"),
+        ("6 & 3 == 3", "6 & (3 == 3)"),
+        ("1 | 2 ^ 3 & 1", "1 | (2 ^ (3 & 1))"),
+        ("1 < 2 == 1", "(1 < 2) == 1"),
+        ("a || b && c", "a || (b && c)"),
+        ("10 - 4 - 3", "(10 - 4) - 3"),
+        ("-2 * ~3", "(-2) * (~3)"),
+        ("+5", "5"),
+    ];
+    for (text, grouped) in same {
+        assert_eq!(read(text), read(grouped), "{}", text);
+    }
+    assert_eq!(read("7 / 2 % 2"), Expr::Binary(BinOp::Rem, Box::new(Expr::Binary(BinOp::Div, num(7), num(2))), num(2)));
+}
+
+
+#[test]
+fn every_kind_of_operand() {
+    assert_eq!(read("0x10 + 0b11 + 0o7 + 1_000"), read("16 + 3 + 7 + 1000"));
+    assert_eq!(read("'A' | '\\n' | '\\'' | ','"), read("65 | 10 | 39 | 44"));
+    assert_eq!(read("@table.hi"), Expr::Label(String::from("table"), Half::Hi));
+    assert_eq!(read("@table + $"), Expr::Binary(BinOp::Add,
+        Box::new(Expr::Label(String::from("table"), Half::Whole)), Box::new(Expr::Here)));
+    assert_eq!(read("[J + 1]"), Expr::Mem(Box::new(Expr::Binary(BinOp::Add,
+        Box::new(Expr::Name(String::from("J"))), num(1)))));
+    assert_eq!(read("lo(!x)"), Expr::Unary(UnOp::Lo,
+        Box::new(Expr::Unary(UnOp::Not, Box::new(Expr::Name(String::from("x")))))));
+}
+
+
+#[test]
+fn names_are_up_to_the_reader() {
+    let registers = |name: &str| match name {
+        "A" => Ok(0),
+        "B" => Ok(1),
+        _ => Err(format!("Unknown register \"{}\"", name)),
+    };
+    assert_eq!(parse("A + B", registers), Ok(Expr::Binary(BinOp::Add,
+        Box::new(Expr::Name(0)), Box::new(Expr::Name(1)))));
+    assert_eq!(parse("A + Q", registers), Err(String::from("Unknown register \"Q\"")));
+
+    // and so is what's allowed, which check finds anywhere in the tree
+    let mut labels = Vec::new();
+    read("(@a + 1) * [@b.lo]").check(&mut |e| {
+        if let Expr::Label(name, _) = e {
+            labels.push(name.clone());
+        }
+        Ok(())
+    }).unwrap();
+    assert_eq!(labels, ["a", "b"]);
+    assert_eq!(read("1 + $").check(&mut |e| match e {
+        Expr::Here => Err(String::from("no $")),
+        _ => Ok(()),
+    }), Err(String::from("no $")));
+}
+
+
+#[test]
+fn bad_expressions() {
+    assert_eq!(error("A = 1"), "Use == to compare");
+    assert_eq!(error("0x"), "Invalid number \"0x\"");
+    assert_eq!(error("(1 + 2"), "Missing ) at the end");
+    assert_eq!(error("[J + 2)"), "Expected ], found )");
+    assert_eq!(error("1 +"), "Expression ended early");
+    assert_eq!(error("1 2"), "Unexpected 2 after the end");
+    assert_eq!(error("1 # 2"), "Unexpected character '#'");
+    assert_eq!(error("sqrt(4)"), "Unknown function \"sqrt\". There are lo() and hi()");
+    assert_eq!(error("@"), "Expected a label name after @");
+    assert_eq!(error("''"), "A character can't be empty. A ' is written '\\''");
+    assert_eq!(error("'ab'"), "Expected one character in quotes, like 'A' or '\\n'");
+    assert_eq!(error("'\\q'"), "Unknown escape \\q in a character. There are \\n, \\t, \\0, \\\\, \\' and \\\"");
+}
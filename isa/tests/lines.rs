@@ -0,0 +1,37 @@
+use isa::lines::LineTable;
+
+
+#[test]
+fn parse_and_write() {
+    let text = "\
+# AustinOS line table
+0x00000000 2 os.aasm
+0x00000001 3 os.aasm
+0x00008000 14 lib/my print.aasm
+";
+    let table = LineTable::parse(text).unwrap();
+    assert_eq!(table.line_at(1), Some(("os.aasm", 3)));
+    assert_eq!(table.line_at(0x8000), Some(("lib/my print.aasm", 14)));
+    assert_eq!(table.line_at(2), None);
+    assert_eq!(table.files(), ["os.aasm", "lib/my print.aasm"]);
+    assert_eq!(table.to_text(), text);
+
+    // written in address order, whatever order they went in
+    let mut table = LineTable::new();
+    table.insert(0x10, "b.aasm", 1);
+    table.insert(0x00, "a.aasm", 7);
+    table.insert(0x00, "a.aasm", 8); // the last line given for a word wins
+    assert_eq!(table.to_text(), "# AustinOS line table\n0x00000000 8 a.aasm\n0x00000010 1 b.aasm\n");
+    assert_eq!(LineTable::parse(&table.to_text()).unwrap().to_text(), table.to_text());
+}
+
+
+#[test]
+fn parse_errors() {
+    let error = |text: &str| LineTable::parse(text).err();
+    assert_eq!(error("0x0 1 a.aasm\n0x1 2\n"), Some(String::from("line 2: expected ADDRESS LINE FILE")));
+    assert_eq!(error("0x0\n"), Some(String::from("line 1: expected ADDRESS LINE FILE")));
+    assert_eq!(error("zz 1 a.aasm\n"), Some(String::from("line 1: invalid address \"zz\"")));
+    assert_eq!(error("0x0 one a.aasm\n"), Some(String::from("line 1: invalid line number \"one\"")));
+    assert!(LineTable::parse("\n   \n# just comments\n").unwrap().is_empty());
+}
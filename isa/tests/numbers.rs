@@ -0,0 +1,25 @@
+use isa::parse_number;
+
+
+#[test]
+fn every_base() {
+    assert_eq!(parse_number("1234"), Ok(1234));
+    assert_eq!(parse_number("0x1F"), Ok(0x1F));
+    assert_eq!(parse_number("0XfF"), Ok(0xFF));
+    assert_eq!(parse_number("0b101"), Ok(5));
+    assert_eq!(parse_number("0o17"), Ok(15));
+    assert_eq!(parse_number("0x0000_9000"), Ok(0x9000));
+    assert_eq!(parse_number(" 42 "), Ok(42));
+}
+
+
+#[test]
+fn bad_numbers() {
+    assert_eq!(parse_number("0x"), Err(String::from("Invalid number \"0x\"")));
+    assert_eq!(parse_number("0b2"), Err(String::from("Invalid number \"0b2\"")));
+    assert_eq!(parse_number("12a"), Err(String::from("Invalid number \"12a\"")));
+    assert_eq!(parse_number("-5"), Err(String::from("Invalid number \"-5\"")));
+    assert_eq!(parse_number("0x-5"), Err(String::from("Invalid number \"0x-5\"")));
+    assert_eq!(parse_number("0x8000_0000_0000_0000"),
+        Err(String::from("0x8000_0000_0000_0000 is too big. Numbers can be up to 9223372036854775807")));
+}
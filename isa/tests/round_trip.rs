@@ -0,0 +1,74 @@
+use isa::{decode, encode, EncodeError, FlagOp, InstrClass, Instruction};
+
+
+#[test]
+fn every_word_round_trips() {
+    for word in 0..=u16::MAX {
+        let instr = decode(word);
+        assert_eq!(encode(&instr), Ok(word), "{:#06x} decoded to {:?}", word, instr);
+        assert_eq!(decode(encode(&instr).unwrap()), instr);
+    }
+}
+
+
+#[test]
+fn every_word_has_one_format() {
+    for word in 0..=u16::MAX {
+        let matching = isa::FORMATS.iter()
+            .filter(|f| word & f.mask == f.pattern && f.mask != 0)
+            .count();
+        assert!(matching <= 1, "{:#06x} matches {} formats", word, matching);
+    }
+}
+
+
+#[test]
+fn the_documented_encodings() {
+    assert_eq!(decode(0x0000), Instruction::Nop);
+    assert_eq!(decode(0x0100), Instruction::Unknown(0x0100));
+    assert_eq!(decode(0x0255), Instruction::SetFlg {
+        gt: FlagOp::Keep, eq: FlagOp::Keep, ls: FlagOp::Keep, ov: FlagOp::Keep });
+    assert_eq!(decode(0x031D), Instruction::Ja { cond: 0b111, addr: 0b1010 });
+    assert_eq!(decode(0x032F), Instruction::Store { src: 0b011, addr: 0b1110 });
+    assert_eq!(decode(0x0344), Instruction::Load { dst: 0b001, addr: 0b1000 });
+    assert_eq!(decode(0x0360), Instruction::Unknown(0x0360));
+    assert_eq!(decode(0x0380), Instruction::Unknown(0x0380));
+    assert_eq!(decode(0x0392), Instruction::PutH { half: 0b1010, src: 2 });
+    assert_eq!(decode(0x03C7), Instruction::GetH { dst: 7, half: 0b1000 });
+    assert_eq!(decode(0x03FF), Instruction::GetH { dst: 7, half: 0b1111 });
+    assert_eq!(decode(0x07FF), Instruction::Jr { cond: 0b111, offset: -1 });
+    assert_eq!(decode(0x0440), Instruction::Jr { cond: 0b000, offset: -64 });
+    assert_eq!(decode(0x2A08), Instruction::Tra { op: 5, dst: 0, a: 0, b: 1 });
+    assert_eq!(decode(0x4048), Instruction::Im { op: 4, dst: 1, a: 0, imm: 1 });
+    assert_eq!(decode(0x1000), Instruction::Unknown(0x1000));
+    assert_eq!(decode(0xFFFF), Instruction::Im { op: 15, dst: 7, a: 7, imm: 63 });
+}
+
+
+#[test]
+fn fields_out_of_range_are_errors() {
+    let err = encode(&Instruction::Im { op: 4, dst: 0, a: 0, imm: 64 }).unwrap_err();
+    assert_eq!(err, EncodeError { class: InstrClass::Im, field: "imm", value: 64 });
+
+    assert!(encode(&Instruction::Im { op: 3, dst: 0, a: 0, imm: 0 }).is_err());
+    assert!(encode(&Instruction::Tra { op: 16, dst: 0, a: 0, b: 0 }).is_err());
+    assert!(encode(&Instruction::Tra { op: 4, dst: 8, a: 0, b: 0 }).is_err());
+    assert!(encode(&Instruction::Jr { cond: 7, offset: 64 }).is_err());
+    assert!(encode(&Instruction::Jr { cond: 7, offset: -65 }).is_err());
+    assert!(encode(&Instruction::Ja { cond: 8, addr: 8 }).is_err());
+    assert!(encode(&Instruction::Load { dst: 0, addr: 9 }).is_err());
+    assert!(encode(&Instruction::PutH { half: 9, src: 0 }).is_err());
+    assert!(encode(&Instruction::GetH { half: 7, dst: 0 }).is_err());
+    assert!(encode(&Instruction::GetH { half: 9, dst: 8 }).is_err());
+}
+
+
+#[test]
+fn register_names() {
+    assert_eq!(isa::register_number("C"), Some(2));
+    assert_eq!(isa::register_number("j1"), Some(11));
+    assert_eq!(isa::register_number("Q"), None);
+    assert_eq!(isa::addr_register_number("P"), Some(8));
+    assert_eq!(isa::addr_register_number("PC"), Some(8));
+    assert_eq!(isa::addr_register_number("L"), Some(14));
+}
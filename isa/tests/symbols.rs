@@ -0,0 +1,43 @@
+use isa::symbols::SymbolTable;
+
+
+#[test]
+fn parse_and_write() {
+    let table = SymbolTable::parse("\
+# AustinOS symbols
+0x00000000 start
+0x00000004 loop   # the main loop
+0x0000_8000 data
+8000 also_data
+
+").unwrap();
+
+    assert_eq!(table.lookup("loop"), Some(4));
+    assert_eq!(table.names_at(0x8000), ["data", "also_data"]);
+    assert_eq!(table.containing(0x8003), Some((0x8000, "data")));
+    assert_eq!(table.containing(3), Some((0, "start")));
+    assert_eq!(table.to_text(), "\
+# AustinOS symbols
+0x00000000 start
+0x00000004 loop
+0x00008000 data
+0x00008000 also_data
+");
+    assert_eq!(SymbolTable::parse(&table.to_text()).unwrap().to_text(), table.to_text());
+    assert!(SymbolTable::parse("# nothing\n").unwrap().is_empty());
+}
+
+
+#[test]
+fn parse_errors() {
+    let error = |text: &str| SymbolTable::parse(text).err();
+    assert_eq!(error("0x0 start\nloop\n"), Some(String::from("line 2: expected ADDRESS NAME")));
+    assert_eq!(error("0x0 two names\n"), Some(String::from("line 1: expected ADDRESS NAME")));
+    assert_eq!(error("start 0x0\n"), Some(String::from("line 1: invalid address \"start\"")));
+    assert_eq!(error("0x1_0000_0000 far\n"), Some(String::from("line 1: invalid address \"0x1_0000_0000\"")));
+    assert_eq!(error("-1 neg\n"), Some(String::from("line 1: invalid address \"-1\"")));
+
+    let missing = std::env::temp_dir().join("no-such-dir-for-isa-tests").join("x.asym");
+    let missing = missing.to_string_lossy().into_owned();
+    assert!(SymbolTable::load(&missing).unwrap_err().starts_with(&format!("Could not read {}: ", missing)));
+}